@@ -0,0 +1,512 @@
+use crate::mode_s::announced_address;
+use crate::mode_s::downlink_format;
+
+/// Altitude codes below this are not valid
+const MIN_ALTITUDE_FEET: i32 = -1000;
+
+/// Characters of the 6-bit callsign alphabet; '#' marks codes that are not assigned
+const CALLSIGN_ALPHABET: &[u8; 64] = b"#ABCDEFGHIJKLMNOPQRSTUVWXYZ##### ###############0123456789######";
+
+/// A DF17 or DF18 extended squitter
+#[derive(Clone, Debug)]
+pub struct ExtendedSquitter {
+    pub address: u32,
+
+    /// False for DF18 squitters whose address is not an ICAO 24-bit address, such as anonymous or
+    /// ground vehicle transmitters
+    pub address_is_icao: bool,
+
+    /// Implied by `content`, but kept as it was sent
+    #[allow(dead_code)]
+    pub type_code: u8,
+    pub content: SquitterContent,
+}
+
+#[derive(Clone, Debug)]
+pub enum SquitterContent {
+    Identification(Identification),
+    SurfacePosition(SurfacePosition),
+    AirbornePosition(AirbornePosition),
+    AirborneVelocity(AirborneVelocity),
+    AircraftStatus(AircraftStatus),
+
+    /// Decoded so that it is recognized, though nothing uses its contents yet
+    #[allow(dead_code)]
+    OperationalStatus(OperationalStatus),
+}
+
+/// One half of a Compact Position Reporting pair. Latitude and longitude are the raw 17-bit encodings.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CprPosition {
+    pub odd: bool,
+    pub lat: u32,
+    pub lon: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Identification {
+    /// Emitter category as dump1090 writes it: 0xA0 to 0xD7 for categories A0 to D7
+    pub category: u8,
+    pub callsign: String,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AltitudeSource {
+    Barometric,
+    Gnss,
+}
+
+#[derive(Clone, Debug)]
+pub struct AirbornePosition {
+    /// Feet
+    pub altitude: Option<i32>,
+    pub altitude_source: AltitudeSource,
+    pub surveillance_status: u8,
+    pub cpr: CprPosition,
+}
+
+#[derive(Clone, Debug)]
+pub struct SurfacePosition {
+    /// Knots
+    pub ground_speed: Option<f64>,
+
+    /// Degrees clockwise from true north
+    pub track: Option<f64>,
+    pub cpr: CprPosition,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AirspeedKind {
+    Indicated,
+    True,
+}
+
+#[derive(Clone, Debug)]
+pub struct AirborneVelocity {
+    /// Knots; only sent by aircraft reporting velocity over ground
+    pub ground_speed: Option<f64>,
+
+    /// Degrees clockwise from true north; only sent with `ground_speed`
+    pub track: Option<f64>,
+
+    /// Knots; only sent by aircraft that do not know their velocity over ground
+    pub airspeed: Option<u32>,
+    pub airspeed_kind: AirspeedKind,
+
+    /// Degrees clockwise from magnetic north; only sent with `airspeed`
+    pub heading: Option<f64>,
+
+    /// Feet per minute, positive when climbing
+    pub vertical_rate: Option<i32>,
+    pub vertical_rate_source: AltitudeSource,
+
+    /// Geometric altitude minus barometric altitude, in feet
+    #[allow(dead_code)]
+    pub geometric_minus_barometric: Option<i32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct AircraftStatus {
+    /// 0 for no emergency; see DO-260B for the meaning of the others, such as 1 for general emergency
+    pub emergency: u8,
+
+    /// The Mode A code, with one octal digit per nibble as in 0x7700
+    pub squawk: Option<u16>,
+}
+
+#[derive(Clone, Debug)]
+#[allow(dead_code)]
+pub struct OperationalStatus {
+    pub airborne: bool,
+
+    /// 0 for DO-260, 1 for DO-260A, 2 for DO-260B
+    pub version: u8,
+    pub nic_supplement_a: bool,
+    pub nac_p: u8,
+    pub sil: u8,
+}
+
+fn bits(me: u64, shift: u32, width: u32) -> u32 {
+    ((me >> shift) & ((1 << width) - 1)) as u32
+}
+
+fn decode_callsign(me: u64) -> String {
+    (0..8)
+        .map(|i| CALLSIGN_ALPHABET[bits(me, 42 - 6 * i, 6) as usize] as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// Rearranges a 13-bit identity field (C1 A1 C2 A2 C4 A4 X B1 D1 B2 D2 B4 D4) into a Mode A code with one
+/// octal digit per nibble, in the order A B C D.
+pub fn decode_id13(id13: u32) -> u16 {
+    let mut code = 0u16;
+    if id13 & 0x1000 != 0 { code |= 0x0010; } // C1
+    if id13 & 0x0800 != 0 { code |= 0x1000; } // A1
+    if id13 & 0x0400 != 0 { code |= 0x0020; } // C2
+    if id13 & 0x0200 != 0 { code |= 0x2000; } // A2
+    if id13 & 0x0100 != 0 { code |= 0x0040; } // C4
+    if id13 & 0x0080 != 0 { code |= 0x4000; } // A4
+    if id13 & 0x0020 != 0 { code |= 0x0100; } // B1
+    if id13 & 0x0010 != 0 { code |= 0x0001; } // D1
+    if id13 & 0x0008 != 0 { code |= 0x0200; } // B2
+    if id13 & 0x0004 != 0 { code |= 0x0002; } // D2
+    if id13 & 0x0002 != 0 { code |= 0x0400; } // B4
+    if id13 & 0x0001 != 0 { code |= 0x0004; } // D4
+    code
+}
+
+/// Converts a Gillham-coded Mode C reply, in the layout `decode_id13` produces, to hundreds of feet.
+fn gillham_to_hundreds_of_feet(code: u16) -> Option<i32> {
+    // D1 is never used for altitude, and a C1/C2/C4 of all zeros is not a valid code.
+    if code & 0x8889 != 0 || code & 0x00F0 == 0 {
+        return None;
+    }
+
+    let mut one_hundreds = 0i32;
+    if code & 0x0010 != 0 { one_hundreds ^= 7; } // C1
+    if code & 0x0020 != 0 { one_hundreds ^= 3; } // C2
+    if code & 0x0040 != 0 { one_hundreds ^= 1; } // C4
+
+    // The C bits are a reflected Gray code where 7 stands in for 5.
+    if one_hundreds & 5 == 5 {
+        one_hundreds ^= 2;
+    }
+    if one_hundreds > 5 {
+        return None;
+    }
+
+    let mut five_hundreds = 0i32;
+    if code & 0x0002 != 0 { five_hundreds ^= 0xFF; } // D2
+    if code & 0x0004 != 0 { five_hundreds ^= 0x7F; } // D4
+    if code & 0x1000 != 0 { five_hundreds ^= 0x3F; } // A1
+    if code & 0x2000 != 0 { five_hundreds ^= 0x1F; } // A2
+    if code & 0x4000 != 0 { five_hundreds ^= 0x0F; } // A4
+    if code & 0x0100 != 0 { five_hundreds ^= 0x07; } // B1
+    if code & 0x0200 != 0 { five_hundreds ^= 0x03; } // B2
+    if code & 0x0400 != 0 { five_hundreds ^= 0x01; } // B4
+
+    if five_hundreds & 1 != 0 {
+        one_hundreds = 6 - one_hundreds;
+    }
+
+    Some(five_hundreds * 5 + one_hundreds - 13)
+}
+
+/// Decodes the 12-bit altitude field of an airborne position, in feet.
+pub fn decode_ac12(ac12: u32) -> Option<i32> {
+    if ac12 == 0 {
+        return None;
+    }
+
+    let feet = if ac12 & 0x10 != 0 {
+        // The Q bit: 25 foot increments, with the Q bit itself removed
+        let n = ((ac12 & 0xFE0) >> 1) | (ac12 & 0x0F);
+        n as i32 * 25 - 1000
+    } else {
+        // 100 foot Gillham code, missing the M bit, which is always 0 here
+        let id13 = ((ac12 & 0xFC0) << 1) | (ac12 & 0x3F);
+        gillham_to_hundreds_of_feet(decode_id13(id13))? * 100
+    };
+    if feet < MIN_ALTITUDE_FEET {
+        return None;
+    }
+    Some(feet)
+}
+
+/// Decodes the 13-bit altitude field of surveillance and air-air replies (DF0, 4, 16, 20), in feet.
+/// Metric altitudes are not decoded.
+pub fn decode_ac13(ac13: u32) -> Option<i32> {
+    if ac13 == 0 || ac13 & 0x40 != 0 {
+        return None;
+    }
+
+    if ac13 & 0x10 != 0 {
+        // The Q bit: 25 foot increments, with the M and Q bits removed
+        let n = ((ac13 & 0x1F80) >> 2) | ((ac13 & 0x20) >> 1) | (ac13 & 0x0F);
+        Some(n as i32 * 25 - 1000)
+    } else {
+        // The Q bit sits where D1 would be, so the layout is otherwise that of an identity field.
+        gillham_to_hundreds_of_feet(decode_id13(ac13)).map(|hundreds| hundreds * 100)
+    }
+}
+
+fn decode_cpr(me: u64) -> CprPosition {
+    CprPosition {
+        odd: bits(me, 34, 1) == 1,
+        lat: bits(me, 17, 17),
+        lon: bits(me, 0, 17),
+    }
+}
+
+/// Decodes the surface movement field to knots. The steps get coarser as speed increases.
+fn decode_movement(movement: u32) -> Option<f64> {
+    let (base_code, base_speed, step) = match movement {
+        1 => return Some(0.0),
+        2..=8 => (2, 0.125, 0.125),
+        9..=12 => (9, 1.0, 0.25),
+        13..=38 => (13, 2.0, 0.5),
+        39..=93 => (39, 15.0, 1.0),
+        94..=108 => (94, 70.0, 2.0),
+        109..=123 => (109, 100.0, 5.0),
+        124 => return Some(175.0),
+        _ => return None,
+    };
+    Some(base_speed + (movement - base_code) as f64 * step)
+}
+
+fn decode_velocity(me: u64) -> Option<AirborneVelocity> {
+    let subtype = bits(me, 48, 3);
+    let supersonic_factor = if subtype == 2 || subtype == 4 { 4.0 } else { 1.0 };
+
+    let vertical_rate = match bits(me, 10, 9) {
+        0 => None,
+        rate => {
+            let rate = (rate as i32 - 1) * 64;
+            Some(if bits(me, 19, 1) == 1 { -rate } else { rate })
+        }
+    };
+    let vertical_rate_source = if bits(me, 20, 1) == 1 { AltitudeSource::Barometric } else { AltitudeSource::Gnss };
+
+    let geometric_minus_barometric = match bits(me, 0, 7) {
+        0 => None,
+        difference => {
+            let difference = (difference as i32 - 1) * 25;
+            Some(if bits(me, 7, 1) == 1 { -difference } else { difference })
+        }
+    };
+
+    let mut velocity = AirborneVelocity {
+        ground_speed: None,
+        track: None,
+        airspeed: None,
+        airspeed_kind: AirspeedKind::Indicated,
+        heading: None,
+        vertical_rate: vertical_rate,
+        vertical_rate_source: vertical_rate_source,
+        geometric_minus_barometric: geometric_minus_barometric,
+    };
+
+    match subtype {
+        1 | 2 => {
+            let east_west = bits(me, 32, 10);
+            let north_south = bits(me, 21, 10);
+            if east_west != 0 && north_south != 0 {
+                let mut east = (east_west - 1) as f64 * supersonic_factor;
+                if bits(me, 42, 1) == 1 {
+                    east = -east;
+                }
+                let mut north = (north_south - 1) as f64 * supersonic_factor;
+                if bits(me, 31, 1) == 1 {
+                    north = -north;
+                }
+
+                velocity.ground_speed = Some(east.hypot(north));
+                let track = east.atan2(north).to_degrees();
+                velocity.track = Some(if track < 0.0 { track + 360.0 } else { track });
+            }
+        }
+        3 | 4 => {
+            if bits(me, 42, 1) == 1 {
+                velocity.heading = Some(bits(me, 32, 10) as f64 * 360.0 / 1024.0);
+            }
+            velocity.airspeed_kind = if bits(me, 31, 1) == 1 { AirspeedKind::True } else { AirspeedKind::Indicated };
+            velocity.airspeed = match bits(me, 21, 10) {
+                0 => None,
+                airspeed => Some((airspeed - 1) * supersonic_factor as u32),
+            };
+        }
+        _ => return None,
+    }
+
+    Some(velocity)
+}
+
+/// Decodes a DF17 or DF18 frame whose parity has already been checked. Other downlink formats, and
+/// message types we do not understand, give `None`.
+pub fn decode(frame: &[u8]) -> Option<ExtendedSquitter> {
+    if frame.len() != 14 {
+        return None;
+    }
+
+    let address_is_icao = match downlink_format(frame) {
+        17 => true,
+        18 => match frame[0] & 7 {
+            // Control field: 0 is ADS-B from an ICAO address, 1 from some other address, and 6 is
+            // rebroadcast (ADS-R) from an ICAO address. The rest are TIS-B, which is laid out differently.
+            0 | 6 => true,
+            1 => false,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let me = frame[4..11].iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+    let type_code = bits(me, 51, 5) as u8;
+
+    let content = match type_code {
+        1..=4 => SquitterContent::Identification(Identification {
+            category: ((0x0E - type_code) << 4) | bits(me, 48, 3) as u8,
+            callsign: decode_callsign(me),
+        }),
+        5..=8 => SquitterContent::SurfacePosition(SurfacePosition {
+            ground_speed: decode_movement(bits(me, 44, 7)),
+            track: if bits(me, 43, 1) == 1 {
+                Some(bits(me, 36, 7) as f64 * 360.0 / 128.0)
+            } else {
+                None
+            },
+            cpr: decode_cpr(me),
+        }),
+        9..=18 | 20..=22 => SquitterContent::AirbornePosition(AirbornePosition {
+            altitude: decode_ac12(bits(me, 36, 12)),
+            altitude_source: if type_code < 19 { AltitudeSource::Barometric } else { AltitudeSource::Gnss },
+            surveillance_status: bits(me, 49, 2) as u8,
+            cpr: decode_cpr(me),
+        }),
+        19 => SquitterContent::AirborneVelocity(decode_velocity(me)?),
+        28 if bits(me, 48, 3) == 1 => SquitterContent::AircraftStatus(AircraftStatus {
+            emergency: bits(me, 45, 3) as u8,
+            squawk: match bits(me, 32, 13) {
+                0 => None,
+                id13 => Some(decode_id13(id13)),
+            },
+        }),
+        31 if bits(me, 48, 3) <= 1 => SquitterContent::OperationalStatus(OperationalStatus {
+            airborne: bits(me, 48, 3) == 0,
+            version: bits(me, 13, 3) as u8,
+            nic_supplement_a: bits(me, 12, 1) == 1,
+            nac_p: bits(me, 8, 4) as u8,
+            sil: bits(me, 4, 2) as u8,
+        }),
+        _ => return None,
+    };
+
+    Some(ExtendedSquitter {
+        address: announced_address(frame),
+        address_is_icao: address_is_icao,
+        type_code: type_code,
+        content: content,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::decode_ac12;
+    use super::AltitudeSource;
+    use super::AirspeedKind;
+    use super::SquitterContent;
+    use crate::mode_s::crc24;
+
+    fn hex(frame: &str) -> Vec<u8> {
+        (0..frame.len() / 2).map(|i| u8::from_str_radix(&frame[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    /// A long frame with the given first byte, address, and ME field, and correct parity
+    fn squitter(first_byte: u8, address: u32, me: u64) -> Vec<u8> {
+        let mut frame = vec![first_byte, (address >> 16) as u8, (address >> 8) as u8, address as u8];
+        frame.extend_from_slice(&me.to_be_bytes()[1..]);
+        let parity = crc24(&frame);
+        frame.extend_from_slice(&parity.to_be_bytes()[1..]);
+        frame
+    }
+
+    #[test]
+    fn decodes_a_callsign() {
+        let squitter = decode(&hex("8D4840D6202CC371C32CE0576098")).unwrap();
+        assert_eq!(squitter.address, 0x4840D6);
+        assert!(squitter.address_is_icao);
+        match squitter.content {
+            SquitterContent::Identification(identification) => {
+                assert_eq!(identification.callsign, "KLM1023");
+                assert_eq!(identification.category, 0xA0);
+            }
+            content => panic!("{:?}", content),
+        }
+    }
+
+    #[test]
+    fn decodes_an_airborne_position() {
+        match decode(&hex("8D40621D58C382D690C8AC2863A7")).unwrap().content {
+            SquitterContent::AirbornePosition(position) => {
+                assert_eq!(position.altitude, Some(38000));
+                assert_eq!(position.altitude_source, AltitudeSource::Barometric);
+                assert!(!position.cpr.odd);
+                assert_eq!( (position.cpr.lat, position.cpr.lon), (93000, 51372) );
+            }
+            content => panic!("{:?}", content),
+        }
+    }
+
+    #[test]
+    fn decodes_gillham_altitudes() {
+        // Type code 11 with the Q bit clear, at 3500 feet
+        match decode(&squitter(0x8D, 0x40621D, 11 << 51 | 0x262 << 36)).unwrap().content {
+            SquitterContent::AirbornePosition(position) => assert_eq!(position.altitude, Some(3500)),
+            content => panic!("{:?}", content),
+        }
+        assert_eq!(decode_ac12(0x643), Some(38000));
+        assert_eq!(decode_ac12(0x200), Some(-1000));
+
+        // Codes below -1000 feet, and a C field of all zeros, are not altitudes.
+        assert_eq!(decode_ac12(0x080), None);
+        assert_eq!(decode_ac12(0x280), None);
+        assert_eq!(decode_ac12(0x040), None);
+    }
+
+    #[test]
+    fn decodes_ground_speed_velocity() {
+        match decode(&hex("8D485020994409940838175B284F")).unwrap().content {
+            SquitterContent::AirborneVelocity(velocity) => {
+                assert!((velocity.ground_speed.unwrap() - 159.20).abs() < 0.01);
+                assert!((velocity.track.unwrap() - 182.88).abs() < 0.01);
+                assert_eq!(velocity.vertical_rate, Some(-832));
+                assert_eq!(velocity.vertical_rate_source, AltitudeSource::Gnss);
+                assert_eq!(velocity.geometric_minus_barometric, Some(550));
+                assert_eq!(velocity.airspeed, None);
+            }
+            content => panic!("{:?}", content),
+        }
+    }
+
+    #[test]
+    fn decodes_airspeed_velocity() {
+        match decode(&hex("8DA05F219B06B6AF189400CBC33F")).unwrap().content {
+            SquitterContent::AirborneVelocity(velocity) => {
+                assert_eq!(velocity.airspeed, Some(375));
+                assert_eq!(velocity.airspeed_kind, AirspeedKind::True);
+                assert!((velocity.heading.unwrap() - 243.98).abs() < 0.01);
+                assert_eq!(velocity.vertical_rate, Some(-2304));
+                assert_eq!(velocity.vertical_rate_source, AltitudeSource::Barometric);
+                assert_eq!(velocity.ground_speed, None);
+            }
+            content => panic!("{:?}", content),
+        }
+    }
+
+    #[test]
+    fn decodes_an_emergency_squawk() {
+        // Type code 28 subtype 1, general emergency, squawking 7700: A1 A2 A4 B1 B2 B4 set
+        let me = 28 << 51 | 1 << 48 | 1 << 45 | 0x0AAA << 32;
+        match decode(&squitter(0x8D, 0xABCDEF, me)).unwrap().content {
+            SquitterContent::AircraftStatus(status) => {
+                assert_eq!(status.squawk, Some(0x7700));
+                assert_eq!(status.emergency, 1);
+            }
+            content => panic!("{:?}", content),
+        }
+    }
+
+    #[test]
+    fn tells_non_icao_addresses_apart() {
+        let me = 0x202CC371C32CE0;
+        assert!(decode(&squitter(0x90, 0x4840D6, me)).unwrap().address_is_icao);
+        assert!(!decode(&squitter(0x91, 0x4840D6, me)).unwrap().address_is_icao);
+        assert!(decode(&squitter(0x96, 0x4840D6, me)).unwrap().address_is_icao);
+
+        // TIS-B is laid out differently and is not decoded.
+        assert!(decode(&squitter(0x92, 0x4840D6, me)).is_none());
+    }
+}
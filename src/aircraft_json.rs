@@ -0,0 +1,232 @@
+use crate::tracker::Aircraft;
+use crate::tracker::AircraftTable;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Converts a Beast signal level byte to dBFS, as dump1090 reports it
+fn rssi(signal_level: u8) -> f64 {
+    let level = signal_level as f64 / 255.0;
+    (10.0 * (level * level).log10()).max(-49.5)
+}
+
+fn seconds_since(now: Instant, then: Instant) -> f64 {
+    now.duration_since(then).as_millis() as f64 / 1000.0
+}
+
+fn write_aircraft(json: &mut String, aircraft: &Aircraft, now: Instant) {
+    let _ = write!(json, "{{\"hex\":\"{:06x}\"", aircraft.address);
+    if let Some(ref callsign) = aircraft.callsign {
+        // dump1090 pads the callsign to its full 8 characters.
+        let _ = write!(json, ",\"flight\":\"{:<8}\"", escape(callsign));
+    }
+    if let Some(squawk) = aircraft.squawk {
+        let _ = write!(json, ",\"squawk\":\"{:04x}\"", squawk);
+    }
+    if let Some(altitude) = aircraft.altitude {
+        let _ = write!(json, ",\"alt_baro\":{}", altitude);
+    }
+    if let Some(ground_speed) = aircraft.ground_speed {
+        let _ = write!(json, ",\"gs\":{:.1}", ground_speed);
+    }
+    if let Some(track) = aircraft.track {
+        let _ = write!(json, ",\"track\":{:.1}", track);
+    }
+    if let Some(vertical_rate) = aircraft.vertical_rate {
+        let _ = write!(json, ",\"baro_rate\":{}", vertical_rate);
+    }
+    if let (Some(position), Some(updated)) = (aircraft.position, aircraft.position_updated) {
+        let _ = write!(json, ",\"lat\":{:.6},\"lon\":{:.6},\"seen_pos\":{:.1}", position.lat, position.lon, seconds_since(now, updated));
+    }
+    let _ = write!(json, ",\"messages\":{},\"seen\":{:.1}", aircraft.messages, seconds_since(now, aircraft.last_seen));
+    if let Some(signal_level) = aircraft.signal_level {
+        let _ = write!(json, ",\"rssi\":{:.1}", rssi(signal_level));
+    }
+    json.push('}');
+}
+
+/// Renders the table in the `aircraft.json` schema of dump1090 and readsb, which tar1090 and Virtual
+/// Radar Server read.
+pub fn aircraft_json(table: &AircraftTable) -> String {
+    let unix_time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as f64 / 1000.0).unwrap_or(0.0);
+    render(table, Instant::now(), unix_time)
+}
+
+/// Renders the table as of `now`, which is `unix_time` seconds since the Unix epoch
+fn render(table: &AircraftTable, now: Instant, unix_time: f64) -> String {
+    let messages: u64 = table.aircraft().map(|aircraft| aircraft.messages).sum();
+
+    let mut json = String::new();
+    let _ = write!(json, "{{\"now\":{:.1},\"messages\":{},\"aircraft\":[", unix_time, messages);
+    for (i, aircraft) in table.aircraft().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write_aircraft(&mut json, aircraft, now);
+    }
+    json.push_str("]}\n");
+    json
+}
+
+/// Replaces the file at `path` with the current table. The new contents are written alongside it
+/// and renamed into place, so that readers never see a partial file.
+pub fn write_file(path: &Path, table: &Mutex<AircraftTable>) -> io::Result<()> {
+    let json = aircraft_json(&table.lock().unwrap());
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, json)?;
+    fs::rename(&temporary, path)
+}
+
+/// Rewrites `path` every `interval` for as long as the program runs.
+pub fn write_periodically(path: PathBuf, interval: Duration, table: Arc<Mutex<AircraftTable>>) {
+    loop {
+        if let Err(e) = write_file(&path, &table) {
+            eprintln!("Failed to write {}: {}", path.display(), e);
+        }
+        sleep(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use super::write_aircraft;
+    use crate::cpr::Position;
+    use crate::ingest::ModeSFrame;
+    use crate::mode_s::crc24;
+    use crate::observer::FrameSource;
+    use crate::observer::ObservedFrame;
+    use crate::tracker::Aircraft;
+    use crate::tracker::AircraftTable;
+    use std::collections::HashSet;
+    use std::time::Duration;
+    use std::time::Instant;
+    use std::time::SystemTime;
+
+    fn aircraft(address: u32, heard: Instant) -> Aircraft {
+        Aircraft {
+            address: address,
+            callsign: None,
+            category: None,
+            position: None,
+            position_updated: None,
+            altitude: None,
+            ground_speed: None,
+            track: None,
+            vertical_rate: None,
+            squawk: None,
+            signal_level: None,
+            first_seen: heard,
+            last_seen: heard,
+            messages: 1,
+            heard_locally: false,
+            partners: HashSet::new(),
+        }
+    }
+
+    fn rendered(aircraft: &Aircraft, now: Instant) -> String {
+        let mut json = String::new();
+        write_aircraft(&mut json, aircraft, now);
+        json
+    }
+
+    #[test]
+    fn uses_dump1090_field_names_and_units() {
+        let start = Instant::now();
+        let aircraft = Aircraft {
+            callsign: Some("KLM1023".to_string()),
+            category: Some(0xA3),
+            position: Some(Position { lat: 52.2572, lon: 3.9194 }),
+            position_updated: Some(start),
+            altitude: Some(38000),
+            ground_speed: Some(159.2037),
+            track: Some(182.8802),
+            vertical_rate: Some(-2304),
+            squawk: Some(0x7700),
+            signal_level: Some(0x80),
+            last_seen: start + Duration::from_millis(2100),
+            messages: 5,
+            heard_locally: true,
+            ..aircraft(0x4840D6, start)
+        };
+
+        assert_eq!(
+            rendered(&aircraft, start + Duration::from_millis(2500)),
+            concat!(
+                "{\"hex\":\"4840d6\",\"flight\":\"KLM1023 \",\"squawk\":\"7700\",\"alt_baro\":38000,",
+                "\"gs\":159.2,\"track\":182.9,\"baro_rate\":-2304,",
+                "\"lat\":52.257200,\"lon\":3.919400,\"seen_pos\":2.5,",
+                "\"messages\":5,\"seen\":0.4,\"rssi\":-6.0}"
+            )
+        );
+    }
+
+    #[test]
+    fn leaves_out_what_is_not_known() {
+        let start = Instant::now();
+        assert_eq!(rendered(&aircraft(0xABC, start), start), "{\"hex\":\"000abc\",\"messages\":1,\"seen\":0.0}");
+
+        // A position is only given with its age.
+        let aircraft = Aircraft {
+            position: Some(Position { lat: 1.0, lon: 2.0 }),
+            ..aircraft(0xABC, start)
+        };
+        assert_eq!(rendered(&aircraft, start), "{\"hex\":\"000abc\",\"messages\":1,\"seen\":0.0}");
+    }
+
+    #[test]
+    fn wraps_the_aircraft_in_a_dump1090_document() {
+        let mut table = AircraftTable::new(Duration::from_secs(60));
+        let start = Instant::now();
+        assert_eq!(render(&table, start, 1700000000.25), "{\"now\":1700000000.2,\"messages\":0,\"aircraft\":[]}\n");
+
+        // An all-call reply, which only says that the aircraft is there
+        let mut frame = vec![0x5D, 0x48, 0x40, 0xD6];
+        let parity = crc24(&frame);
+        frame.extend_from_slice(&parity.to_be_bytes()[1..]);
+        for _ in 0..2 {
+            table.update(&ObservedFrame {
+                source: FrameSource::Partner(7),
+                frame: ModeSFrame {
+                    bytes: frame.clone(),
+                    mlat_timestamp: None,
+                    signal_level: None,
+                    received_at: SystemTime::now(),
+                    receiver_location: None,
+                },
+                position: None,
+                received_at: start,
+            });
+        }
+        assert_eq!(
+            render(&table, start + Duration::from_secs(3), 1700000003.0),
+            "{\"now\":1700000003.0,\"messages\":2,\"aircraft\":[{\"hex\":\"4840d6\",\"messages\":2,\"seen\":3.0}]}\n"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use crate::ingest::FrameDecoder;
+use crate::ingest::ModeSFrame;
+use std::time::SystemTime;
+
+/// Longer than any valid line, so that a stream without newlines cannot grow the buffer forever
+const MAX_LINE_LEN: usize = 64;
+
+/// Splits the textual AVR format, as served on port 30002, into frames. Each line is a frame in hex,
+/// either `*8D4840D6202CC371C32CE0576098;` or with a 12 MHz timestamp as
+/// `@0000B0D5F6C88D4840D6202CC371C32CE0576098;`. dump1090 also writes timestamped lines starting with
+/// `%`, and with a signal level byte after the timestamp starting with `<`.
+#[derive(Default)]
+pub struct AvrDecoder {
+    line: Vec<u8>,
+}
+
+impl AvrDecoder {
+    pub fn new() -> AvrDecoder {
+        AvrDecoder {
+            line: Vec::new(),
+        }
+    }
+}
+
+impl FrameDecoder for AvrDecoder {
+    fn push(&mut self, bytes: &[u8]) {
+        self.line.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<ModeSFrame> {
+        while let Some(end) = self.line.iter().position(|&b| b == b'\n') {
+            let frame = parse_line(&self.line[..end]);
+            self.line.drain(..=end);
+            if frame.is_some() {
+                return frame;
+            }
+        }
+
+        if self.line.len() > MAX_LINE_LEN {
+            self.line.clear();
+        }
+        None
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    hex.chunks(2)
+        .map(|pair| Some(hex_digit(pair[0])? << 4 | hex_digit(pair[1])?))
+        .collect()
+}
+
+/// Reads a 12 MHz timestamp from 12 hex digits
+fn parse_timestamp(hex: &[u8]) -> Option<u64> {
+    Some(parse_hex(hex)?.iter().fold(0u64, |acc, &b| (acc << 8) | b as u64))
+}
+
+/// Parses one line. Lines that are not Mode S frames, such as Mode A/C replies, give `None`.
+pub fn parse_line(line: &[u8]) -> Option<ModeSFrame> {
+    let line = match line.iter().rposition(|&b| b == b';') {
+        Some(end) => &line[..end],
+        None => return None,
+    };
+    let line = match line.iter().position(|&b| b == b'*' || b == b'@' || b == b'%' || b == b'<') {
+        Some(start) => &line[start..],
+        None => return None,
+    };
+
+    let (mlat_timestamp, signal_level, hex) = match line[0] {
+        b'@' | b'%' => {
+            if line.len() < 1 + 12 {
+                return None;
+            }
+            (Some(parse_timestamp(&line[1..13])?), None, &line[13..])
+        }
+        b'<' => {
+            if line.len() < 1 + 12 + 2 {
+                return None;
+            }
+            (Some(parse_timestamp(&line[1..13])?), Some(parse_hex(&line[13..15])?[0]), &line[15..])
+        }
+        _ => (None, None, &line[1..]),
+    };
+
+    let bytes = parse_hex(hex)?;
+    if bytes.len() != 7 && bytes.len() != 14 {
+        return None;
+    }
+
+    Some(ModeSFrame {
+        bytes: bytes,
+        mlat_timestamp: mlat_timestamp,
+        signal_level: signal_level,
+        received_at: SystemTime::now(),
+        receiver_location: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_line;
+    use super::AvrDecoder;
+    use crate::ingest::FrameDecoder;
+
+    const FRAME: [u8; 14] = [0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98];
+
+    #[test]
+    fn parses_plain_lines() {
+        let frame = parse_line(b"*8D4840D6202CC371C32CE0576098;").unwrap();
+        assert_eq!(frame.bytes, FRAME.to_vec());
+        assert_eq!( (frame.mlat_timestamp, frame.signal_level), (None, None) );
+
+        assert_eq!(parse_line(b"*8d4840d6202cc371c32ce0576098;\r").unwrap().bytes, FRAME.to_vec());
+        assert_eq!(parse_line(b"*5D4840D6A1B2C3;").unwrap().bytes, vec![0x5D, 0x48, 0x40, 0xD6, 0xA1, 0xB2, 0xC3]);
+    }
+
+    #[test]
+    fn parses_timestamped_lines() {
+        for line in &[&b"@0000B0D5F6C88D4840D6202CC371C32CE0576098;"[..], &b"%0000b0d5f6c88d4840d6202cc371c32ce0576098;"[..]] {
+            let frame = parse_line(line).unwrap();
+            assert_eq!(frame.bytes, FRAME.to_vec());
+            assert_eq!( (frame.mlat_timestamp, frame.signal_level), (Some(0xB0D5F6C8), None) );
+        }
+
+        let frame = parse_line(b"<0000B0D5F6C8A08D4840D6202CC371C32CE0576098;").unwrap();
+        assert_eq!(frame.bytes, FRAME.to_vec());
+        assert_eq!( (frame.mlat_timestamp, frame.signal_level), (Some(0xB0D5F6C8), Some(0xA0)) );
+    }
+
+    #[test]
+    fn rejects_lines_that_are_not_mode_s_frames() {
+        // Mode A/C replies
+        assert!(parse_line(b"*2000;").is_none());
+        assert!(parse_line(b"@0000B0D5F6C82000;").is_none());
+        // Neither 7 nor 14 bytes
+        assert!(parse_line(b"*8D4840D6202CC371C32CE05760;").is_none());
+        assert!(parse_line(b"*8D4840D6202CC371C32CE057609800;").is_none());
+        assert!(parse_line(b"*5D4840D6A1B2;").is_none());
+        // Odd lengths, non-hex digits, and missing markers
+        assert!(parse_line(b"*8D4840D6202CC371C32CE057609;").is_none());
+        assert!(parse_line(b"*8D4840D6202CC371C32CE05760GG;").is_none());
+        assert!(parse_line(b"@0000B0D5F6;").is_none());
+        assert!(parse_line(b"<0000B0D5F6C8;").is_none());
+        assert!(parse_line(b"8D4840D6202CC371C32CE0576098;").is_none());
+        assert!(parse_line(b"*8D4840D6202CC371C32CE0576098").is_none());
+        assert!(parse_line(b"").is_none());
+    }
+
+    #[test]
+    fn skips_bad_lines_in_a_stream() {
+        let mut decoder = AvrDecoder::new();
+        decoder.push(b"garbage\n*2000;\n*8D4840D6202CC37");
+        assert!(decoder.next_frame().is_none());
+        decoder.push(b"1C32CE0576098;\r\n*5D4840D6A1B2C3;\n");
+        assert_eq!(decoder.next_frame().unwrap().bytes, FRAME.to_vec());
+        assert_eq!(decoder.next_frame().unwrap().bytes.len(), 7);
+        assert!(decoder.next_frame().is_none());
+
+        // A stream without newlines is not kept forever.
+        decoder.push(&[b'*'; 100]);
+        assert!(decoder.next_frame().is_none());
+        decoder.push(b"\n*8D4840D6202CC371C32CE0576098;\n");
+        assert_eq!(decoder.next_frame().unwrap().bytes, FRAME.to_vec());
+    }
+}
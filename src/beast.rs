@@ -0,0 +1,314 @@
+use crate::ingest::FrameDecoder;
+use crate::ingest::ModeSFrame;
+use std::time::SystemTime;
+
+/// Starts every Beast message, and is doubled wherever it appears inside one
+const ESCAPE: u8 = 0x1A;
+
+/// The signal level we give frames relayed from partners, which have none of their own. Frames that
+/// come back to our ingest with this level are recognized as our own output.
+pub const RELAYED_SIGNAL_LEVEL: u8 = 0x01;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BeastMessageKind {
+    ModeAC,
+    ModeSShort,
+    ModeSLong,
+}
+
+impl BeastMessageKind {
+    fn from_type_byte(type_byte: u8) -> Option<BeastMessageKind> {
+        match type_byte {
+            b'1' => Some(BeastMessageKind::ModeAC),
+            b'2' => Some(BeastMessageKind::ModeSShort),
+            b'3' => Some(BeastMessageKind::ModeSLong),
+            _ => None,
+        }
+    }
+
+    fn type_byte(self) -> u8 {
+        match self {
+            BeastMessageKind::ModeAC => b'1',
+            BeastMessageKind::ModeSShort => b'2',
+            BeastMessageKind::ModeSLong => b'3',
+        }
+    }
+
+    fn data_len(self) -> usize {
+        match self {
+            BeastMessageKind::ModeAC => 2,
+            BeastMessageKind::ModeSShort => 7,
+            BeastMessageKind::ModeSLong => 14,
+        }
+    }
+}
+
+pub struct BeastMessage {
+    pub kind: BeastMessageKind,
+
+    /// The receiver's 12 MHz counter when the message was heard
+    pub mlat_timestamp: u64,
+    pub signal_level: u8,
+    pub data: Vec<u8>,
+}
+
+impl BeastMessage {
+    /// Writes the message in the Beast binary format, escaping as needed. The timestamp is truncated
+    /// to the format's 48 bits.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::with_capacity(6 + 1 + self.data.len());
+        body.extend_from_slice(&self.mlat_timestamp.to_be_bytes()[2..]);
+        body.push(self.signal_level);
+        body.extend_from_slice(&self.data);
+
+        let mut encoded = Vec::with_capacity(2 + body.len() * 2);
+        encoded.push(ESCAPE);
+        encoded.push(self.kind.type_byte());
+        for &b in &body {
+            if b == ESCAPE {
+                encoded.push(ESCAPE);
+            }
+            encoded.push(b);
+        }
+        encoded
+    }
+
+    /// Mode A/C replies are not worth sharing, since they do not say which aircraft sent them.
+    pub fn into_mode_s_frame(self) -> Option<ModeSFrame> {
+        if self.kind == BeastMessageKind::ModeAC {
+            return None;
+        }
+
+        Some(ModeSFrame {
+            bytes: self.data,
+            mlat_timestamp: Some(self.mlat_timestamp),
+            signal_level: Some(self.signal_level),
+            received_at: SystemTime::now(),
+            receiver_location: None,
+        })
+    }
+}
+
+enum Unescaped {
+    Complete{message: BeastMessage, consumed: usize},
+    NeedMoreData,
+
+    /// A lone escape byte turned up mid-message, at this index. It is probably the start of the next message.
+    Corrupt{resync_at: usize},
+}
+
+/// Splits a Beast binary stream, as served by dump1090 or readsb on port 30005, into messages. Bytes can be
+/// pushed in arbitrarily sized pieces; a message split across pieces is held until the rest arrives.
+#[derive(Default)]
+pub struct BeastDecoder {
+    buf: Vec<u8>,
+}
+
+impl BeastDecoder {
+    pub fn new() -> BeastDecoder {
+        BeastDecoder {
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn next_message(&mut self) -> Option<BeastMessage> {
+        loop {
+            // Discard anything before the next message start.
+            match self.buf.iter().position(|&b| b == ESCAPE) {
+                Some(start) => {
+                    self.buf.drain(..start);
+                }
+                None => {
+                    self.buf.clear();
+                    return None;
+                }
+            }
+
+            if self.buf.len() < 2 {
+                return None;
+            }
+
+            let kind = match BeastMessageKind::from_type_byte(self.buf[1]) {
+                Some(kind) => kind,
+                None => {
+                    // Either an escaped 0x1A from the middle of a message we joined partway through, or a
+                    // message type we do not use. Either way, look for the next start.
+                    self.buf.drain(..1);
+                    continue;
+                }
+            };
+
+            match unescape(&self.buf, kind) {
+                Unescaped::Complete{message, consumed} => {
+                    self.buf.drain(..consumed);
+                    return Some(message);
+                }
+                Unescaped::NeedMoreData => {
+                    return None;
+                }
+                Unescaped::Corrupt{resync_at} => {
+                    self.buf.drain(..resync_at);
+                }
+            }
+        }
+    }
+}
+
+impl FrameDecoder for BeastDecoder {
+    fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn next_frame(&mut self) -> Option<ModeSFrame> {
+        while let Some(message) = self.next_message() {
+            if let Some(frame) = message.into_mode_s_frame() {
+                return Some(frame);
+            }
+        }
+        None
+    }
+}
+
+/// Reads the message starting at `buf[0]`, which is known to be an escape followed by a valid type byte.
+fn unescape(buf: &[u8], kind: BeastMessageKind) -> Unescaped {
+    let wanted = 6 + 1 + kind.data_len();
+    let mut body = Vec::with_capacity(wanted);
+
+    let mut index = 2;
+    while body.len() < wanted {
+        if index >= buf.len() {
+            return Unescaped::NeedMoreData;
+        }
+
+        let b = buf[index];
+        if b == ESCAPE {
+            match buf.get(index + 1) {
+                Some(&ESCAPE) => {
+                    index += 1;
+                }
+                Some(_) => {
+                    return Unescaped::Corrupt{resync_at: index};
+                }
+                None => {
+                    return Unescaped::NeedMoreData;
+                }
+            }
+        }
+
+        body.push(b);
+        index += 1;
+    }
+
+    let mlat_timestamp = body[..6].iter().fold(0u64, |acc, &b| (acc << 8) | b as u64);
+    let signal_level = body[6];
+    let data = body[7..].to_vec();
+
+    Unescaped::Complete{
+        message: BeastMessage {
+            kind: kind,
+            mlat_timestamp: mlat_timestamp,
+            signal_level: signal_level,
+            data: data,
+        },
+        consumed: index,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BeastDecoder;
+    use super::BeastMessage;
+    use super::BeastMessageKind;
+    use crate::ingest::FrameDecoder;
+
+    const LONG: [u8; 14] = [0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98];
+    const SHORT: [u8; 7] = [0x5D, 0x48, 0x40, 0xD6, 0x1A, 0x1A, 0x1A];
+
+    fn decode_all(bytes: &[u8]) -> Vec<BeastMessage> {
+        let mut decoder = BeastDecoder::new();
+        decoder.push(bytes);
+        let mut messages = Vec::new();
+        while let Some(message) = decoder.next_message() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn encoded(kind: BeastMessageKind, mlat_timestamp: u64, signal_level: u8, data: &[u8]) -> Vec<u8> {
+        BeastMessage {
+            kind: kind,
+            mlat_timestamp: mlat_timestamp,
+            signal_level: signal_level,
+            data: data.to_vec(),
+        }.encode()
+    }
+
+    #[test]
+    fn reads_each_message_type() {
+        let mut stream = vec![0x1A, b'3', 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x80];
+        stream.extend_from_slice(&LONG);
+        stream.extend(encoded(BeastMessageKind::ModeSShort, 5, 0x20, &SHORT[..4]).iter().chain(&[0x1A, 0x1A, 0x1A, 0x1A, 0x1A, 0x1A]));
+        stream.extend(encoded(BeastMessageKind::ModeAC, 6, 0x30, &[0x12, 0x34]));
+
+        let messages = decode_all(&stream);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].kind, BeastMessageKind::ModeSLong);
+        assert_eq!(messages[0].mlat_timestamp, 0x01020304);
+        assert_eq!(messages[0].signal_level, 0x80);
+        assert_eq!(messages[0].data, LONG.to_vec());
+        assert_eq!(messages[1].kind, BeastMessageKind::ModeSShort);
+        assert_eq!(messages[1].data, SHORT.to_vec());
+        assert_eq!(messages[2].kind, BeastMessageKind::ModeAC);
+        assert_eq!(messages[2].data, vec![0x12, 0x34]);
+    }
+
+    #[test]
+    fn doubles_escapes_inside_a_message() {
+        let stream = encoded(BeastMessageKind::ModeSShort, 0x1A_1A00_001A, 0x1A, &SHORT);
+        // The three escapes in the timestamp, the one in the signal level, and the three in the data are all doubled.
+        assert_eq!(stream.len(), 2 + 6 + 1 + 7 + 7);
+
+        let messages = decode_all(&stream);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].mlat_timestamp, 0x1A_1A00_001A);
+        assert_eq!(messages[0].signal_level, 0x1A);
+        assert_eq!(messages[0].data, SHORT.to_vec());
+    }
+
+    #[test]
+    fn resynchronizes_after_garbage_and_truncated_messages() {
+        let good = encoded(BeastMessageKind::ModeSLong, 7, 0x40, &LONG);
+        let mut stream = vec![0x00, 0xFF, 0x1A, 0x1A, 0x1A, b'9', 0x42];
+        // A message cut off partway, as when a feed restarts
+        stream.extend_from_slice(&good[..10]);
+        stream.extend_from_slice(&good);
+
+        let messages = decode_all(&stream);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].mlat_timestamp, 7);
+        assert_eq!(messages[0].data, LONG.to_vec());
+    }
+
+    #[test]
+    fn waits_for_the_rest_of_a_split_message() {
+        let stream = encoded(BeastMessageKind::ModeSLong, 8, 0x50, &LONG);
+        let mut decoder = BeastDecoder::new();
+        for &b in &stream[..stream.len() - 1] {
+            decoder.push(&[b]);
+            assert!(decoder.next_frame().is_none());
+        }
+        decoder.push(&stream[stream.len() - 1..]);
+        let frame = decoder.next_frame().unwrap();
+        assert_eq!(frame.bytes, LONG.to_vec());
+        assert_eq!( (frame.mlat_timestamp, frame.signal_level), (Some(8), Some(0x50)) );
+    }
+
+    #[test]
+    fn mode_a_c_replies_are_not_frames() {
+        let mut decoder = BeastDecoder::new();
+        decoder.push(&encoded(BeastMessageKind::ModeAC, 1, 0x10, &[0x12, 0x34]));
+        decoder.push(&encoded(BeastMessageKind::ModeSShort, 2, 0x10, &SHORT));
+        assert_eq!(decoder.next_frame().unwrap().bytes, SHORT.to_vec());
+        assert!(decoder.next_frame().is_none());
+    }
+}
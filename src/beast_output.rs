@@ -0,0 +1,183 @@
+use crate::beast::BeastMessage;
+use crate::beast::BeastMessageKind;
+use crate::beast::RELAYED_SIGNAL_LEVEL;
+use crate::fanout::Fanout;
+use crate::ingest::ModeSFrame;
+use crate::observer::FrameSource;
+use crate::observer::ObservedFrame;
+use std::collections::HashMap;
+use std::net::TcpListener;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Frames we served come back within this long if something loops our output into our input
+const ECHO_LIFETIME: Duration = Duration::from_secs(2);
+
+/// The Beast timestamp counts at 12 MHz
+const TICKS_PER_SECOND: u128 = 12_000_000;
+
+/// The Beast timestamp for when a frame was heard. Frames from different receivers share no clock, so
+/// the time of day is the only timestamp that means the same thing for all of them.
+fn receive_timestamp(received_at: SystemTime) -> u64 {
+    let since_epoch = received_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since_epoch.as_nanos() * TICKS_PER_SECOND / 1_000_000_000) as u64 & 0xFFFF_FFFF_FFFF
+}
+
+/// The signal level a frame is served with. Partners' frames get a made up one.
+fn served_signal_level(observed: &ObservedFrame) -> u8 {
+    match observed.source {
+        FrameSource::Local(_) => observed.frame.signal_level.unwrap_or(RELAYED_SIGNAL_LEVEL),
+        FrameSource::Partner(_) => RELAYED_SIGNAL_LEVEL,
+    }
+}
+
+/// Encodes a frame as a Beast message, or `None` if it is not a Mode S frame.
+pub fn encode_frame(observed: &ObservedFrame) -> Option<Vec<u8>> {
+    let kind = match observed.frame.bytes.len() {
+        7 => BeastMessageKind::ModeSShort,
+        14 => BeastMessageKind::ModeSLong,
+        _ => return None,
+    };
+
+    let message = BeastMessage {
+        kind: kind,
+        mlat_timestamp: receive_timestamp(observed.frame.received_at),
+        signal_level: served_signal_level(observed),
+        data: observed.frame.bytes.clone(),
+    };
+    Some(message.encode())
+}
+
+/// Sends frames from `frames`, as from `FrameObserver::listen`, to each client connected to `listener`
+/// in the Beast binary format, so that tools like readsb can treat the mesh as a receiver. Frames from
+/// partners are always sent; our own receivers' frames only if `include_local` is set. Returns when the
+/// node goes away.
+pub fn serve(listener: TcpListener, frames: Receiver<ObservedFrame>, include_local: bool) {
+    let clients = Fanout::new(listener, "Beast");
+
+    for observed in frames {
+        if let FrameSource::Local(_) = observed.source {
+            if !include_local {
+                continue;
+            }
+        }
+
+        if let Some(message) = encode_frame(&observed) {
+            clients.send(message);
+        }
+    }
+}
+
+/// Remembers the frames recently sent out by `serve`, so that if a tool feeds them back into our own
+/// ingest they are not shared with partners a second time as if we had heard them.
+pub struct EchoGuard {
+    include_local: bool,
+
+    /// The signal level and timestamp each frame was served with, and when
+    served: HashMap<Vec<u8>, (u8, u64, Instant)>,
+    last_pruned: Instant,
+}
+
+impl EchoGuard {
+    /// `include_local` must match what is given to `serve`.
+    pub fn new(include_local: bool) -> EchoGuard {
+        EchoGuard {
+            include_local: include_local,
+            served: HashMap::new(),
+            last_pruned: Instant::now(),
+        }
+    }
+
+    /// Notes a frame that `serve` is about to send.
+    pub fn record(&mut self, observed: &ObservedFrame) {
+        if let FrameSource::Local(_) = observed.source {
+            if !self.include_local {
+                return;
+            }
+        }
+        let served = (served_signal_level(observed), receive_timestamp(observed.frame.received_at), observed.received_at);
+        self.served.insert(observed.frame.bytes.clone(), served);
+
+        let now = observed.received_at;
+        if now.duration_since(self.last_pruned) > ECHO_LIFETIME {
+            self.served.retain(|_, &mut (_, _, served_at)| now.duration_since(served_at) < ECHO_LIFETIME);
+            self.last_pruned = now;
+        }
+    }
+
+    /// Whether a frame from one of our inputs is one we just served. Overlapping receivers hear the same
+    /// bytes all the time, so only a frame with the very timestamp and signal level we served it with
+    /// counts; one without them, as from an AVR feed, never does.
+    pub fn is_echo(&self, frame: &ModeSFrame, now: Instant) -> bool {
+        match self.served.get(&frame.bytes) {
+            Some(&(signal_level, mlat_timestamp, served_at)) if now.duration_since(served_at) < ECHO_LIFETIME => {
+                frame.signal_level == Some(signal_level) && frame.mlat_timestamp == Some(mlat_timestamp)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::encode_frame;
+    use super::EchoGuard;
+    use crate::beast::BeastDecoder;
+    use crate::beast::RELAYED_SIGNAL_LEVEL;
+    use crate::ingest::FrameDecoder;
+    use crate::ingest::ModeSFrame;
+    use crate::observer::FrameSource;
+    use crate::observer::ObservedFrame;
+    use std::time::Instant;
+    use std::time::SystemTime;
+
+    const FRAME: [u8; 14] = [0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98];
+
+    fn frame(mlat_timestamp: Option<u64>, signal_level: Option<u8>) -> ModeSFrame {
+        ModeSFrame {
+            bytes: FRAME.to_vec(),
+            mlat_timestamp: mlat_timestamp,
+            signal_level: signal_level,
+            received_at: SystemTime::now(),
+            receiver_location: None,
+        }
+    }
+
+    /// An echo guard that has just served `FRAME` as relayed by a partner
+    fn guard_with_relayed_frame() -> (EchoGuard, ObservedFrame) {
+        let observed = ObservedFrame {
+            source: FrameSource::Partner(7),
+            frame: frame(None, None),
+            position: None,
+            received_at: Instant::now(),
+        };
+        let mut echo_guard = EchoGuard::new(false);
+        echo_guard.record(&observed);
+        (echo_guard, observed)
+    }
+
+    #[test]
+    fn recognizes_what_it_served_coming_back() {
+        let (echo_guard, observed) = guard_with_relayed_frame();
+        let mut decoder = BeastDecoder::new();
+        decoder.push(&encode_frame(&observed).unwrap());
+        let echo = decoder.next_frame().unwrap();
+        assert!(echo_guard.is_echo(&echo, Instant::now()));
+    }
+
+    #[test]
+    fn a_local_avr_frame_is_not_an_echo() {
+        let (echo_guard, _) = guard_with_relayed_frame();
+        assert!(!echo_guard.is_echo(&frame(None, None), Instant::now()));
+    }
+
+    #[test]
+    fn a_local_beast_frame_at_the_relayed_level_is_not_an_echo() {
+        let (echo_guard, _) = guard_with_relayed_frame();
+        let heard = frame(Some(0x0123_4567_89AB), Some(RELAYED_SIGNAL_LEVEL));
+        assert!(!echo_guard.is_echo(&heard, Instant::now()));
+    }
+}
@@ -0,0 +1,185 @@
+use crate::data;
+use crate::frame_record;
+use crate::ingest::ModeSFrame;
+use crate::mlat::ReceiverLocation;
+use crate::node::Node;
+use crate::peel::peel_u8;
+use crate::peel::peel_slice;
+use std::cmp::max;
+use std::io;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Keeps a bundle, with the `Data` header and IPv6/UDP headers around it, within the 1280 byte minimum
+/// IPv6 MTU
+pub const DEFAULT_BUNDLE_BUDGET: usize = 1200;
+
+/// The largest bundle that can be sent at all: a `Data Bundle` packet must fit in one UDP datagram. A
+/// bundle this large is fragmented on almost any path, so the default stays within the minimum MTU.
+pub const MAX_BUNDLE_BUDGET: usize = 65507 - data::HEADER_LEN;
+
+/// The largest record a bundle can hold, since its length is a single byte
+pub const MAX_RECORD_LEN: usize = 255;
+
+/// Appends a record to a bundle, as `[Length: U8] [Record]`.
+pub fn push_record(bundle: &mut Vec<u8>, record: &[u8]) {
+    debug_assert!(record.len() <= MAX_RECORD_LEN);
+    bundle.push(record.len() as u8);
+    bundle.extend_from_slice(record);
+}
+
+/// Splits the payload of a `Data` packet back into its records. A last record cut short by the end of
+/// the bundle is dropped, and the records before it kept.
+pub fn split_records(mut bundle: &[u8]) -> Vec<&[u8]> {
+    let mut records = Vec::new();
+    while let Ok( (len, rest) ) = peel_u8(bundle) {
+        match peel_slice(rest, len as usize) {
+            Ok( (record, rest) ) => {
+                records.push(record);
+                bundle = rest;
+            }
+            Err(_) => break,
+        }
+    }
+    records
+}
+
+/// Collects records into a bundle until the bundle is either old enough or large enough to send
+pub struct Batcher {
+    bundle: Vec<u8>,
+    started: Option<Instant>,
+
+    /// How long the first record in a bundle may wait for others to join it. Zero sends every record
+    /// in a bundle of its own.
+    window: Duration,
+
+    /// The largest bundle to send, in bytes
+    budget: usize,
+}
+
+impl Batcher {
+    pub fn new(window: Duration, budget: usize) -> Batcher {
+        Batcher {
+            bundle: Vec::new(),
+            started: None,
+            window: window,
+            budget: budget,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bundle.is_empty()
+    }
+
+    /// Whether a record of `record_len` bytes can join the current bundle without going over budget
+    pub fn fits(&self, record_len: usize) -> bool {
+        self.bundle.is_empty() || self.bundle.len() + 1 + record_len <= self.budget
+    }
+
+    pub fn push(&mut self, record: &[u8], now: Instant) {
+        if self.started.is_none() {
+            self.started = Some(now);
+        }
+        push_record(&mut self.bundle, record);
+    }
+
+    /// Whether the bundle should be sent now
+    pub fn is_due(&self, now: Instant) -> bool {
+        match self.started {
+            Some(started) => now.duration_since(started) >= self.window || self.bundle.len() >= self.budget,
+            None => false,
+        }
+    }
+
+    /// Hands over the bundle and starts a new one.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.started = None;
+        std::mem::take(&mut self.bundle)
+    }
+}
+
+/// Gathers the frames our own receivers hear into bundles, and broadcasts each bundle to our partners
+/// when it is full or old enough
+pub struct Outbox {
+    node: Arc<Mutex<Node>>,
+    batcher: Batcher,
+
+    /// Where our receivers are, told to partners with each frame they could use for MLAT
+    receiver_location: Option<ReceiverLocation>,
+}
+
+impl Outbox {
+    pub fn new(node: Arc<Mutex<Node>>, window: Duration, budget: usize, receiver_location: Option<ReceiverLocation>) -> Outbox {
+        Outbox {
+            node: node,
+            batcher: Batcher::new(window, budget),
+            receiver_location: receiver_location,
+        }
+    }
+
+    pub fn window(&self) -> Duration {
+        self.batcher.window()
+    }
+
+    /// Adds a frame to the bundle being gathered, sending the bundle if it is full or old enough.
+    pub fn queue_frame(&mut self, frame: &ModeSFrame) -> io::Result<()> {
+        let frame = ModeSFrame {
+            receiver_location: frame.mlat_timestamp.and(self.receiver_location),
+            ..frame.clone()
+        };
+        let mut record = Vec::with_capacity(frame_record::encoded_len(&frame));
+        if !frame_record::encode(&frame, &mut record) {
+            return Ok( () );
+        }
+
+        let now = Instant::now();
+        let mut result = Ok( () );
+        if !self.batcher.fits(record.len()) {
+            result = self.flush();
+        }
+        self.batcher.push(&record, now);
+        if self.batcher.is_due(now) {
+            result = self.flush();
+        }
+        result
+    }
+
+    /// Sends the bundle being gathered, if there is one.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.batcher.is_empty() {
+            return Ok( () );
+        }
+        let bundle = self.batcher.take();
+        self.node.lock().unwrap().broadcast_bundle(&bundle)
+    }
+
+    /// Sends the bundle being gathered if its window has passed.
+    pub fn flush_due(&mut self, now: Instant) -> io::Result<()> {
+        if self.batcher.is_due(now) {
+            self.flush()
+        } else {
+            Ok( () )
+        }
+    }
+}
+
+/// Sends each bundle the outbox gathers once its window has passed, for as long as the program runs.
+/// Bundles that fill up are sent as soon as they do, without waiting for this.
+pub fn flush_periodically(outbox: Arc<Mutex<Outbox>>) {
+    let window = outbox.lock().unwrap().window();
+    // Checking twice per window keeps frames from waiting much more than a window.
+    let interval = max(window / 2, Duration::from_millis(1));
+    loop {
+        sleep(interval);
+        if let Err(e) = outbox.lock().unwrap().flush_due(Instant::now()) {
+            eprintln!("Failed to broadcast frames: {}", e);
+        }
+    }
+}
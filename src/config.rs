@@ -0,0 +1,335 @@
+use std::net::SocketAddr;
+use std::net::IpAddr;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::path::PathBuf;
+use crate::node::split_addressable;
+use crate::node::Addressable;
+use crate::ingest::Input;
+use crate::cpr::Position;
+use crate::sbs::SbsOrigins;
+use crate::bundle::DEFAULT_BUNDLE_BUDGET;
+use crate::bundle::MAX_BUNDLE_BUDGET;
+use crate::sequence_monitor::AnomalyAction;
+
+/// Settings chosen on the command line.
+pub struct Config {
+    /// Local address the UDP socket is bound to
+    pub bind_address: SocketAddr,
+
+    /// How other nodes should reach us, as advertised in `Subscribe` messages and the partner list
+    pub contact_host: String,
+    pub contact_port: u16,
+
+    /// Partnerships proposed by others are declined once we have this many
+    pub max_partners: usize,
+
+    /// If not empty, only these addresses may subscribe to us
+    pub allowed: HashSet<IpAddr>,
+
+    /// These addresses are permanently declined
+    pub denied: HashSet<IpAddr>,
+
+    /// Where to read local receiver frames from
+    pub inputs: Vec<Input>,
+
+    /// Whether frames with a single wrong bit are repaired rather than dropped
+    pub correct_errors: bool,
+
+    /// Where our own receiver is, so that positions can be decoded from single frames it hears
+    pub receiver_location: Option<Position>,
+
+    /// How high our own receiver's antenna is, in meters above sea level, for MLAT
+    pub receiver_altitude: f64,
+
+    /// Aircraft not heard from for this long are dropped from the aircraft table
+    pub aircraft_timeout: Duration,
+
+    /// Where to serve `aircraft.json` over HTTP, if anywhere
+    pub http_address: Option<SocketAddr>,
+
+    /// A file to keep rewriting with `aircraft.json`, and how often
+    pub json_file: Option<PathBuf>,
+    pub json_interval: Duration,
+
+    /// Where to serve BaseStation lines, if anywhere, and which frames to include in them
+    pub sbs_address: Option<SocketAddr>,
+    pub sbs_origins: SbsOrigins,
+
+    /// Where to serve frames in the Beast binary format, if anywhere, and whether to include our own
+    /// receivers' frames as well as partners'
+    pub beast_output_address: Option<SocketAddr>,
+    pub beast_output_local: bool,
+
+    /// How long local frames may wait to share a `Data` packet with others, and how large that packet's
+    /// payload may get
+    pub batch_window: Duration,
+    pub batch_budget: usize,
+
+    /// Whether to gather frames heard by several receivers into multilateration candidates
+    pub mlat: bool,
+
+    /// How many recent sequence numbers from each partner are judged, how suspicious they must look
+    /// for the partnership to be treated as compromised, and what happens to it then
+    pub sequence_window: usize,
+    pub sequence_threshold: f64,
+    pub anomaly_action: AnomalyAction,
+
+    /// How far behind the highest sequence number seen from a partner a packet may be and still be accepted
+    pub replay_window: u32,
+
+    /// Nodes to propose partnerships to and ask for partner lists when we know of no others
+    pub seeds: Vec<Addressable>,
+}
+
+/// Each partnership keeps a bit for every sequence number in its replay window
+const MAX_REPLAY_WINDOW: u32 = 1 << 16;
+
+fn parse_ip(value: String) -> Result<IpAddr, String> {
+    value.parse().map_err(|_| format!("Invalid IP address: {}", value))
+}
+
+/// Sequence scores are never negative, so neither is a threshold that means anything.
+fn parse_sequence_threshold(value: String) -> Result<f64, String> {
+    let threshold: f64 = value.parse().map_err(|_| format!("Invalid sequence threshold: {}", value))?;
+    if !threshold.is_finite() || threshold < 0.0 {
+        return Err(format!("Sequence threshold must be a finite number no less than 0, not {}", value));
+    }
+    Ok(threshold)
+}
+
+fn parse_location(value: String) -> Result<Position, String> {
+    let invalid = || format!("Location must be lat,lon in degrees, not {}", value);
+    let mut parts = value.split(',');
+    let lat: f64 = parts.next().and_then(|lat| lat.trim().parse().ok()).ok_or_else(invalid)?;
+    let lon: f64 = parts.next().and_then(|lon| lon.trim().parse().ok()).ok_or_else(invalid)?;
+    if parts.next().is_some() || !lat.is_finite() || !lon.is_finite() || lat.abs() > 90.0 || lon.abs() > 180.0 {
+        return Err(invalid());
+    }
+    Ok(Position{lat: lat, lon: lon})
+}
+
+impl Config {
+    pub fn from_args<I: Iterator<Item=String>>(mut args: I) -> Result<Config, String> {
+        let mut bind_address = SocketAddr::from(([0, 0, 0, 0], 4040));
+        let mut contact_method = None;
+        let mut max_partners = 40;
+        let mut allowed = HashSet::new();
+        let mut denied = HashSet::new();
+        let mut inputs = Vec::new();
+        let mut correct_errors = true;
+        let mut receiver_location = None;
+        let mut receiver_altitude = 0.0;
+        let mut aircraft_timeout = Duration::from_secs(60);
+        let mut http_address = None;
+        let mut json_file = None;
+        let mut json_interval = Duration::from_secs(1);
+        let mut sbs_address = None;
+        let mut sbs_origins = SbsOrigins::All;
+        let mut beast_output_address = None;
+        let mut beast_output_local = false;
+        let mut batch_window = Duration::from_millis(0);
+        let mut batch_budget = DEFAULT_BUNDLE_BUDGET;
+        let mut mlat = false;
+        let mut sequence_window = 32;
+        let mut sequence_threshold = 0.25;
+        let mut anomaly_action = AnomalyAction::Demote;
+        let mut replay_window = 256;
+        let mut seeds = Vec::new();
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+            match arg.as_str() {
+                "--bind" => {
+                    let value = value()?;
+                    bind_address = value.parse().map_err(|_| format!("Invalid bind address: {}", value))?;
+                }
+                "--contact" => {
+                    contact_method = Some(value()?);
+                }
+                "--max-partners" => {
+                    let value = value()?;
+                    max_partners = value.parse().map_err(|_| format!("Invalid partner count: {}", value))?;
+                }
+                "--allow" => {
+                    allowed.insert(parse_ip(value()?)?);
+                }
+                "--deny" => {
+                    denied.insert(parse_ip(value()?)?);
+                }
+                "--beast" => {
+                    inputs.push(Input::Beast(value()?));
+                }
+                "--avr" => {
+                    inputs.push(Input::Avr(value()?));
+                }
+                "--avr-file" => {
+                    inputs.push(Input::AvrFile(value()?.into()));
+                }
+                "--no-fix" => {
+                    correct_errors = false;
+                }
+                "--receiver-location" => {
+                    receiver_location = Some(parse_location(value()?)?);
+                }
+                "--receiver-altitude" => {
+                    let value = value()?;
+                    receiver_altitude = value.parse().map_err(|_| format!("Invalid receiver altitude in meters: {}", value))?;
+                    if !(-1000.0..=10_000.0).contains(&receiver_altitude) {
+                        return Err(format!("Receiver altitude must be from -1000 to 10000 meters, not {}", receiver_altitude));
+                    }
+                }
+                "--aircraft-timeout" => {
+                    let value = value()?;
+                    let seconds = value.parse().map_err(|_| format!("Invalid aircraft timeout: {}", value))?;
+                    aircraft_timeout = Duration::from_secs(seconds);
+                }
+                "--http" => {
+                    let value = value()?;
+                    http_address = Some(value.parse().map_err(|_| format!("Invalid HTTP address: {}", value))?);
+                }
+                "--json-file" => {
+                    json_file = Some(value()?.into());
+                }
+                "--json-interval" => {
+                    let value = value()?;
+                    let seconds: u64 = value.parse().map_err(|_| format!("Invalid JSON interval: {}", value))?;
+                    if seconds == 0 {
+                        return Err("JSON interval must be at least 1 second".to_string());
+                    }
+                    json_interval = Duration::from_secs(seconds);
+                }
+                "--sbs" => {
+                    let value = value()?;
+                    sbs_address = Some(value.parse().map_err(|_| format!("Invalid SBS address: {}", value))?);
+                }
+                "--sbs-local-only" => {
+                    sbs_origins = SbsOrigins::LocalOnly;
+                }
+                "--sbs-partners-only" => {
+                    sbs_origins = SbsOrigins::PartnersOnly;
+                }
+                "--beast-out" => {
+                    let value = value()?;
+                    beast_output_address = Some(value.parse().map_err(|_| format!("Invalid Beast output address: {}", value))?);
+                }
+                "--beast-out-local" => {
+                    beast_output_local = true;
+                }
+                "--batch-window" => {
+                    let value = value()?;
+                    let milliseconds = value.parse().map_err(|_| format!("Invalid batch window in milliseconds: {}", value))?;
+                    batch_window = Duration::from_millis(milliseconds);
+                }
+                "--mlat" => {
+                    mlat = true;
+                }
+                "--sequence-window" => {
+                    let value = value()?;
+                    sequence_window = value.parse().map_err(|_| format!("Invalid sequence window: {}", value))?;
+                    if sequence_window < 2 {
+                        return Err("Sequence window must hold at least 2 packets".to_string());
+                    }
+                }
+                "--sequence-threshold" => {
+                    let value = value()?;
+                    sequence_threshold = parse_sequence_threshold(value)?;
+                }
+                "--seed" => {
+                    let value = value()?;
+                    if split_addressable(&value).is_none() {
+                        return Err(format!("Seed must be host:port, not {}", value));
+                    }
+                    seeds.push(value);
+                }
+                "--replay-window" => {
+                    let value = value()?;
+                    replay_window = value.parse().map_err(|_| format!("Invalid replay window: {}", value))?;
+                    if replay_window == 0 || replay_window > MAX_REPLAY_WINDOW {
+                        return Err(format!("Replay window must be from 1 to {}, not {}", MAX_REPLAY_WINDOW, replay_window));
+                    }
+                }
+                "--on-sequence-anomaly" => {
+                    anomaly_action = match value()?.as_str() {
+                        "demote" => AnomalyAction::Demote,
+                        "drop" => AnomalyAction::Drop,
+                        other => return Err(format!("Sequence anomaly action must be demote or drop, not {}", other)),
+                    };
+                }
+                "--batch-budget" => {
+                    let value = value()?;
+                    batch_budget = value.parse().map_err(|_| format!("Invalid batch budget: {}", value))?;
+                    if !(64..=MAX_BUNDLE_BUDGET).contains(&batch_budget) {
+                        return Err(format!("Batch budget must be from 64 to {} bytes, not {}", MAX_BUNDLE_BUDGET, batch_budget));
+                    }
+                }
+                _ => {
+                    return Err(format!("Unrecognized argument: {}", arg));
+                }
+            }
+        }
+
+        let (contact_host, contact_port) = match contact_method {
+            Some(contact_method) => {
+                let (host, port) = split_addressable(&contact_method).ok_or_else(|| format!("Contact must be host:port, not {}", contact_method))?;
+                (host.to_string(), port)
+            }
+            None => ("self.mynode.net".to_string(), bind_address.port()),
+        };
+
+        Ok(Config {
+            bind_address: bind_address,
+            contact_host: contact_host,
+            contact_port: contact_port,
+            max_partners: max_partners,
+            allowed: allowed,
+            denied: denied,
+            inputs: inputs,
+            correct_errors: correct_errors,
+            receiver_location: receiver_location,
+            receiver_altitude: receiver_altitude,
+            aircraft_timeout: aircraft_timeout,
+            http_address: http_address,
+            json_file: json_file,
+            json_interval: json_interval,
+            sbs_address: sbs_address,
+            sbs_origins: sbs_origins,
+            beast_output_address: beast_output_address,
+            beast_output_local: beast_output_local,
+            batch_window: batch_window,
+            batch_budget: batch_budget,
+            mlat: mlat,
+            sequence_window: sequence_window,
+            sequence_threshold: sequence_threshold,
+            anomaly_action: anomaly_action,
+            replay_window: replay_window,
+            seeds: seeds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_location;
+    use super::parse_sequence_threshold;
+
+    #[test]
+    fn locations_must_be_real_coordinates() {
+        let location = parse_location("51.5, -0.12".to_string()).unwrap();
+        assert_eq!( (location.lat, location.lon), (51.5, -0.12) );
+
+        for bad in &["NaN,0", "0,NaN", "inf,0", "0,-inf", "91,0", "0,181", "1", "1,2,3", "a,b"] {
+            assert!(parse_location(bad.to_string()).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn sequence_thresholds_must_be_scores() {
+        assert_eq!(parse_sequence_threshold("0.25".to_string()), Ok(0.25));
+        assert_eq!(parse_sequence_threshold("0".to_string()), Ok(0.0));
+
+        for bad in &["NaN", "inf", "-inf", "-0.1", "a", ""] {
+            assert!(parse_sequence_threshold(bad.to_string()).is_err(), "{}", bad);
+        }
+    }
+}
@@ -0,0 +1,358 @@
+use crate::adsb::CprPosition;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The CPR encodings are 17-bit fractions of a zone
+const CPR_SCALE: f64 = 131072.0;
+
+/// An even/odd pair further apart than this may describe two different places
+const MAX_PAIR_AGE: Duration = Duration::from_secs(10);
+
+/// Surface movement is slow enough that pairs may be further apart
+const MAX_SURFACE_PAIR_AGE: Duration = Duration::from_secs(25);
+
+/// A previous position older than this is not trusted as a reference for local decoding
+const MAX_REFERENCE_AGE: Duration = Duration::from_secs(120);
+
+/// Aircraft not heard from in this long are forgotten
+const AIRCRAFT_LIFETIME: Duration = Duration::from_secs(300);
+
+/// Local decoding is only unambiguous within half a zone of the reference
+const MAX_AIRBORNE_REFERENCE_DISTANCE_NM: f64 = 180.0;
+const MAX_SURFACE_REFERENCE_DISTANCE_NM: f64 = 45.0;
+
+const EARTH_RADIUS_NM: f64 = 3440.065;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Position {
+    pub fn distance_nm(&self, other: &Position) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.lon - self.lon).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_NM * a.sqrt().asin()
+    }
+}
+
+/// The positive remainder, as CPR requires
+fn modulo(x: f64, y: f64) -> f64 {
+    x - y * (x / y).floor()
+}
+
+/// The number of longitude zones at a latitude
+fn nl(lat: f64) -> u32 {
+    let lat = lat.abs();
+    if lat < 1e-9 {
+        return 59;
+    }
+    if (lat - 87.0).abs() < 1e-9 {
+        return 2;
+    }
+    if lat > 87.0 {
+        return 1;
+    }
+
+    let nz = 15.0;
+    let a = 1.0 - (PI / (2.0 * nz)).cos();
+    let b = (PI / 180.0 * lat).cos().powi(2);
+    (2.0 * PI / (1.0 - a / b).acos()).floor() as u32
+}
+
+fn normalize_lon(lon: f64) -> f64 {
+    modulo(lon + 180.0, 360.0) - 180.0
+}
+
+/// Decodes an even/odd pair of airborne encodings without any reference. `odd_is_newer` picks which
+/// of the two the answer describes.
+pub fn global_airborne(even: &CprPosition, odd: &CprPosition, odd_is_newer: bool) -> Option<Position> {
+    let south = |lat: f64| if lat >= 270.0 { lat - 360.0 } else { lat };
+    let (lat_even, lat_odd) = global_latitudes(even, odd, 360.0);
+    let (lat_even, lat_odd) = (south(lat_even), south(lat_odd));
+    if lat_even.abs() > 90.0 || lat_odd.abs() > 90.0 {
+        return None;
+    }
+
+    global_longitude(even, odd, odd_is_newer, 360.0, lat_even, lat_odd).map(|(lat, lon)| {
+        Position {
+            lat: lat,
+            lon: normalize_lon(lon),
+        }
+    })
+}
+
+/// Decodes an even/odd pair of surface encodings. Each one fits four places around the globe, so
+/// `reference` (anywhere within a few hundred miles) picks the right one.
+pub fn global_surface(even: &CprPosition, odd: &CprPosition, odd_is_newer: bool, reference: &Position) -> Option<Position> {
+    let (lat_even, lat_odd) = global_latitudes(even, odd, 90.0);
+
+    // The latitude is either in the northern hemisphere or 90 degrees south of that. The hemisphere must
+    // be settled first, since it decides how many longitude zones there are.
+    let newer = if odd_is_newer { lat_odd } else { lat_even };
+    let (lat_even, lat_odd) = if (newer - 90.0 - reference.lat).abs() < (newer - reference.lat).abs() {
+        (lat_even - 90.0, lat_odd - 90.0)
+    } else {
+        (lat_even, lat_odd)
+    };
+
+    let (lat, lon) = global_longitude(even, odd, odd_is_newer, 90.0, lat_even, lat_odd)?;
+
+    // The longitude is in one of four quadrants.
+    let lon = (0..4)
+        .map(|quadrant| normalize_lon(lon + 90.0 * quadrant as f64))
+        .min_by(|a, b| {
+            let distance = |lon: f64| normalize_lon(lon - reference.lon).abs();
+            distance(*a).partial_cmp(&distance(*b)).unwrap()
+        })?;
+
+    Some(Position{lat: lat, lon: lon})
+}
+
+/// The latitudes of an even/odd pair, in the first `span` degrees north of the equator; `span` is 360
+/// degrees for airborne and 90 for surface encodings.
+fn global_latitudes(even: &CprPosition, odd: &CprPosition, span: f64) -> (f64, f64) {
+    let (lat_even, lat_odd) = (even.lat as f64 / CPR_SCALE, odd.lat as f64 / CPR_SCALE);
+    let j = (59.0 * lat_even - 60.0 * lat_odd + 0.5).floor();
+    (
+        span / 60.0 * (modulo(j, 60.0) + lat_even),
+        span / 59.0 * (modulo(j, 59.0) + lat_odd),
+    )
+}
+
+/// The latitude and longitude of the newer of a pair, given the pair's latitudes in their true hemisphere.
+fn global_longitude(even: &CprPosition, odd: &CprPosition, odd_is_newer: bool, span: f64, lat_even: f64, lat_odd: f64) -> Option<(f64, f64)> {
+    // Both must lie in the same longitude zone band, or the pair straddles a boundary and cannot be combined.
+    let nl_even = nl(lat_even);
+    if nl_even != nl(lat_odd) {
+        return None;
+    }
+
+    let (lon_even, lon_odd) = (even.lon as f64 / CPR_SCALE, odd.lon as f64 / CPR_SCALE);
+    let (lat, lon, odd_offset) = if odd_is_newer {
+        (lat_odd, lon_odd, 1)
+    } else {
+        (lat_even, lon_even, 0)
+    };
+
+    let ni = (nl_even.saturating_sub(odd_offset)).max(1) as f64;
+    let nl_even = nl_even as f64;
+    let m = (lon_even * (nl_even - 1.0) - lon_odd * nl_even + 0.5).floor();
+    let lon = (span / ni) * (modulo(m, ni) + lon);
+
+    Some( (lat, lon) )
+}
+
+/// Decodes a single encoding using a reference position within half a zone of the answer.
+pub fn local(cpr: &CprPosition, surface: bool, reference: &Position) -> Position {
+    let span = if surface { 90.0 } else { 360.0 };
+    let odd_offset = if cpr.odd { 1.0 } else { 0.0 };
+    let lat = cpr.lat as f64 / CPR_SCALE;
+    let lon = cpr.lon as f64 / CPR_SCALE;
+
+    let dlat = span / (60.0 - odd_offset);
+    let j = (reference.lat / dlat).floor() + (0.5 + modulo(reference.lat, dlat) / dlat - lat).floor();
+    let rlat = dlat * (j + lat);
+
+    let ni = (nl(rlat) as f64 - odd_offset).max(1.0);
+    let dlon = span / ni;
+    let m = (reference.lon / dlon).floor() + (0.5 + modulo(reference.lon, dlon) / dlon - lon).floor();
+    let rlon = dlon * (m + lon);
+
+    Position{lat: rlat, lon: normalize_lon(rlon)}
+}
+
+#[derive(Default)]
+struct AircraftCpr {
+    surface: bool,
+    even: Option<(CprPosition, Instant)>,
+    odd: Option<(CprPosition, Instant)>,
+    last_position: Option<(Position, Instant)>,
+}
+
+/// Turns the stream of CPR encodings from many aircraft into positions. State is kept per address, so
+/// the two halves of a pair may come from different receivers, whether ours or our partners'.
+pub struct PositionResolver {
+    receiver_location: Option<Position>,
+    aircraft: HashMap<u32, AircraftCpr>,
+    last_pruned: Instant,
+}
+
+impl PositionResolver {
+    /// `receiver_location` is where our own receiver is, if it is known.
+    pub fn new(receiver_location: Option<Position>) -> PositionResolver {
+        PositionResolver {
+            receiver_location: receiver_location,
+            aircraft: HashMap::new(),
+            last_pruned: Instant::now(),
+        }
+    }
+
+    /// Records an encoding and returns the position it resolves to, if one can be found yet.
+    /// `heard_locally` says the frame came from our own receiver, so the aircraft must be within range of it.
+    pub fn resolve(&mut self, address: u32, cpr: CprPosition, surface: bool, heard_locally: bool, now: Instant) -> Option<Position> {
+        if now.duration_since(self.last_pruned) > AIRCRAFT_LIFETIME {
+            self.expire(now, AIRCRAFT_LIFETIME);
+            self.last_pruned = now;
+        }
+
+        let receiver_location = if heard_locally { self.receiver_location } else { None };
+        let state = self.aircraft.entry(address).or_default();
+
+        if state.surface != surface {
+            // Airborne and surface encodings cannot be paired with each other.
+            state.surface = surface;
+            state.even = None;
+            state.odd = None;
+        }
+
+        if cpr.odd {
+            state.odd = Some( (cpr, now) );
+        } else {
+            state.even = Some( (cpr, now) );
+        }
+
+        let recent_position = state.last_position
+            .filter(|&(_, when)| now.duration_since(when) < MAX_REFERENCE_AGE)
+            .map(|(position, _)| position);
+        let reference = recent_position.or(receiver_location);
+
+        let max_pair_age = if surface { MAX_SURFACE_PAIR_AGE } else { MAX_PAIR_AGE };
+        let global_position = match (state.even, state.odd) {
+            (Some((even, even_time)), Some((odd, odd_time))) if duration_between(even_time, odd_time) < max_pair_age => {
+                if surface {
+                    reference.and_then(|reference| global_surface(&even, &odd, cpr.odd, &reference))
+                } else {
+                    global_airborne(&even, &odd, cpr.odd)
+                }
+            }
+            _ => None,
+        };
+
+        let max_reference_distance = if surface { MAX_SURFACE_REFERENCE_DISTANCE_NM } else { MAX_AIRBORNE_REFERENCE_DISTANCE_NM };
+        let position = match (global_position, reference) {
+            // A global result far from where the aircraft just was is more likely a bad pair than a jump.
+            (Some(position), Some(reference)) if recent_position.is_some() && position.distance_nm(&reference) > max_reference_distance => None,
+            (Some(position), _) => Some(position),
+            (None, Some(reference)) => {
+                let position = local(&cpr, surface, &reference);
+                if position.distance_nm(&reference) < max_reference_distance {
+                    Some(position)
+                } else {
+                    None
+                }
+            }
+            (None, None) => None,
+        };
+
+        if let Some(position) = position {
+            state.last_position = Some( (position, now) );
+        }
+        position
+    }
+
+    /// The most recent position resolved for an aircraft
+    #[allow(dead_code)]
+    pub fn position(&self, address: u32) -> Option<Position> {
+        self.aircraft.get(&address)
+            .and_then(|state| state.last_position)
+            .map(|(position, _)| position)
+    }
+
+    /// Forgets aircraft that have not been heard from in `max_age`
+    pub fn expire(&mut self, now: Instant, max_age: Duration) {
+        self.aircraft.retain(|_, state| {
+            let last_heard = [state.even, state.odd].iter()
+                .filter_map(|half| half.map(|(_, when)| when))
+                .max();
+            last_heard.map(|when| now.duration_since(when) < max_age).unwrap_or(false)
+        });
+    }
+}
+
+fn duration_between(a: Instant, b: Instant) -> Duration {
+    if a > b { a - b } else { b - a }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::global_airborne;
+    use super::global_surface;
+    use super::local;
+    use super::modulo;
+    use super::nl;
+    use super::Position;
+    use super::CPR_SCALE;
+    use crate::adsb::CprPosition;
+
+    // Examples from "The 1090 Megahertz Riddle": KLM1023 cruising, and an aircraft taxiing at Schiphol.
+    const AIRBORNE_EVEN: CprPosition = CprPosition{odd: false, lat: 93000, lon: 51372};
+    const AIRBORNE_ODD: CprPosition = CprPosition{odd: true, lat: 74158, lon: 50194};
+    const SURFACE_EVEN: CprPosition = CprPosition{odd: false, lat: 115609, lon: 116941};
+    const SURFACE_ODD: CprPosition = CprPosition{odd: true, lat: 39199, lon: 110269};
+
+    /// Encodes a position as a transmitter would, with `span` 360 degrees for airborne and 90 for surface
+    fn encode(position: Position, odd: bool, span: f64) -> CprPosition {
+        let i = if odd { 1.0 } else { 0.0 };
+        let dlat = span / (60.0 - i);
+        let yz = (CPR_SCALE * modulo(position.lat, dlat) / dlat + 0.5).floor();
+        let rlat = dlat * (yz / CPR_SCALE + (position.lat / dlat).floor());
+        let dlon = span / (nl(rlat) as f64 - i).max(1.0);
+        let xz = (CPR_SCALE * modulo(position.lon, dlon) / dlon + 0.5).floor();
+        CprPosition {
+            odd: odd,
+            lat: modulo(yz, CPR_SCALE) as u32,
+            lon: modulo(xz, CPR_SCALE) as u32,
+        }
+    }
+
+    fn assert_near(position: Position, lat: f64, lon: f64) {
+        assert!((position.lat - lat).abs() < 1e-4 && (position.lon - lon).abs() < 1e-4, "{:?} is not {}, {}", position, lat, lon);
+    }
+
+    #[test]
+    fn decodes_an_airborne_pair() {
+        assert_near(global_airborne(&AIRBORNE_EVEN, &AIRBORNE_ODD, false).unwrap(), 52.25720, 3.91937);
+    }
+
+    #[test]
+    fn decodes_an_airborne_encoding_near_a_reference() {
+        let reference = Position{lat: 52.258, lon: 3.918};
+        assert_near(local(&AIRBORNE_EVEN, false, &reference), 52.25720, 3.91937);
+    }
+
+    #[test]
+    fn decodes_a_northern_surface_pair() {
+        let reference = Position{lat: 51.990, lon: 4.375};
+        assert_near(global_surface(&SURFACE_EVEN, &SURFACE_ODD, true, &reference).unwrap(), 52.32061, 4.73473);
+    }
+
+    #[test]
+    fn decodes_a_southern_surface_pair() {
+        // Sydney airport, where the number of longitude zones differs from that 90 degrees north of it
+        let airport = Position{lat: -33.9461, lon: 151.1772};
+        let even = encode(airport, false, 90.0);
+        let odd = encode(airport, true, 90.0);
+        let reference = Position{lat: -33.8, lon: 151.0};
+        assert_near(global_surface(&even, &odd, true, &reference).unwrap(), airport.lat, airport.lon);
+        assert_near(global_surface(&even, &odd, false, &reference).unwrap(), airport.lat, airport.lon);
+        assert_near(local(&odd, true, &reference), airport.lat, airport.lon);
+    }
+
+    #[test]
+    fn rejects_a_pair_from_different_longitude_zone_bands() {
+        // The number of zones drops from 59 to 58 at about 10.4705 degrees.
+        let even = encode(Position{lat: 10.465, lon: 20.0}, false, 360.0);
+        let odd = encode(Position{lat: 10.476, lon: 20.0}, true, 360.0);
+        assert!(global_airborne(&even, &odd, true).is_none());
+
+        let odd = encode(Position{lat: 10.466, lon: 20.0}, true, 360.0);
+        assert_near(global_airborne(&even, &odd, true).unwrap(), 10.466, 20.0);
+    }
+}
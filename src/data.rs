@@ -1,41 +1,363 @@
+use crate::node::Node;
+use crate::node::HandleError;
+use crate::message;
+use crate::node::ReceivedData;
+use crate::frame_record;
+use crate::ingest::ModeSFrame;
+use crate::bundle::split_records;
+use std::net::SocketAddr;
 use crypto::poly1305::Poly1305;
 use crypto::mac::Mac;
+use crypto::util::fixed_time_eq;
+use std::time::SystemTime;
 
-struct Data<'a> {
-    partnering_id: u32,
-    signature: [u8; 16],
-    data: &'a [u8],
+/// The length of a `Data` or `Data Bundle` packet without its payload: type, partnering ID, signature
+/// and sequence number
+pub const HEADER_LEN: usize = 1 + 4 + 16 + 4;
+
+/// How a data packet's payload is laid out, which its type byte says
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadFormat {
+    /// `Data`: one frame as it was heard, or nothing for a keep-alive
+    Frame,
+
+    /// `Data Bundle`: length-prefixed records, each a frame with the details of how it was heard
+    Bundle,
+}
+
+impl PayloadFormat {
+    fn type_byte(self) -> u8 {
+        match self {
+            PayloadFormat::Frame => message::DATA,
+            PayloadFormat::Bundle => message::DATA_BUNDLE,
+        }
+    }
+}
+
+pub struct Data<'a> {
+    pub format: PayloadFormat,
+    pub partnering_id: u32,
+    pub signature: [u8; 16],
+    pub sequence_number: u32,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Data<'a> {
+    fn is_signed_by(&self, key: &[u8; 32]) -> bool {
+        // The signature covers the sequence number and payload, and for a bundle the type byte too.
+        let mut expected = [0u8; 16];
+        let mut signer = Poly1305::new(&key[..]);
+        if self.format == PayloadFormat::Bundle {
+            signer.input(&[message::DATA_BUNDLE]);
+        }
+        signer.input(&self.sequence_number.to_le_bytes()[..]);
+        signer.input(self.payload);
+        signer.raw_result(&mut expected);
+
+        fixed_time_eq(&expected[..], &self.signature[..])
+    }
 }
 
 /// `DataSerializer` turns a data payload into a data packet, optimized for sending the same payload to
 /// multiple recipients.
 pub struct DataSerializer {
+    format: PayloadFormat,
     buf: Vec<u8>
 }
 
 impl DataSerializer {
-    pub fn new(sequence_number: u32, payload: &[u8]) -> DataSerializer {
-        let capacity = 1 + 4 + 16 + 4 +  payload.len();
+    pub fn new(sequence_number: u32, format: PayloadFormat, payload: &[u8]) -> DataSerializer {
+        let capacity = HEADER_LEN + payload.len();
         let mut xs = Vec::with_capacity(capacity);
-        xs.push(5); // Packet type
+        xs.push(format.type_byte());
         xs.resize(1 + 4 + 16, 0); // Make space for the partnering_id and signature
         xs.extend_from_slice(&sequence_number.to_le_bytes()[..]);
-        
+
         xs.extend_from_slice(payload);
         debug_assert!(xs.len() == capacity);
-        
+
         DataSerializer {
+            format: format,
             buf: xs
         }
     }
 
     pub fn serialize_for(&mut self, partnering_id: u32, key: &[u8; 32]) -> &[u8] {
-        (&mut self.buf[1..1+4]).copy_from_slice(&partnering_id.to_le_bytes()[..]);
-        
+        self.buf[1..1+4].copy_from_slice(&partnering_id.to_le_bytes()[..]);
+
         let mut signer = Poly1305::new(&key[..]);
+        if self.format == PayloadFormat::Bundle {
+            signer.input(&self.buf[..1]);
+        }
         signer.input(&self.buf[1+4+16..]);
         signer.raw_result(&mut self.buf[1+4..1+4+16]);
-        
+
         &self.buf
     }
 }
+
+pub fn handle_data(node: &mut Node, _source: &SocketAddr, message: Data) -> Result<(), HandleError> {
+    let key = node.get_partnership(message.partnering_id).ok_or(HandleError::UnknownPartneringId)?.key;
+    if !message.is_signed_by(&key) {
+        return Err(HandleError::InvalidSignature);
+    }
+
+    // The sequence monitor sees every signed packet, replayed and stale ones included: a second sender
+    // using the key shows up as exactly those. An eavesdropper replaying packets can therefore get the
+    // partnership demoted, but not get anything it sent accepted twice.
+    let is_new = node.check_replay(message.partnering_id, message.sequence_number);
+    if !node.check_sequence_number(message.partnering_id, message.sequence_number) || !is_new {
+        return Ok( () );
+    }
+
+    let mut frames = Vec::new();
+    match message.format {
+        PayloadFormat::Frame => {
+            // An empty payload is a keep-alive and has no frame.
+            if !message.payload.is_empty() {
+                frames.push(ModeSFrame {
+                    bytes: message.payload.to_vec(),
+                    mlat_timestamp: None,
+                    signal_level: None,
+                    received_at: SystemTime::now(),
+                    receiver_location: None,
+                });
+            }
+        }
+        PayloadFormat::Bundle => {
+            // The sequence number is already spent, so a record that cannot be read is skipped rather
+            // than costing the rest of the packet.
+            for record in split_records(message.payload) {
+                match frame_record::decode(record) {
+                    Ok(frame) => frames.push(frame),
+                    Err(_) => node.count_undecodable_record(message.partnering_id),
+                }
+            }
+        }
+    }
+
+    node.deliver_data(ReceivedData{
+        partnering_id: message.partnering_id,
+        sequence_number: message.sequence_number,
+        frames: frames,
+    });
+
+    Ok( () )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DataSerializer;
+    use super::PayloadFormat;
+    use crate::bundle::push_record;
+    use crate::message;
+    use crate::node::HandleError;
+    use crate::frame_record;
+    use crate::ingest::ModeSFrame;
+    use crate::node::Node;
+    use crate::node::Partnership;
+    use crate::node::PartnershipEvent;
+    use crate::sequence_monitor::AnomalyAction;
+    use crate::simulated_network::LinkConditions;
+    use crate::simulated_network::SimulatedNetwork;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::mpsc::Receiver;
+    use std::time::UNIX_EPOCH;
+
+    const PARTNERING_ID: u32 = 7;
+    const KEY: [u8; 32] = [3; 32];
+
+    fn partner_address() -> SocketAddr {
+        "10.0.0.2:4040".parse().unwrap()
+    }
+
+    fn node_with_partner(network: &SimulatedNetwork) -> Node {
+        let address: SocketAddr = "10.0.0.1:4040".parse().unwrap();
+        let mut node = Node::new(address.ip().to_string(), address.port(), Arc::new(network.endpoint(address).unwrap()));
+        node.add_active_partnership(Partnership {
+            address: partner_address().to_string(),
+            resolved_address: Some(partner_address()),
+            key: KEY,
+            id: PARTNERING_ID,
+        });
+        node
+    }
+
+    fn keep_alive(sequence_number: u32) -> Vec<u8> {
+        DataSerializer::new(sequence_number, PayloadFormat::Frame, &[]).serialize_for(PARTNERING_ID, &KEY).to_vec()
+    }
+
+    #[test]
+    fn delivers_each_packet_once() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+        let data = node.listen_for_data();
+
+        for &sequence_number in &[10, 12, 11, 12, 10] {
+            node.handle_received_packet(&partner_address(), &keep_alive(sequence_number)).unwrap();
+        }
+
+        let delivered: Vec<u32> = data.try_iter().map(|data| data.sequence_number).collect();
+        assert_eq!(delivered, vec![10, 12, 11]);
+        assert_eq!(node.stats().partners[&PARTNERING_ID].replayed, 2);
+    }
+
+    #[test]
+    fn a_steady_partner_is_not_flagged() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+        let events = node.listen_for_partnership_events();
+
+        for sequence_number in 1000..1200 {
+            node.handle_received_packet(&partner_address(), &keep_alive(sequence_number)).unwrap();
+        }
+
+        assert!(events.try_recv().is_err());
+        assert_eq!(node.active_partnership_count(), 1);
+    }
+
+    /// Sends packets as the real partner and someone else who has its key would, each counting up from
+    /// its own place, until the monitor trips. The replay window only lets one of them through, but the
+    /// monitor hears both.
+    fn share_key_until_tripped(node: &mut Node, events: &Receiver<PartnershipEvent>, start: u32) -> Option<PartnershipEvent> {
+        for i in start..start + 32 {
+            for &sequence_number in &[1000 + i, 5_000_000 + i] {
+                node.handle_received_packet(&partner_address(), &keep_alive(sequence_number)).unwrap();
+                if let Ok(event) = events.try_recv() {
+                    return Some(event);
+                }
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn two_senders_sharing_a_key_trip_the_monitor() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+        let events = node.listen_for_partnership_events();
+
+        match share_key_until_tripped(&mut node, &events, 0) {
+            Some(PartnershipEvent::SequenceAnomaly{partnering_id, action, ..}) => {
+                assert_eq!(partnering_id, PARTNERING_ID);
+                assert_eq!(action, AnomalyAction::Demote);
+            }
+            None => panic!("the monitor did not trip"),
+        }
+        assert_eq!(node.active_partnership_count(), 0);
+        assert!(node.get_partnership(PARTNERING_ID).is_some());
+    }
+
+    #[test]
+    fn a_demoted_partnership_that_trips_again_is_dropped() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+        let events = node.listen_for_partnership_events();
+        assert!(share_key_until_tripped(&mut node, &events, 0).is_some());
+
+        // A demoted partnership is judged on a fresh window, so a single odd packet does not drop it.
+        node.handle_received_packet(&partner_address(), &keep_alive(9_000_000)).unwrap();
+        assert!(events.try_recv().is_err());
+        assert!(node.get_partnership(PARTNERING_ID).is_some());
+
+        match share_key_until_tripped(&mut node, &events, 100) {
+            Some(PartnershipEvent::SequenceAnomaly{partnering_id, action, ..}) => {
+                assert_eq!(partnering_id, PARTNERING_ID);
+                assert_eq!(action, AnomalyAction::Drop);
+            }
+            None => panic!("the monitor did not trip again"),
+        }
+        assert!(node.get_partnership(PARTNERING_ID).is_none());
+    }
+
+    #[test]
+    fn skips_records_it_cannot_read() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+        let data = node.listen_for_data();
+
+        let mut record = Vec::new();
+        frame_record::encode(&ModeSFrame {
+            bytes: vec![0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98],
+            mlat_timestamp: None,
+            signal_level: None,
+            received_at: UNIX_EPOCH,
+            receiver_location: None,
+        }, &mut record);
+
+        let mut bundle = Vec::new();
+        push_record(&mut bundle, &record);
+        push_record(&mut bundle, &[0x7F, 0x00]);
+        push_record(&mut bundle, &record[..5]);
+        push_record(&mut bundle, &record);
+        bundle.extend_from_slice(&[200, 1, 2, 3]);
+
+        let packet = DataSerializer::new(1, PayloadFormat::Bundle, &bundle).serialize_for(PARTNERING_ID, &KEY).to_vec();
+        node.handle_received_packet(&partner_address(), &packet).unwrap();
+
+        let delivered = data.try_recv().unwrap();
+        assert_eq!(delivered.frames.len(), 2);
+        assert_eq!(node.stats().partners[&PARTNERING_ID].undecodable, 2);
+    }
+
+    #[test]
+    fn a_data_packet_carries_one_plain_frame() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+        let data = node.listen_for_data();
+
+        let frame = [0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98];
+        let packet = DataSerializer::new(1, PayloadFormat::Frame, &frame).serialize_for(PARTNERING_ID, &KEY).to_vec();
+        node.handle_received_packet(&partner_address(), &packet).unwrap();
+
+        let delivered = data.try_recv().unwrap();
+        assert_eq!(delivered.frames.len(), 1);
+        assert_eq!(delivered.frames[0].bytes, frame.to_vec());
+    }
+
+    #[test]
+    fn the_type_byte_of_a_bundle_is_signed() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+
+        let mut packet = DataSerializer::new(1, PayloadFormat::Bundle, &[]).serialize_for(PARTNERING_ID, &KEY).to_vec();
+        packet[0] = message::DATA;
+        assert!(matches!(node.handle_received_packet(&partner_address(), &packet), Err(HandleError::InvalidSignature)));
+
+        packet[0] = message::DATA_BUNDLE;
+        assert!(node.handle_received_packet(&partner_address(), &packet).is_ok());
+    }
+
+    #[test]
+    fn packets_from_strangers_are_rejected() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+        let data = node.listen_for_data();
+
+        let packet = DataSerializer::new(1, PayloadFormat::Frame, &[]).serialize_for(PARTNERING_ID + 1, &KEY).to_vec();
+        assert!(matches!(node.handle_received_packet(&partner_address(), &packet), Err(HandleError::UnknownPartneringId)));
+        assert!(data.try_recv().is_err());
+    }
+
+    #[test]
+    fn packets_that_do_not_match_their_signature_are_rejected() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let mut node = node_with_partner(&network);
+        let data = node.listen_for_data();
+
+        let wrong_key = DataSerializer::new(1, PayloadFormat::Frame, &[]).serialize_for(PARTNERING_ID, &[4; 32]).to_vec();
+        assert!(matches!(node.handle_received_packet(&partner_address(), &wrong_key), Err(HandleError::InvalidSignature)));
+
+        let mut tampered = DataSerializer::new(2, PayloadFormat::Frame, &[1, 2, 3]).serialize_for(PARTNERING_ID, &KEY).to_vec();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(matches!(node.handle_received_packet(&partner_address(), &tampered), Err(HandleError::InvalidSignature)));
+
+        // Rejected packets spend no sequence numbers, so the genuine ones still get through.
+        node.handle_received_packet(&partner_address(), &keep_alive(1)).unwrap();
+        node.handle_received_packet(&partner_address(), &keep_alive(2)).unwrap();
+        let delivered: Vec<u32> = data.try_iter().map(|data| data.sequence_number).collect();
+        assert_eq!(delivered, vec![1, 2]);
+    }
+}
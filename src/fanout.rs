@@ -0,0 +1,114 @@
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::time::Duration;
+
+/// A client that cannot take a message within this long is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client with this many messages waiting has fallen too far behind, and is dropped
+const MAX_QUEUED_MESSAGES: usize = 1024;
+
+/// Where each client's writer thread takes its messages from
+type Client = SyncSender<Arc<Vec<u8>>>;
+
+/// Sends each message to every client connected to a TCP listener. Every client is written to by a
+/// thread of its own from a bounded queue, so a client that stops reading holds up nobody but itself.
+pub struct Fanout {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+fn write_messages(mut stream: TcpStream, messages: impl Iterator<Item=Arc<Vec<u8>>>) {
+    for message in messages {
+        if stream.write_all(&message).is_err() {
+            return;
+        }
+    }
+}
+
+fn accept_clients(listener: TcpListener, name: &'static str, clients: Arc<Mutex<Vec<Client>>>) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = stream.set_write_timeout(Some(WRITE_TIMEOUT)) {
+                    eprintln!("Failed to set up {} client: {}", name, e);
+                    continue;
+                }
+                let (sender, messages) = sync_channel(MAX_QUEUED_MESSAGES);
+                thread::spawn(move || {
+                    write_messages(stream, messages.into_iter())
+                });
+                clients.lock().unwrap().push(sender);
+            }
+            Err(e) => {
+                eprintln!("Failed to accept {} connection: {}", name, e);
+            }
+        }
+    }
+}
+
+impl Fanout {
+    /// Starts accepting clients on `listener`. `name` says what is being served, for logging.
+    pub fn new(listener: TcpListener, name: &'static str) -> Fanout {
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let thread_clients = clients.clone();
+        thread::spawn(move || {
+            accept_clients(listener, name, thread_clients)
+        });
+
+        Fanout {
+            clients: clients,
+        }
+    }
+
+    /// Queues `message` for every client without waiting for any of them. Clients that have gone away
+    /// or fallen too far behind are dropped.
+    pub fn send(&self, message: Vec<u8>) {
+        let message = Arc::new(message);
+        self.clients.lock().unwrap().retain(|client| client.try_send(message.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Fanout;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::thread;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn a_stalled_client_does_not_hold_up_the_others() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let fanout = Fanout::new(listener, "test");
+
+        let _stalled = TcpStream::connect(address).unwrap();
+        let mut reading = TcpStream::connect(address).unwrap();
+        sleep(Duration::from_millis(100));
+
+        // Far more than a stalled client's socket buffers can take
+        const MESSAGES: usize = 500;
+        const MESSAGE_LEN: usize = 64 * 1024;
+        let reader = thread::spawn(move || {
+            let mut buf = vec![0u8; MESSAGES * MESSAGE_LEN];
+            reading.read_exact(&mut buf).unwrap();
+            buf.iter().all(|&b| b == 0xA5)
+        });
+
+        let started = Instant::now();
+        for _ in 0..MESSAGES {
+            fanout.send(vec![0xA5; MESSAGE_LEN]);
+        }
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(reader.join().unwrap());
+    }
+}
@@ -0,0 +1,235 @@
+use crate::node::Addressable;
+use crate::node::DataRequestResolution;
+use crate::node::Node;
+use crate::partner_list::PartnerListAssembler;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+/// The largest UDP payload that fits in the minimum IPv6 MTU of 1280 bytes, which avoids fragmentation
+/// on any path
+const MAX_DATAGRAM_PAYLOAD: usize = 1280 - 40 - 8;
+
+/// The header on a `Profile Request` or `Partner List Request`: type, token, and start index. The
+/// padding makes a request as long as what it asks for, so the request, not the response, is the limit.
+const REQUEST_HEADER_LEN: usize = 1 + 4 + 4;
+
+/// How much to ask for at once so that neither a request nor its response is fragmented
+pub const DEFAULT_CHUNK_LEN: usize = MAX_DATAGRAM_PAYLOAD - REQUEST_HEADER_LEN;
+
+#[derive(Debug)]
+#[allow(dead_code)] // Only `fetch_profile` returns these, and nothing calls it yet.
+pub enum FetchError {
+    SendFailed(io::Error),
+
+    /// A chunk went unanswered every time it was asked for
+    TimedOut,
+
+    /// The profile was longer than we were willing to read
+    TooLong,
+}
+
+impl From<io::Error> for FetchError {
+    fn from(e: io::Error) -> FetchError {
+        FetchError::SendFailed(e)
+    }
+}
+
+/// How a whole profile or partner list is fetched
+#[derive(Clone, Debug)]
+pub struct FetchOptions {
+    /// Bytes to ask for in each request
+    pub chunk_len: usize,
+
+    /// A profile longer than this is rejected, and a partner list is read no further
+    pub max_len: usize,
+
+    /// How long to wait for each response
+    pub timeout: Duration,
+
+    /// How many times to ask again for a chunk that went unanswered
+    pub retries: u32,
+}
+
+impl Default for FetchOptions {
+    fn default() -> FetchOptions {
+        FetchOptions {
+            chunk_len: DEFAULT_CHUNK_LEN,
+            max_len: 64 * 1024,
+            timeout: Duration::from_secs(5),
+            retries: 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Resource {
+    Profile,
+    PartnerList,
+}
+
+impl Resource {
+    fn send_request(self, node: &mut Node, destination: &SocketAddr, start: u32, len: usize) -> io::Result<(u32, Receiver<DataRequestResolution>)> {
+        match self {
+            Resource::Profile => node.send_profile_request(destination, start, len),
+            Resource::PartnerList => node.send_partner_list_request(destination, start, len),
+        }
+    }
+
+    fn cancel_request(self, node: &mut Node, token: u32) {
+        match self {
+            Resource::Profile => node.cancel_profile_request(token),
+            Resource::PartnerList => node.cancel_partner_list_request(token),
+        }
+    }
+}
+
+/// Asks for one chunk, asking again with a fresh token each time the response does not arrive in time.
+/// Tokens that time out are cancelled, so that a late response is ignored.
+fn fetch_chunk(node: &Mutex<Node>, resource: Resource, destination: &SocketAddr, start: u32, len: usize, options: &FetchOptions) -> Result<Vec<u8>, FetchError> {
+    for _ in 0..=options.retries {
+        // The lock must not be held while waiting, or the response could never be handled.
+        let (token, receiver) = resource.send_request(&mut node.lock().unwrap(), destination, start, len)?;
+
+        match receiver.recv_timeout(options.timeout) {
+            Ok(mut chunk) => {
+                // Anything past what was asked for is not the responder's to send.
+                chunk.bytes.truncate(len);
+                return Ok(chunk.bytes);
+            }
+            Err(_) => resource.cancel_request(&mut node.lock().unwrap(), token),
+        }
+    }
+    Err(FetchError::TimedOut)
+}
+
+/// Reads a node's whole profile, one chunk at a time, until a chunk comes back short. Blocks until
+/// the profile is read or a chunk is given up on.
+#[allow(dead_code)] // Nothing reads profiles yet.
+pub fn fetch_profile(node: &Mutex<Node>, destination: &SocketAddr, options: &FetchOptions) -> Result<Vec<u8>, FetchError> {
+    let chunk_len = options.chunk_len.max(1);
+    let mut profile = Vec::new();
+    loop {
+        let chunk = fetch_chunk(node, Resource::Profile, destination, profile.len() as u32, chunk_len, options)?;
+        let short = chunk.len() < chunk_len;
+        profile.extend(chunk);
+        if profile.len() > options.max_len || (!short && profile.len() == options.max_len) {
+            return Err(FetchError::TooLong);
+        }
+        if short {
+            return Ok(profile);
+        }
+    }
+}
+
+/// Reads the valid entries of a node's whole partner list, the first of which is the node itself.
+/// Blocks until the list is read or a chunk is given up on; in that case the entries read before it
+/// are still returned, since each was read whole.
+pub fn fetch_partner_list(node: &Mutex<Node>, destination: &SocketAddr, options: &FetchOptions) -> Vec<Addressable> {
+    let mut assembler = PartnerListAssembler::new(options.chunk_len, options.max_len);
+    while let Some((start, len)) = assembler.next_request() {
+        match fetch_chunk(node, Resource::PartnerList, destination, start, len, options) {
+            Ok(chunk) => assembler.add_response(start, &chunk),
+            Err(_) => assembler.abandon(),
+        }
+    }
+    assembler.into_entries()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fetch_partner_list;
+    use super::fetch_profile;
+    use super::FetchOptions;
+    use crate::message::Message;
+    use crate::node::Node;
+    use crate::partner_list_response::PartnerListResponse;
+    use crate::profile_response::ProfileResponse;
+    use crate::receive::receive;
+    use crate::simulated_network::LinkConditions;
+    use crate::simulated_network::SimulatedNetwork;
+    use crate::transport::Transport;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    fn spawn_node(network: &SimulatedNetwork) -> Arc<Mutex<Node>> {
+        let address: SocketAddr = "10.0.0.1:4040".parse().unwrap();
+        let transport: Arc<dyn Transport> = Arc::new(network.endpoint(address).unwrap());
+        let node = Arc::new(Mutex::new(Node::new(address.ip().to_string(), address.port(), transport.clone())));
+        let thread_node = node.clone();
+        thread::spawn(move || receive(thread_node, transport));
+        node
+    }
+
+    #[test]
+    fn reads_a_profile_across_several_chunks() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let node = spawn_node(&network);
+
+        let peer = network.endpoint("10.0.0.2:4040".parse().unwrap()).unwrap();
+        let peer_address = peer.address();
+        let profile: Vec<u8> = (0..25).collect();
+        let served = profile.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, source)) = peer.receive(&mut buf) {
+                if let Ok(Message::ProfileRequest(request)) = Message::decode(&buf[..len]) {
+                    let start = (request.start_index as usize).min(served.len());
+                    let response = Message::ProfileResponse(ProfileResponse{
+                        token: request.token,
+                        slice: &served[start..(start + request.requested_len).min(served.len())],
+                    }).encode();
+                    peer.send(&source, &response).unwrap();
+                }
+            }
+        });
+
+        let options = FetchOptions {
+            chunk_len: 10,
+            max_len: 1024,
+            timeout: Duration::from_millis(50),
+            retries: 1,
+        };
+        assert_eq!(fetch_profile(&node, &peer_address, &options).unwrap(), profile);
+    }
+
+    #[test]
+    fn keeps_the_entries_read_before_a_node_stops_answering() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let node = spawn_node(&network);
+
+        // A peer that answers only the first request
+        let peer = network.endpoint("10.0.0.2:4040".parse().unwrap()).unwrap();
+        let peer_address = peer.address();
+        thread::spawn(move || {
+            let list = b"0FC8a\x000FC8b\x000FC8c\x000FC8d\x00";
+            let mut buf = [0u8; 2048];
+            let mut answered = false;
+            while let Ok((len, source)) = peer.receive(&mut buf) {
+                if let Ok(Message::PartnerListRequest(request)) = Message::decode(&buf[..len]) {
+                    if !answered {
+                        let response = Message::PartnerListResponse(PartnerListResponse{
+                            token: request.token,
+                            slice: &list[..request.requested_len.min(list.len())],
+                        }).encode();
+                        peer.send(&source, &response).unwrap();
+                        answered = true;
+                    }
+                }
+            }
+        });
+
+        let options = FetchOptions {
+            chunk_len: 10,
+            max_len: 1024,
+            timeout: Duration::from_millis(50),
+            retries: 1,
+        };
+        assert_eq!(fetch_partner_list(&node, &peer_address, &options), vec!["a:4040".to_string()]);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::cpr::Position;
+use crate::ingest::ModeSFrame;
+use crate::mlat::ReceiverLocation;
+use crate::node::HandleError;
+use crate::peel::peel_slice;
+use crate::peel::peel_u16;
+use crate::peel::peel_u32;
+use crate::peel::peel_u8;
+use std::time::Duration;
+use std::time::UNIX_EPOCH;
+
+pub const MODE_S_SHORT: u8 = 0x01;
+pub const MODE_S_LONG: u8 = 0x02;
+
+/// Flag bits saying which optional fields a record has
+const HAS_SIGNAL_LEVEL: u8 = 0x01;
+const HAS_MLAT_TIMESTAMP: u8 = 0x02;
+const HAS_RECEIVER_LOCATION: u8 = 0x04;
+
+/// The MLAT counter is carried in 48 bits, as in the Beast format
+const MLAT_TIMESTAMP_LEN: usize = 6;
+
+/// Latitude and longitude in ten-millionths of a degree, then altitude in meters
+const RECEIVER_LOCATION_LEN: usize = 4 + 4 + 2;
+const DEGREE_UNITS: f64 = 10_000_000.0;
+
+fn frame_type(frame: &[u8]) -> Option<u8> {
+    match frame.len() {
+        7 => Some(MODE_S_SHORT),
+        14 => Some(MODE_S_LONG),
+        _ => None,
+    }
+}
+
+fn frame_len(frame_type: u8) -> Option<usize> {
+    match frame_type {
+        MODE_S_SHORT => Some(7),
+        MODE_S_LONG => Some(14),
+        _ => None,
+    }
+}
+
+fn peel_u64(xs: &[u8]) -> Result<(u64, &[u8]), HandleError> {
+    let (bytes, rest) = peel_slice(xs, 8)?;
+    let mut le = [0u8; 8];
+    le.copy_from_slice(bytes);
+    Ok( (u64::from_le_bytes(le), rest) )
+}
+
+/// Writes a frame as one record of a `Data Bundle`: `[Frame Type: U8] [Flags: U8] [Receive Time: U64LE]
+/// [Signal Level: U8]? [MLAT Counter: U48LE]? [Receiver Location: I32LE I32LE I16LE]? [Frame]`.
+/// Frames that are not Mode S are skipped, and `false` returned.
+pub fn encode(frame: &ModeSFrame, out: &mut Vec<u8>) -> bool {
+    let frame_type = match frame_type(&frame.bytes) {
+        Some(frame_type) => frame_type,
+        None => return false,
+    };
+
+    let mut flags = 0;
+    if frame.signal_level.is_some() {
+        flags |= HAS_SIGNAL_LEVEL;
+    }
+    if frame.mlat_timestamp.is_some() {
+        flags |= HAS_MLAT_TIMESTAMP;
+    }
+    if frame.receiver_location.is_some() {
+        flags |= HAS_RECEIVER_LOCATION;
+    }
+
+    // Microseconds since the Unix epoch, by the clock of the node that heard the frame
+    let received_at = frame.received_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64;
+
+    out.push(frame_type);
+    out.push(flags);
+    out.extend_from_slice(&received_at.to_le_bytes());
+    if let Some(signal_level) = frame.signal_level {
+        out.push(signal_level);
+    }
+    if let Some(mlat_timestamp) = frame.mlat_timestamp {
+        out.extend_from_slice(&mlat_timestamp.to_le_bytes()[..MLAT_TIMESTAMP_LEN]);
+    }
+    if let Some(ref location) = frame.receiver_location {
+        out.extend_from_slice(&((location.position.lat * DEGREE_UNITS).round() as i32).to_le_bytes());
+        out.extend_from_slice(&((location.position.lon * DEGREE_UNITS).round() as i32).to_le_bytes());
+        out.extend_from_slice(&(location.altitude_meters.round() as i16).to_le_bytes());
+    }
+    out.extend_from_slice(&frame.bytes);
+    true
+}
+
+/// The length of a record, so that a bundle can be kept within budget
+pub fn encoded_len(frame: &ModeSFrame) -> usize {
+    let mut len = 1 + 1 + 8 + frame.bytes.len();
+    if frame.signal_level.is_some() {
+        len += 1;
+    }
+    if frame.mlat_timestamp.is_some() {
+        len += MLAT_TIMESTAMP_LEN;
+    }
+    if frame.receiver_location.is_some() {
+        len += RECEIVER_LOCATION_LEN;
+    }
+    len
+}
+
+/// Reads one record of a `Data` bundle. The frame is taken from the end of the record, so that fields
+/// for flag bits added later, which follow the MLAT counter, are skipped.
+pub fn decode(record: &[u8]) -> Result<ModeSFrame, HandleError> {
+    let (frame_type, rest) = peel_u8(record)?;
+    let frame_len = frame_len(frame_type).ok_or(HandleError::InvalidFrameType)?;
+    let header_len = rest.len().checked_sub(frame_len).ok_or(HandleError::PacketTruncated)?;
+    let (header, bytes) = rest.split_at(header_len);
+
+    let (flags, rest) = peel_u8(header)?;
+    let (received_at, rest) = peel_u64(rest)?;
+    // A time too far off for our clock to hold makes the record unreadable, rather than panicking.
+    let received_at = UNIX_EPOCH.checked_add(Duration::from_micros(received_at)).ok_or(HandleError::InvalidReceiveTime)?;
+
+    let (signal_level, rest) = if flags & HAS_SIGNAL_LEVEL != 0 {
+        let (signal_level, rest) = peel_u8(rest)?;
+        (Some(signal_level), rest)
+    } else {
+        (None, rest)
+    };
+
+    let (mlat_timestamp, rest) = if flags & HAS_MLAT_TIMESTAMP != 0 {
+        let (bytes, rest) = peel_slice(rest, MLAT_TIMESTAMP_LEN)?;
+        let mut le = [0u8; 8];
+        le[..MLAT_TIMESTAMP_LEN].copy_from_slice(bytes);
+        (Some(u64::from_le_bytes(le)), rest)
+    } else {
+        (None, rest)
+    };
+
+    let receiver_location = if flags & HAS_RECEIVER_LOCATION != 0 {
+        let (lat, rest) = peel_u32(rest)?;
+        let (lon, rest) = peel_u32(rest)?;
+        let (altitude, _) = peel_u16(rest)?;
+        let location = ReceiverLocation {
+            position: Position {
+                lat: lat as i32 as f64 / DEGREE_UNITS,
+                lon: lon as i32 as f64 / DEGREE_UNITS,
+            },
+            altitude_meters: altitude as i16 as f64,
+        };
+        // A location that is not on the earth would throw off every clock it is compared with.
+        if location.position.lat.abs() > 90.0 || location.position.lon.abs() > 180.0 {
+            return Err(HandleError::InvalidReceiverLocation);
+        }
+        Some(location)
+    } else {
+        None
+    };
+
+    Ok(ModeSFrame {
+        bytes: bytes.to_vec(),
+        mlat_timestamp: mlat_timestamp,
+        signal_level: signal_level,
+        received_at: received_at,
+        receiver_location: receiver_location,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::encode;
+    use crate::cpr::Position;
+    use crate::ingest::ModeSFrame;
+    use crate::mlat::ReceiverLocation;
+    use crate::node::HandleError;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+
+    const FRAME: [u8; 14] = [0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98];
+
+    fn frame() -> ModeSFrame {
+        ModeSFrame {
+            bytes: FRAME.to_vec(),
+            mlat_timestamp: Some(0xB0D5F6C8),
+            signal_level: Some(0x40),
+            received_at: UNIX_EPOCH + Duration::from_micros(1_600_000_000_000_000),
+            receiver_location: Some(ReceiverLocation {
+                position: Position{lat: 51.4775, lon: -0.4614},
+                altitude_meters: 25.0,
+            }),
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut record = Vec::new();
+        assert!(encode(&frame(), &mut record));
+        let decoded = decode(&record).unwrap();
+        assert_eq!(decoded.bytes, FRAME.to_vec());
+        assert_eq!(decoded.mlat_timestamp, Some(0xB0D5F6C8));
+        assert_eq!(decoded.signal_level, Some(0x40));
+        assert_eq!(decoded.received_at, frame().received_at);
+        assert_eq!(decoded.receiver_location, frame().receiver_location);
+    }
+
+    #[test]
+    fn skips_fields_for_flags_it_does_not_understand() {
+        let mut record = Vec::new();
+        encode(&frame(), &mut record);
+
+        // A newer sender sets flag 0x80 and puts its field after the ones we know.
+        record[1] |= 0x80;
+        let frame_start = record.len() - FRAME.len();
+        record.splice(frame_start..frame_start, vec![0xEE; 5]);
+
+        let decoded = decode(&record).unwrap();
+        assert_eq!(decoded.bytes, FRAME.to_vec());
+        assert_eq!(decoded.mlat_timestamp, Some(0xB0D5F6C8));
+        assert_eq!(decoded.signal_level, Some(0x40));
+        assert_eq!(decoded.receiver_location, frame().receiver_location);
+    }
+
+    #[test]
+    fn rejects_locations_off_the_earth() {
+        let mut record = Vec::new();
+        encode(&frame(), &mut record);
+        let latitude_start = 1 + 1 + 8 + 1 + 6;
+        record[latitude_start..latitude_start + 4].copy_from_slice(&1_000_000_000i32.to_le_bytes());
+        assert!(decode(&record).is_err());
+    }
+
+    #[test]
+    fn does_not_panic_on_receive_times_the_clock_cannot_hold() {
+        let mut record = Vec::new();
+        encode(&frame(), &mut record);
+        record[2..10].copy_from_slice(&u64::MAX.to_le_bytes());
+        match decode(&record) {
+            Ok(decoded) => assert_eq!(Some(decoded.received_at), UNIX_EPOCH.checked_add(Duration::from_micros(u64::MAX))),
+            Err(e) => assert!(matches!(e, HandleError::InvalidReceiveTime)),
+        }
+    }
+
+    #[test]
+    fn rejects_records_too_short_for_their_fields() {
+        let mut record = Vec::new();
+        encode(&frame(), &mut record);
+        for len in 0..record.len() {
+            assert!(decode(&record[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_frame_types() {
+        let mut record = Vec::new();
+        encode(&frame(), &mut record);
+        record[0] = 0x03;
+        assert!(decode(&record).is_err());
+    }
+}
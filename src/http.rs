@@ -0,0 +1,89 @@
+use crate::aircraft_json::aircraft_json;
+use crate::mlat::MlatState;
+use crate::tracker::AircraftTable;
+use std::io;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// A client that has not finished sending its request by then is dropped
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Request headers longer than this are not read
+const MAX_REQUEST_LEN: u64 = 8192;
+
+fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &[u8]) -> io::Result<()> {
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nAccess-Control-Allow-Origin: *\r\nConnection: close\r\n\r\n",
+        status, content_type, body.len())?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn handle_connection(mut stream: TcpStream, table: &Mutex<AircraftTable>, mlat_state: Option<&Mutex<MlatState>>) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    {
+        let mut reader = BufReader::new((&stream).take(MAX_REQUEST_LEN));
+        reader.read_line(&mut request_line)?;
+
+        // The headers are not needed, but are read so that closing the connection does not reset it.
+        let mut header = String::new();
+        loop {
+            header.clear();
+            if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+                break;
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    let path = parts.next().unwrap_or("");
+    let path = path.split('?').next().unwrap_or("");
+
+    match (method, path) {
+        ("GET", "/data/aircraft.json") | ("GET", "/aircraft.json") => {
+            let json = aircraft_json(&table.lock().unwrap());
+            respond(&mut stream, "200 OK", "application/json", json.as_bytes())
+        }
+        ("GET", "/data/mlat.json") | ("GET", "/mlat.json") => match mlat_state {
+            Some(mlat_state) => {
+                let json = mlat_state.lock().unwrap().json();
+                respond(&mut stream, "200 OK", "application/json", json.as_bytes())
+            }
+            None => respond(&mut stream, "404 Not Found", "text/plain", b"MLAT is not enabled\n"),
+        },
+        ("GET", _) => respond(&mut stream, "404 Not Found", "text/plain", b"Not found\n"),
+        _ => respond(&mut stream, "405 Method Not Allowed", "text/plain", b"Method not allowed\n"),
+    }
+}
+
+/// Serves `aircraft.json` at both `/data/aircraft.json`, where tar1090 looks for it, and `/aircraft.json`,
+/// and `mlat.json` beside it if MLAT correlation is running.
+pub fn serve(listener: TcpListener, table: Arc<Mutex<AircraftTable>>, mlat_state: Option<Arc<Mutex<MlatState>>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept HTTP connection: {}", e);
+                continue;
+            }
+        };
+
+        let table = table.clone();
+        let mlat_state = mlat_state.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_connection(stream, &table, mlat_state.as_deref()) {
+                eprintln!("HTTP connection failed: {}", e);
+            }
+        });
+    }
+}
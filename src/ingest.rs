@@ -0,0 +1,242 @@
+use crate::avr::AvrDecoder;
+use crate::beast::BeastDecoder;
+use crate::bundle::Outbox;
+use crate::mlat::ReceiverLocation;
+use crate::observer::FrameObserver;
+use crate::observer::FrameSource;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::SystemTime;
+
+/// A Mode S frame as heard by a local receiver
+#[derive(Clone, Debug)]
+pub struct ModeSFrame {
+    pub bytes: Vec<u8>,
+
+    /// The receiver's 12 MHz counter when the frame was heard, if it reports one
+    pub mlat_timestamp: Option<u64>,
+    pub signal_level: Option<u8>,
+
+    /// When the receiver's output reached us
+    pub received_at: SystemTime,
+
+    /// Where the receiver is, if a partner relaying the frame said. Our own receivers' frames do not
+    /// carry it.
+    pub receiver_location: Option<ReceiverLocation>,
+}
+
+/// Turns a receiver's output format into frames. Bytes can be pushed in arbitrarily sized pieces.
+pub trait FrameDecoder {
+    fn push(&mut self, bytes: &[u8]);
+    fn next_frame(&mut self) -> Option<ModeSFrame>;
+}
+
+/// Where local receiver frames come from, and in which format
+#[derive(Clone, Debug)]
+pub enum Input {
+    /// A Beast binary feed, as dump1090 and readsb serve on port 30005
+    Beast(String),
+
+    /// An AVR text feed, as served on port 30002
+    Avr(String),
+
+    /// A file of AVR lines, or standard input if the path is "-"
+    AvrFile(PathBuf),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Input::Beast(ref address) => write!(f, "beast:{}", address),
+            Input::Avr(ref address) => write!(f, "avr:{}", address),
+            Input::AvrFile(ref path) => write!(f, "avr-file:{}", path.display()),
+        }
+    }
+}
+
+/// How long to wait before reconnecting to a feed that went away
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Broadcasts a frame heard by one of our own receivers, unless it fails validation or is an echo.
+fn share(observer: &Mutex<FrameObserver>, outbox: &Mutex<Outbox>, input_name: &str, frame: ModeSFrame) {
+    let frame = match observer.lock().unwrap().observe(FrameSource::Local(input_name.to_string()), frame) {
+        Some(frame) => frame,
+        None => return,
+    };
+    if let Err(e) = outbox.lock().unwrap().queue_frame(&frame) {
+        eprintln!("Failed to broadcast frame: {}", e);
+    }
+}
+
+/// Broadcasts every valid frame read from `source` to our partners. Returns when `source` fails or ends.
+pub fn ingest<R: Read, D: FrameDecoder>(observer: &Mutex<FrameObserver>, outbox: &Mutex<Outbox>, input_name: &str, mut source: R, mut decoder: D) -> io::Result<()> {
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let len = match source.read(&mut buf) {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if len == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "input ended"));
+        }
+
+        decoder.push(&buf[..len]);
+        while let Some(frame) = decoder.next_frame() {
+            share(observer, outbox, input_name, frame);
+        }
+    }
+}
+
+fn ingest_once(observer: &Mutex<FrameObserver>, outbox: &Mutex<Outbox>, input: &Input) -> io::Result<()> {
+    let name = input.to_string();
+    match *input {
+        Input::Beast(ref address) => ingest(observer, outbox, &name, TcpStream::connect(address)?, BeastDecoder::new()),
+        Input::Avr(ref address) => ingest(observer, outbox, &name, TcpStream::connect(address)?, AvrDecoder::new()),
+        Input::AvrFile(ref path) if path.as_os_str() == "-" => ingest(observer, outbox, &name, io::stdin(), AvrDecoder::new()),
+        Input::AvrFile(ref path) => ingest(observer, outbox, &name, File::open(path)?, AvrDecoder::new()),
+    }
+}
+
+/// Keeps a network feed connected for as long as the program runs. A file is read through once.
+pub fn run_input(observer: Arc<Mutex<FrameObserver>>, outbox: Arc<Mutex<Outbox>>, input: Input) {
+    loop {
+        let result = ingest_once(&observer, &outbox, &input);
+
+        if let Input::AvrFile(ref path) = input {
+            match result {
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {}
+                Err(e) => eprintln!("Input {}: {}", path.display(), e),
+                Ok( () ) => {}
+            }
+            return;
+        }
+
+        if let Err(e) = result {
+            eprintln!("Input {}: {}", input, e);
+        }
+        sleep(RECONNECT_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ingest_once;
+    use super::Input;
+    use crate::beast::BeastMessage;
+    use crate::beast::BeastMessageKind;
+    use crate::bundle::split_records;
+    use crate::bundle::Outbox;
+    use crate::bundle::DEFAULT_BUNDLE_BUDGET;
+    use crate::frame_record;
+    use crate::message::Message;
+    use crate::mode_s::crc24;
+    use crate::node::Node;
+    use crate::node::Partnership;
+    use crate::observer::FrameObserver;
+    use crate::observer::FrameSource;
+    use crate::simulated_network::LinkConditions;
+    use crate::simulated_network::SimulatedNetwork;
+    use crate::transport::Transport;
+    use std::io::ErrorKind;
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    const LONG: [u8; 14] = [0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98];
+
+    fn beast(kind: BeastMessageKind, mlat_timestamp: u64, data: &[u8]) -> Vec<u8> {
+        BeastMessage {
+            kind: kind,
+            mlat_timestamp: mlat_timestamp,
+            signal_level: 0x60,
+            data: data.to_vec(),
+        }.encode()
+    }
+
+    #[test]
+    fn broadcasts_the_frames_of_a_beast_feed() {
+        // An all-call reply from the same aircraft, with its parity intact
+        let mut short = vec![0x5D, 0x48, 0x40, 0xD6];
+        let parity = crc24(&short);
+        short.extend_from_slice(&parity.to_be_bytes()[1..]);
+        let mut corrupt = LONG;
+        corrupt[8] ^= 0x21;
+
+        let mut feed = vec![0x1A, b'3', 0x00, 0x00];
+        feed.extend(beast(BeastMessageKind::ModeSLong, 0x1A_0001, &LONG));
+        feed.extend(beast(BeastMessageKind::ModeAC, 0x1A_0002, &[0x12, 0x34]));
+        feed.extend(beast(BeastMessageKind::ModeSLong, 0x1A_0003, &corrupt));
+        feed.extend(beast(BeastMessageKind::ModeSShort, 0x1A_0004, &short));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let feed_address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            // Written in two pieces so that a message straddles them
+            let (first, second) = feed.split_at(feed.len() / 2);
+            stream.write_all(first).unwrap();
+            stream.flush().unwrap();
+            thread::sleep(Duration::from_millis(20));
+            stream.write_all(second).unwrap();
+        });
+
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let address: SocketAddr = "10.0.0.1:4040".parse().unwrap();
+        let partner_address: SocketAddr = "10.0.0.2:4040".parse().unwrap();
+        let partner = network.endpoint(partner_address).unwrap();
+        let mut node = Node::new(address.ip().to_string(), address.port(), Arc::new(network.endpoint(address).unwrap()));
+        node.add_active_partnership(Partnership {
+            address: partner_address.to_string(),
+            resolved_address: Some(partner_address),
+            key: [3; 32],
+            id: 7,
+        });
+        let node = Arc::new(Mutex::new(node));
+
+        let mut observer = FrameObserver::new(false, None);
+        let observed = observer.listen();
+        let observer = Mutex::new(observer);
+        let outbox = Mutex::new(Outbox::new(node, Duration::from_secs(60), DEFAULT_BUNDLE_BUDGET, None));
+
+        let input = Input::Beast(feed_address.to_string());
+        let result = ingest_once(&observer, &outbox, &input);
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        server.join().unwrap();
+
+        let observed: Vec<_> = observed.try_iter().collect();
+        assert_eq!(observed.len(), 2);
+        assert!(observed.iter().all(|frame| frame.source == FrameSource::Local(format!("beast:{}", feed_address))));
+        let source = FrameSource::Local(input.to_string());
+        assert_eq!(observer.lock().unwrap().stats()[&source].rejected, 1);
+
+        outbox.lock().unwrap().flush().unwrap();
+        let mut buf = [0u8; 2048];
+        let (len, from) = partner.receive(&mut buf).unwrap();
+        assert_eq!(from, address);
+        let payload = match Message::decode(&buf[..len]).unwrap() {
+            Message::Data(data) => data.payload.to_vec(),
+            _ => panic!("expected a Data packet"),
+        };
+        let frames: Vec<_> = split_records(&payload).into_iter().map(|record| frame_record::decode(record).unwrap()).collect();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].bytes, LONG.to_vec());
+        assert_eq!(frames[0].mlat_timestamp, Some(0x1A_0001));
+        assert_eq!(frames[1].bytes, short);
+        assert_eq!(frames[1].signal_level, Some(0x60));
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::redundant_field_names)]
+
 mod subscribe;
 mod subscribe_decline;
 mod subscribe_accept;
@@ -10,25 +12,226 @@ mod profile_response;
 mod partner_list_request;
 mod partner_list_response;
 mod seek;
+mod receive;
+mod config;
+mod transport;
+#[cfg(test)]
+mod simulated_network;
+mod subscription_policy;
+mod message;
+mod beast;
+mod avr;
+mod ingest;
+mod mode_s;
+mod adsb;
+mod cpr;
+mod tracker;
+mod aircraft_json;
+mod http;
+mod sbs;
+mod beast_output;
+mod bundle;
+mod frame_record;
+mod mlat;
+mod sequence_monitor;
+mod replay_window;
+mod partner_list;
+mod fetch;
+mod resolver;
+mod observer;
+mod fanout;
 
 
 use node::Node;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use config::Config;
+use transport::Transport;
+use tracker::AircraftTable;
+use beast_output::EchoGuard;
+use mlat::MlatState;
+use mlat::ReceiverLocation;
+use observer::FrameObserver;
+use observer::FrameSource;
+use bundle::Outbox;
+use node::PartnershipEvent;
+use sequence_monitor::SequenceMonitor;
+use sequence_monitor::RepeatAndAlternationScorer;
+use subscription_policy::{SubscriptionPolicy, AllOf, AllowList, DenyList, PartnerCap};
+use std::net::UdpSocket;
+use std::net::TcpListener;
+use std::process::exit;
 use std::sync::Mutex;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+
+/// How often the frame and partner counts are logged
+const STATS_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Logs, every `STATS_INTERVAL`, how many frames each source has given us and how many packets from
+/// each partner were dropped, for as long as the program runs.
+fn log_stats_periodically(node: Arc<Mutex<Node>>, observer: Arc<Mutex<FrameObserver>>) {
+    loop {
+        thread::sleep(STATS_INTERVAL);
+        for (source, stats) in observer.lock().unwrap().stats() {
+            eprintln!("{}: {} frames accepted ({} corrected), {} rejected, {} echoed",
+                source, stats.accepted, stats.corrected, stats.rejected, stats.echoed);
+        }
+        for (partnering_id, stats) in &node.lock().unwrap().stats().partners {
+            eprintln!("Partnership {}: {} packets replayed, {} stale, {} undecodable records",
+                partnering_id, stats.replayed, stats.stale, stats.undecodable);
+        }
+    }
+}
 
 fn main() {
-    let source = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+    let config = Config::from_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        exit(2);
+    });
+
+    let socket = UdpSocket::bind(config.bind_address).unwrap_or_else(|e| {
+        eprintln!("Failed to bind {}: {}", config.bind_address, e);
+        exit(1);
+    });
+    let transport: Arc<dyn Transport> = Arc::new(socket);
+
+    let mut policies: Vec<Box<dyn SubscriptionPolicy>> = vec![Box::new(DenyList{denied: config.denied})];
+    if !config.allowed.is_empty() {
+        policies.push(Box::new(AllowList{allowed: config.allowed}));
+    }
+    policies.push(Box::new(PartnerCap{max_active_partnerships: config.max_partners, retry_delay_seconds: 60 * 60}));
 
-    let node = Node::new("self.mynode.net".to_string());
+    let mut node = Node::new(config.contact_host, config.contact_port, transport.clone());
+    node.set_subscription_policy(Box::new(AllOf{policies: policies}));
+    node.set_sequence_monitor(
+        SequenceMonitor::new(config.sequence_window, config.sequence_threshold, Box::new(RepeatAndAlternationScorer{jump_threshold: 1000})),
+        config.anomaly_action);
+    node.set_replay_window_size(config.replay_window);
+    let events = node.listen_for_partnership_events();
+    let partner_data = node.listen_for_data();
     let node = Arc::new(Mutex::new(node));
     
+    let mut observer = FrameObserver::new(config.correct_errors, config.receiver_location);
+    let frames = observer.listen();
+    let sbs_frames = config.sbs_address.map(|_| observer.listen());
+    let beast_output_frames = config.beast_output_address.map(|_| observer.listen());
+    let mlat_frames = if config.mlat { Some(observer.listen()) } else { None };
+    if config.beast_output_address.is_some() {
+        observer.set_echo_guard(EchoGuard::new(config.beast_output_local));
+    }
+    let observer = Arc::new(Mutex::new(observer));
+    let thread_observer = observer.clone();
+    thread::spawn(move || {
+        observer::observe_partner_data(partner_data, thread_observer)
+    });
+    
+    let receiver_altitude = config.receiver_altitude;
+    let receiver_location = config.receiver_location.map(|position| ReceiverLocation {
+        position: position,
+        altitude_meters: receiver_altitude,
+    });
+    let outbox = Arc::new(Mutex::new(Outbox::new(node.clone(), config.batch_window, config.batch_budget, receiver_location)));
+    
+    let aircraft_table = Arc::new(Mutex::new(AircraftTable::new(config.aircraft_timeout)));
+    let mlat_state = mlat_frames.as_ref().map(|_| Arc::new(Mutex::new(MlatState::new())));
+    let thread_table = aircraft_table.clone();
+    thread::spawn(move || {
+        tracker::track(frames, thread_table)
+    });
+    
+    if let Some(http_address) = config.http_address {
+        let listener = TcpListener::bind(http_address).unwrap_or_else(|e| {
+            eprintln!("Failed to bind {}: {}", http_address, e);
+            exit(1);
+        });
+        let thread_table = aircraft_table.clone();
+        let thread_mlat_state = mlat_state.clone();
+        thread::spawn(move || {
+            http::serve(listener, thread_table, thread_mlat_state)
+        });
+    }
+    
+    if let (Some(sbs_address), Some(sbs_frames)) = (config.sbs_address, sbs_frames) {
+        let listener = TcpListener::bind(sbs_address).unwrap_or_else(|e| {
+            eprintln!("Failed to bind {}: {}", sbs_address, e);
+            exit(1);
+        });
+        let sbs_origins = config.sbs_origins;
+        thread::spawn(move || {
+            sbs::serve(listener, sbs_frames, sbs_origins)
+        });
+    }
+    
+    if let (Some(beast_output_address), Some(beast_output_frames)) = (config.beast_output_address, beast_output_frames) {
+        let listener = TcpListener::bind(beast_output_address).unwrap_or_else(|e| {
+            eprintln!("Failed to bind {}: {}", beast_output_address, e);
+            exit(1);
+        });
+        let include_local = config.beast_output_local;
+        thread::spawn(move || {
+            beast_output::serve(listener, beast_output_frames, include_local)
+        });
+    }
+    
+    if let (Some(mlat_frames), Some(mlat_state)) = (mlat_frames, mlat_state) {
+        if let Some(ref location) = receiver_location {
+            let mut state = mlat_state.lock().unwrap();
+            for input in &config.inputs {
+                state.clocks.set_receiver_location(FrameSource::Local(input.to_string()), location);
+            }
+        }
+        thread::spawn(move || {
+            mlat::correlate(mlat_frames, mlat::DEFAULT_CORRELATION_WINDOW, mlat_state)
+        });
+    }
+    
+    if let Some(json_file) = config.json_file {
+        let json_interval = config.json_interval;
+        let thread_table = aircraft_table.clone();
+        thread::spawn(move || {
+            aircraft_json::write_periodically(json_file, json_interval, thread_table)
+        });
+    }
+    
+    let thread_node = node.clone();
+    let seeds = config.seeds;
+    thread::spawn(move || {
+        seek::seek(thread_node, seeds)
+    });
+    
+    thread::spawn(move || {
+        for event in events {
+            match event {
+                PartnershipEvent::SequenceAnomaly{partnering_id, score, action} => {
+                    eprintln!("Partnership {} looks compromised (sequence score {:.2}); action: {:?}", partnering_id, score, action);
+                }
+            }
+        }
+    });
+    
     let thread_node = node.clone();
+    let thread_observer = observer.clone();
     thread::spawn(move || {
-        seek::seek(thread_node)
+        log_stats_periodically(thread_node, thread_observer)
     });
     
-    node.lock().unwrap().handle_received_packet(&source, &[][..]).unwrap();
+    if config.batch_window > Duration::from_secs(0) {
+        let thread_outbox = outbox.clone();
+        thread::spawn(move || {
+            bundle::flush_periodically(thread_outbox)
+        });
+    }
+    
+    for input in config.inputs {
+        let thread_observer = observer.clone();
+        let thread_outbox = outbox.clone();
+        thread::spawn(move || {
+            ingest::run_input(thread_observer, thread_outbox, input)
+        });
+    }
     
+    if let Err(e) = receive::receive(node, transport) {
+        eprintln!("Receiving failed: {}", e);
+        exit(1);
+    }
 }
@@ -0,0 +1,425 @@
+use crate::node::HandleError;
+use crate::peel::{peel_u8, peel_u16, peel_u32, peel_slice, peel_end};
+use crate::subscribe::Subscribe;
+use crate::subscribe_decline::SubscribeDecline;
+use crate::subscribe_accept::SubscribeAccept;
+use crate::subscribe_finalize::SubscribeFinalize;
+use crate::data::Data;
+use crate::data::PayloadFormat;
+use crate::profile_request::ProfileRequest;
+use crate::profile_response::ProfileResponse;
+use crate::partner_list_request::PartnerListRequest;
+use crate::partner_list_response::PartnerListResponse;
+
+// The first byte of every packet, as listed in DESIGN.md
+pub const SUBSCRIBE: u8 = 0x01;
+pub const SUBSCRIBE_DECLINE: u8 = 0x02;
+pub const SUBSCRIBE_ACCEPT: u8 = 0x03;
+pub const SUBSCRIBE_FINALIZE: u8 = 0x04;
+pub const DATA: u8 = 0x05;
+pub const DATA_BUNDLE: u8 = 0x06;
+pub const PROFILE_REQUEST: u8 = 0x08;
+pub const PROFILE_RESPONSE: u8 = 0x09;
+pub const PARTNER_LIST_REQUEST: u8 = 0x0A;
+pub const PARTNER_LIST_RESPONSE: u8 = 0x0B;
+
+/// Every packet that nodes exchange. `encode` and `decode` are the one place the wire format is defined;
+/// DESIGN.md describes the same formats in prose.
+pub enum Message<'a> {
+    Subscribe(Subscribe<'a>),
+    SubscribeDecline(SubscribeDecline),
+    SubscribeAccept(SubscribeAccept),
+    SubscribeFinalize(SubscribeFinalize),
+    Data(Data<'a>),
+    ProfileRequest(ProfileRequest),
+    ProfileResponse(ProfileResponse<'a>),
+    PartnerListRequest(PartnerListRequest),
+    PartnerListResponse(PartnerListResponse<'a>),
+}
+
+impl<'a> Message<'a> {
+    pub fn type_byte(&self) -> u8 {
+        match *self {
+            Message::Subscribe(_) => SUBSCRIBE,
+            Message::SubscribeDecline(_) => SUBSCRIBE_DECLINE,
+            Message::SubscribeAccept(_) => SUBSCRIBE_ACCEPT,
+            Message::SubscribeFinalize(_) => SUBSCRIBE_FINALIZE,
+            Message::Data(ref m) => match m.format {
+                PayloadFormat::Frame => DATA,
+                PayloadFormat::Bundle => DATA_BUNDLE,
+            },
+            Message::ProfileRequest(_) => PROFILE_REQUEST,
+            Message::ProfileResponse(_) => PROFILE_RESPONSE,
+            Message::PartnerListRequest(_) => PARTNER_LIST_REQUEST,
+            Message::PartnerListResponse(_) => PARTNER_LIST_RESPONSE,
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        1 + match *self {
+            Message::Subscribe(ref m) => 4 + 32 + 2 + m.contact_host.len(),
+            Message::SubscribeDecline(_) => 4 + 4,
+            Message::SubscribeAccept(_) => 4 + 4,
+            Message::SubscribeFinalize(_) => 4 + 4,
+            Message::Data(ref m) => 4 + 16 + 4 + m.payload.len(),
+            Message::ProfileRequest(ref m) => 4 + 4 + m.requested_len,
+            Message::ProfileResponse(ref m) => 4 + m.slice.len(),
+            Message::PartnerListRequest(ref m) => 4 + 4 + m.requested_len,
+            Message::PartnerListResponse(ref m) => 4 + m.slice.len(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let capacity = self.encoded_len();
+        let mut bs = Vec::with_capacity(capacity);
+
+        bs.push(self.type_byte());
+        match *self {
+            Message::Subscribe(ref m) => {
+                debug_assert_eq!(m.key.len(), 32);
+                bs.extend_from_slice(&m.partnering_id.to_le_bytes()[..]);
+                bs.extend_from_slice(m.key);
+                bs.extend_from_slice(&m.contact_port.to_le_bytes()[..]);
+                bs.extend_from_slice(m.contact_host);
+            }
+            Message::SubscribeDecline(ref m) => {
+                bs.extend_from_slice(&m.partnering_id.to_le_bytes()[..]);
+                bs.extend_from_slice(&m.retry_delay_seconds.to_le_bytes()[..]);
+            }
+            Message::SubscribeAccept(ref m) => {
+                bs.extend_from_slice(&m.partnering_id.to_le_bytes()[..]);
+                bs.extend_from_slice(&m.confirmation_nonce.to_le_bytes()[..]);
+            }
+            Message::SubscribeFinalize(ref m) => {
+                bs.extend_from_slice(&m.partnering_id.to_le_bytes()[..]);
+                bs.extend_from_slice(&m.confirmation_nonce.to_le_bytes()[..]);
+            }
+            Message::Data(ref m) => {
+                bs.extend_from_slice(&m.partnering_id.to_le_bytes()[..]);
+                bs.extend_from_slice(&m.signature[..]);
+                bs.extend_from_slice(&m.sequence_number.to_le_bytes()[..]);
+                bs.extend_from_slice(m.payload);
+            }
+            Message::ProfileRequest(ProfileRequest{token, start_index, requested_len}) |
+            Message::PartnerListRequest(PartnerListRequest{token, start_index, requested_len}) => {
+                bs.extend_from_slice(&token.to_le_bytes()[..]);
+                bs.extend_from_slice(&start_index.to_le_bytes()[..]);
+                bs.resize(bs.len() + requested_len, 0);
+            }
+            Message::ProfileResponse(ProfileResponse{token, slice}) |
+            Message::PartnerListResponse(PartnerListResponse{token, slice}) => {
+                bs.extend_from_slice(&token.to_le_bytes()[..]);
+                bs.extend_from_slice(slice);
+            }
+        }
+
+        debug_assert_eq!(bs.len(), capacity);
+        bs
+    }
+
+    pub fn decode(packet: &'a [u8]) -> Result<Message<'a>, HandleError> {
+        let (type_byte, body) = peel_u8(packet).map_err(|_| HandleError::MissingPacketType)?;
+
+        Ok(match type_byte {
+            SUBSCRIBE => {
+                let (partnering_id, body) = peel_u32(body)?;
+                let (key, body) = peel_slice(body, 32)?;
+                let (contact_port, contact_host) = peel_u16(body)?;
+                Message::Subscribe(Subscribe {
+                    partnering_id: partnering_id,
+                    key: key,
+                    contact_port: contact_port,
+                    contact_host: contact_host,
+                })
+            }
+            SUBSCRIBE_DECLINE => {
+                let (partnering_id, body) = peel_u32(body)?;
+                let (retry_delay_seconds, body) = peel_u32(body)?;
+                peel_end(body)?;
+                Message::SubscribeDecline(SubscribeDecline {
+                    partnering_id: partnering_id,
+                    retry_delay_seconds: retry_delay_seconds,
+                })
+            }
+            SUBSCRIBE_ACCEPT => {
+                let (partnering_id, body) = peel_u32(body)?;
+                let (confirmation_nonce, body) = peel_u32(body)?;
+                peel_end(body)?;
+                Message::SubscribeAccept(SubscribeAccept {
+                    partnering_id: partnering_id,
+                    confirmation_nonce: confirmation_nonce,
+                })
+            }
+            SUBSCRIBE_FINALIZE => {
+                let (partnering_id, body) = peel_u32(body)?;
+                let (confirmation_nonce, body) = peel_u32(body)?;
+                peel_end(body)?;
+                Message::SubscribeFinalize(SubscribeFinalize {
+                    partnering_id: partnering_id,
+                    confirmation_nonce: confirmation_nonce,
+                })
+            }
+            DATA | DATA_BUNDLE => {
+                let (partnering_id, body) = peel_u32(body)?;
+                let (signature_slice, body) = peel_slice(body, 16)?;
+                let (sequence_number, payload) = peel_u32(body)?;
+                let mut signature = [0u8; 16];
+                signature.copy_from_slice(signature_slice);
+                Message::Data(Data {
+                    format: if type_byte == DATA { PayloadFormat::Frame } else { PayloadFormat::Bundle },
+                    partnering_id: partnering_id,
+                    signature: signature,
+                    sequence_number: sequence_number,
+                    payload: payload,
+                })
+            }
+            PROFILE_REQUEST => {
+                let (token, body) = peel_u32(body)?;
+                let (start_index, padding) = peel_u32(body)?;
+                Message::ProfileRequest(ProfileRequest {
+                    token: token,
+                    start_index: start_index,
+                    requested_len: padding.len(),
+                })
+            }
+            PROFILE_RESPONSE => {
+                let (token, slice) = peel_u32(body)?;
+                Message::ProfileResponse(ProfileResponse {
+                    token: token,
+                    slice: slice,
+                })
+            }
+            PARTNER_LIST_REQUEST => {
+                let (token, body) = peel_u32(body)?;
+                let (start_index, padding) = peel_u32(body)?;
+                Message::PartnerListRequest(PartnerListRequest {
+                    token: token,
+                    start_index: start_index,
+                    requested_len: padding.len(),
+                })
+            }
+            PARTNER_LIST_RESPONSE => {
+                let (token, slice) = peel_u32(body)?;
+                Message::PartnerListResponse(PartnerListResponse {
+                    token: token,
+                    slice: slice,
+                })
+            }
+            _ => return Err(HandleError::InvalidPacketType),
+        })
+    }
+}
+
+/// The formats in DESIGN.md, written out byte by byte, so that the codec cannot drift from them
+#[cfg(test)]
+mod tests {
+    use super::Message;
+    use crate::data::Data;
+    use crate::data::PayloadFormat;
+    use crate::partner_list_request::PartnerListRequest;
+    use crate::partner_list_response::PartnerListResponse;
+    use crate::profile_request::ProfileRequest;
+    use crate::profile_response::ProfileResponse;
+    use crate::subscribe::Subscribe;
+    use crate::subscribe_accept::SubscribeAccept;
+    use crate::subscribe_decline::SubscribeDecline;
+    use crate::subscribe_finalize::SubscribeFinalize;
+
+    const KEY: [u8; 32] = [
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F,
+        0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x2B, 0x2C, 0x2D, 0x2E, 0x2F,
+    ];
+
+    const SUBSCRIBE: &[u8] = &[
+        0x01,
+        0x01, 0x02, 0x03, 0x04,
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17, 0x18, 0x19, 0x1A, 0x1B, 0x1C, 0x1D, 0x1E, 0x1F,
+        0x20, 0x21, 0x22, 0x23, 0x24, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2A, 0x2B, 0x2C, 0x2D, 0x2E, 0x2F,
+        0xC8, 0x0F,
+        b'a', b'.', b'n', b'e', b't',
+    ];
+
+    const SUBSCRIBE_DECLINE: &[u8] = &[0x02, 0x01, 0x02, 0x03, 0x04, 0xFF, 0xFF, 0xFF, 0xFF];
+    const SUBSCRIBE_ACCEPT: &[u8] = &[0x03, 0x01, 0x02, 0x03, 0x04, 0x0A, 0x0B, 0x0C, 0x0D];
+    const SUBSCRIBE_FINALIZE: &[u8] = &[0x04, 0x01, 0x02, 0x03, 0x04, 0x0A, 0x0B, 0x0C, 0x0D];
+
+    const DATA: &[u8] = &[
+        0x05,
+        0x01, 0x02, 0x03, 0x04,
+        0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF,
+        0x78, 0x56, 0x34, 0x12,
+        0xEE, 0xFF,
+    ];
+
+    const DATA_BUNDLE: &[u8] = &[
+        0x06,
+        0x01, 0x02, 0x03, 0x04,
+        0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF,
+        0x78, 0x56, 0x34, 0x12,
+        0x01, 0xEE,
+    ];
+
+    const PROFILE_REQUEST: &[u8] = &[0x08, 0x21, 0x22, 0x23, 0x24, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+    const PROFILE_RESPONSE: &[u8] = &[0x09, 0x21, 0x22, 0x23, 0x24, b'a', b'b', b'c'];
+    const PARTNER_LIST_REQUEST: &[u8] = &[0x0A, 0x21, 0x22, 0x23, 0x24, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+    const PARTNER_LIST_RESPONSE: &[u8] = &[0x0B, 0x21, 0x22, 0x23, 0x24, b'0', b'F', b'C', b'8', b'a', 0x00];
+
+    #[test]
+    fn subscribe() {
+        let message = Message::Subscribe(Subscribe::new(0x04030201, &KEY, 4040, b"a.net"));
+        assert_eq!(message.encode(), SUBSCRIBE);
+
+        match Message::decode(SUBSCRIBE).unwrap() {
+            Message::Subscribe(m) => {
+                assert_eq!(m.partnering_id, 0x04030201);
+                assert_eq!(m.key, &KEY[..]);
+                assert_eq!(m.contact_port, 4040);
+                assert_eq!(m.contact_host, b"a.net");
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn subscribe_decline() {
+        let message = Message::SubscribeDecline(SubscribeDecline{partnering_id: 0x04030201, retry_delay_seconds: 0xFFFFFFFF});
+        assert_eq!(message.encode(), SUBSCRIBE_DECLINE);
+
+        match Message::decode(SUBSCRIBE_DECLINE).unwrap() {
+            Message::SubscribeDecline(m) => {
+                assert_eq!(m.partnering_id, 0x04030201);
+                assert_eq!(m.retry_delay_seconds, 0xFFFFFFFF);
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn subscribe_accept() {
+        let message = Message::SubscribeAccept(SubscribeAccept{partnering_id: 0x04030201, confirmation_nonce: 0x0D0C0B0A});
+        assert_eq!(message.encode(), SUBSCRIBE_ACCEPT);
+
+        match Message::decode(SUBSCRIBE_ACCEPT).unwrap() {
+            Message::SubscribeAccept(m) => {
+                assert_eq!(m.partnering_id, 0x04030201);
+                assert_eq!(m.confirmation_nonce, 0x0D0C0B0A);
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn subscribe_finalize() {
+        let message = Message::SubscribeFinalize(SubscribeFinalize{partnering_id: 0x04030201, confirmation_nonce: 0x0D0C0B0A});
+        assert_eq!(message.encode(), SUBSCRIBE_FINALIZE);
+
+        match Message::decode(SUBSCRIBE_FINALIZE).unwrap() {
+            Message::SubscribeFinalize(m) => {
+                assert_eq!(m.partnering_id, 0x04030201);
+                assert_eq!(m.confirmation_nonce, 0x0D0C0B0A);
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn data() {
+        let signature = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF];
+        let message = Message::Data(Data{format: PayloadFormat::Frame, partnering_id: 0x04030201, signature: signature, sequence_number: 0x12345678, payload: &[0xEE, 0xFF]});
+        assert_eq!(message.encode(), DATA);
+
+        match Message::decode(DATA).unwrap() {
+            Message::Data(m) => {
+                assert_eq!(m.format, PayloadFormat::Frame);
+                assert_eq!(m.partnering_id, 0x04030201);
+                assert_eq!(m.signature, signature);
+                assert_eq!(m.sequence_number, 0x12345678);
+                assert_eq!(m.payload, &[0xEE, 0xFF]);
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn data_bundle() {
+        let signature = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5, 0xA6, 0xA7, 0xA8, 0xA9, 0xAA, 0xAB, 0xAC, 0xAD, 0xAE, 0xAF];
+        let message = Message::Data(Data{format: PayloadFormat::Bundle, partnering_id: 0x04030201, signature: signature, sequence_number: 0x12345678, payload: &[0x01, 0xEE]});
+        assert_eq!(message.encode(), DATA_BUNDLE);
+
+        match Message::decode(DATA_BUNDLE).unwrap() {
+            Message::Data(m) => {
+                assert_eq!(m.format, PayloadFormat::Bundle);
+                assert_eq!(m.payload, &[0x01, 0xEE]);
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn profile_request() {
+        let message = Message::ProfileRequest(ProfileRequest{token: 0x24232221, start_index: 0x100, requested_len: 3});
+        assert_eq!(message.encode(), PROFILE_REQUEST);
+
+        match Message::decode(PROFILE_REQUEST).unwrap() {
+            Message::ProfileRequest(m) => {
+                assert_eq!(m.token, 0x24232221);
+                assert_eq!(m.start_index, 0x100);
+                assert_eq!(m.requested_len, 3);
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn profile_response() {
+        let message = Message::ProfileResponse(ProfileResponse{token: 0x24232221, slice: b"abc"});
+        assert_eq!(message.encode(), PROFILE_RESPONSE);
+
+        match Message::decode(PROFILE_RESPONSE).unwrap() {
+            Message::ProfileResponse(m) => {
+                assert_eq!(m.token, 0x24232221);
+                assert_eq!(m.slice, b"abc");
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn partner_list_request() {
+        let message = Message::PartnerListRequest(PartnerListRequest{token: 0x24232221, start_index: 0x100, requested_len: 3});
+        assert_eq!(message.encode(), PARTNER_LIST_REQUEST);
+
+        match Message::decode(PARTNER_LIST_REQUEST).unwrap() {
+            Message::PartnerListRequest(m) => {
+                assert_eq!(m.token, 0x24232221);
+                assert_eq!(m.start_index, 0x100);
+                assert_eq!(m.requested_len, 3);
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn partner_list_response() {
+        let message = Message::PartnerListResponse(PartnerListResponse{token: 0x24232221, slice: b"0FC8a\0"});
+        assert_eq!(message.encode(), PARTNER_LIST_RESPONSE);
+
+        match Message::decode(PARTNER_LIST_RESPONSE).unwrap() {
+            Message::PartnerListResponse(m) => {
+                assert_eq!(m.token, 0x24232221);
+                assert_eq!(m.slice, b"0FC8a\0");
+            }
+            _ => panic!("decoded as the wrong message"),
+        }
+    }
+
+    #[test]
+    fn fixed_length_messages_reject_extra_bytes() {
+        for golden in &[SUBSCRIBE_DECLINE, SUBSCRIBE_ACCEPT, SUBSCRIBE_FINALIZE] {
+            let mut longer = golden.to_vec();
+            longer.push(0);
+            assert!(Message::decode(&longer).is_err());
+            assert!(Message::decode(&golden[..golden.len() - 1]).is_err());
+        }
+    }
+}
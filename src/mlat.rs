@@ -0,0 +1,485 @@
+use crate::adsb;
+use crate::aircraft_json::escape;
+use crate::adsb::SquitterContent;
+use crate::cpr::Position;
+use crate::mode_s::announced_address;
+use crate::mode_s::downlink_format;
+use crate::mode_s::residual;
+use crate::observer::FrameSource;
+use crate::observer::ObservedFrame;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The MLAT counter runs at 12 MHz
+const TICKS_PER_SECOND: f64 = 12_000_000.0;
+
+/// The counter is 48 bits wide, and wraps
+const COUNTER_MASK: u64 = 0xFFFF_FFFF_FFFF;
+
+const SPEED_OF_LIGHT_METERS_PER_SECOND: f64 = 299_792_458.0;
+
+/// Relayed copies of one transmission all arrive within this long of the first
+pub const DEFAULT_CORRELATION_WINDOW: Duration = Duration::from_secs(2);
+
+/// How much each new sample moves a clock offset estimate
+const OFFSET_SMOOTHING: f64 = 0.1;
+
+/// Samples this far from the estimate, in ticks (about 80 microseconds), are treated as outliers
+const MAX_OFFSET_DEVIATION_TICKS: f64 = 1000.0;
+
+/// After this many outliers in a row, the clocks are assumed to have been reset and the estimate restarts
+const MAX_CONSECUTIVE_OUTLIERS: u32 = 5;
+
+/// Estimates for a pair of clocks not updated for this long are dropped, since clocks drift
+const OFFSET_LIFETIME: Duration = Duration::from_secs(120);
+
+/// Where a receiver's antenna is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReceiverLocation {
+    pub position: Position,
+    pub altitude_meters: f64,
+}
+
+/// One receiver's report of hearing a frame
+#[derive(Clone, Debug)]
+pub struct Observation {
+    /// The receiver, which is one of our inputs or the partner that relayed the frame. A partner with
+    /// several receivers looks like one receiver with an unreliable clock.
+    pub receiver: FrameSource,
+
+    /// The receiver's 12 MHz counter when the frame arrived
+    pub mlat_timestamp: u64,
+}
+
+/// The same transmission as heard by several receivers, which is what multilateration works from
+#[derive(Clone, Debug)]
+pub struct CandidateGroup {
+    pub frame: Vec<u8>,
+    pub address: u32,
+    pub observations: Vec<Observation>,
+}
+
+struct PendingGroup {
+    address: u32,
+    observations: Vec<Observation>,
+    first_seen: Instant,
+
+    /// Where the transmitting aircraft said it was, if the frame was an ADS-B position
+    beacon: Option<Beacon>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Beacon {
+    position: Position,
+
+    /// Feet above sea level
+    altitude: i32,
+}
+
+/// Converts a position to earth-centered, earth-fixed coordinates in meters, on the WGS84 ellipsoid.
+fn ecef(position: &Position, altitude_meters: f64) -> [f64; 3] {
+    const A: f64 = 6_378_137.0;
+    const E2: f64 = 6.694_379_990_14e-3;
+
+    let (lat, lon) = (position.lat.to_radians(), position.lon.to_radians());
+    let n = A / (1.0 - E2 * lat.sin().powi(2)).sqrt();
+    [
+        (n + altitude_meters) * lat.cos() * lon.cos(),
+        (n + altitude_meters) * lat.cos() * lon.sin(),
+        (n * (1.0 - E2) + altitude_meters) * lat.sin(),
+    ]
+}
+
+fn distance_meters(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt()
+}
+
+/// `later - earlier` on the wrapping 48-bit counter, as a signed number of ticks
+fn counter_difference(later: u64, earlier: u64) -> i64 {
+    let difference = later.wrapping_sub(earlier) & COUNTER_MASK;
+    if difference > COUNTER_MASK / 2 {
+        difference as i64 - (COUNTER_MASK as i64 + 1)
+    } else {
+        difference as i64
+    }
+}
+
+struct ClockOffset {
+    /// Ticks to add to the first clock's reading to get the second's
+    ticks: f64,
+    samples: u64,
+    consecutive_outliers: u32,
+    updated: Instant,
+}
+
+/// Estimates how far apart the counters of each pair of receivers are, using ADS-B aircraft as beacons:
+/// an aircraft that says where it is transmits from a known point, so the difference in when two
+/// receivers heard it, less the difference in the time the signal took to reach each, is the difference
+/// between their clocks.
+pub struct ClockSynchronizer {
+    receiver_locations: HashMap<FrameSource, [f64; 3]>,
+    offsets: HashMap<(FrameSource, FrameSource), ClockOffset>,
+}
+
+impl Default for ClockSynchronizer {
+    fn default() -> ClockSynchronizer {
+        ClockSynchronizer::new()
+    }
+}
+
+impl ClockSynchronizer {
+    pub fn new() -> ClockSynchronizer {
+        ClockSynchronizer {
+            receiver_locations: HashMap::new(),
+            offsets: HashMap::new(),
+        }
+    }
+
+    /// Tells the synchronizer where a receiver's antenna is. Receivers with no known location are not
+    /// synchronized.
+    pub fn set_receiver_location(&mut self, receiver: FrameSource, location: &ReceiverLocation) {
+        self.receiver_locations.insert(receiver, ecef(&location.position, location.altitude_meters));
+    }
+
+    fn add_sample(&mut self, first: &FrameSource, second: &FrameSource, sample: f64, now: Instant) {
+        let offset = self.offsets.entry((first.clone(), second.clone())).or_insert(ClockOffset {
+            ticks: sample,
+            samples: 0,
+            consecutive_outliers: 0,
+            updated: now,
+        });
+
+        if offset.samples > 0 && (sample - offset.ticks).abs() > MAX_OFFSET_DEVIATION_TICKS {
+            offset.consecutive_outliers += 1;
+            if offset.consecutive_outliers < MAX_CONSECUTIVE_OUTLIERS {
+                return;
+            }
+            offset.ticks = sample;
+            offset.samples = 0;
+        }
+
+        offset.ticks = if offset.samples == 0 { sample } else { offset.ticks + OFFSET_SMOOTHING * (sample - offset.ticks) };
+        offset.samples += 1;
+        offset.consecutive_outliers = 0;
+        offset.updated = now;
+    }
+
+    /// Learns from one transmission by a beacon at `source`, heard by every receiver in `observations`.
+    fn observe_beacon(&mut self, source: &[f64; 3], observations: &[Observation], now: Instant) {
+        for (i, first) in observations.iter().enumerate() {
+            for second in &observations[i + 1..] {
+                let (first_location, second_location) = match (self.receiver_locations.get(&first.receiver), self.receiver_locations.get(&second.receiver)) {
+                    (Some(first_location), Some(second_location)) => (first_location, second_location),
+                    _ => continue,
+                };
+
+                let flight_time_difference = (distance_meters(source, second_location) - distance_meters(source, first_location))
+                    / SPEED_OF_LIGHT_METERS_PER_SECOND * TICKS_PER_SECOND;
+                let sample = counter_difference(second.mlat_timestamp, first.mlat_timestamp) as f64 - flight_time_difference;
+
+                self.add_sample(&first.receiver, &second.receiver, sample, now);
+                self.add_sample(&second.receiver, &first.receiver, -sample, now);
+            }
+        }
+
+        self.offsets.retain(|_, offset| now.duration_since(offset.updated) < OFFSET_LIFETIME);
+    }
+
+    /// Ticks to add to `first`'s counter to get `second`'s, with the number of samples behind the estimate
+    pub fn offset(&self, first: &FrameSource, second: &FrameSource) -> Option<(f64, u64)> {
+        self.offsets.get(&(first.clone(), second.clone()))
+            .filter(|offset| offset.samples > 0)
+            .map(|offset| (offset.ticks, offset.samples))
+    }
+}
+
+/// Gathers the copies of each transmission heard by different receivers into candidate groups
+pub struct Correlator {
+    window: Duration,
+    pending: HashMap<Vec<u8>, PendingGroup>,
+}
+
+impl Correlator {
+    pub fn new(window: Duration) -> Correlator {
+        Correlator {
+            window: window,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Adds a frame. Frames without an MLAT counter cannot be used.
+    pub fn add(&mut self, observed: &ObservedFrame) {
+        let mlat_timestamp = match observed.frame.mlat_timestamp {
+            Some(mlat_timestamp) => mlat_timestamp,
+            None => return,
+        };
+        let frame = &observed.frame.bytes;
+        if frame.is_empty() {
+            return;
+        }
+
+        let group = self.pending.entry(frame.clone()).or_insert_with(|| {
+            let address = match downlink_format(frame) {
+                11 | 17 | 18 => announced_address(frame),
+                _ => residual(frame),
+            };
+            PendingGroup {
+                address: address,
+                observations: Vec::new(),
+                first_seen: observed.received_at,
+                beacon: None,
+            }
+        });
+
+        // A receiver hearing the same bytes twice in one window is hearing a repeated message, which
+        // says nothing about timing.
+        if group.observations.iter().any(|observation| observation.receiver == observed.source) {
+            return;
+        }
+        group.observations.push(Observation {
+            receiver: observed.source.clone(),
+            mlat_timestamp: mlat_timestamp,
+        });
+
+        if group.beacon.is_none() {
+            group.beacon = beacon(frame, observed);
+        }
+    }
+
+    /// Finishes the groups whose window has passed. Those heard by more than one receiver are returned
+    /// and, if they came from an ADS-B beacon, fed to `clocks`.
+    pub fn take_ready(&mut self, now: Instant, clocks: &mut ClockSynchronizer) -> Vec<CandidateGroup> {
+        let window = self.window;
+        let ready: Vec<Vec<u8>> = self.pending.iter()
+            .filter(|&(_, group)| now.duration_since(group.first_seen) >= window)
+            .map(|(frame, _)| frame.clone())
+            .collect();
+
+        let mut candidates = Vec::new();
+        for frame in ready {
+            let group = self.pending.remove(&frame).unwrap();
+            if group.observations.len() < 2 {
+                continue;
+            }
+
+            if let Some(beacon) = group.beacon {
+                let altitude_meters = beacon.altitude as f64 * 0.3048;
+                clocks.observe_beacon(&ecef(&beacon.position, altitude_meters), &group.observations, now);
+            }
+
+            candidates.push(CandidateGroup {
+                frame: frame,
+                address: group.address,
+                observations: group.observations,
+            });
+        }
+        candidates
+    }
+}
+
+/// Where the aircraft was when it sent `frame`, if it was an airborne position with a known altitude
+fn beacon(frame: &[u8], observed: &ObservedFrame) -> Option<Beacon> {
+    let position = observed.position?;
+    match adsb::decode(frame)?.content {
+        SquitterContent::AirbornePosition(airborne) => Some(Beacon {
+            position: position,
+            altitude: airborne.altitude?,
+        }),
+        _ => None,
+    }
+}
+
+/// How many of the latest candidate groups `mlat.json` shows
+const MAX_RECENT_CANDIDATES: usize = 100;
+
+/// What correlation has learned so far: the clock offsets, and the latest candidate groups
+pub struct MlatState {
+    pub clocks: ClockSynchronizer,
+    recent_candidates: VecDeque<CandidateGroup>,
+}
+
+impl Default for MlatState {
+    fn default() -> MlatState {
+        MlatState::new()
+    }
+}
+
+impl MlatState {
+    pub fn new() -> MlatState {
+        MlatState {
+            clocks: ClockSynchronizer::new(),
+            recent_candidates: VecDeque::new(),
+        }
+    }
+
+    fn add_candidate(&mut self, candidate: CandidateGroup) {
+        if self.recent_candidates.len() == MAX_RECENT_CANDIDATES {
+            self.recent_candidates.pop_front();
+        }
+        self.recent_candidates.push_back(candidate);
+    }
+
+    /// The clock offsets and latest candidate groups as JSON. Each group has its frame in hex, and each
+    /// receiver in it has its time difference of arrival from the first receiver, in microseconds, once
+    /// their clocks are synchronized.
+    pub fn json(&self) -> String {
+        let mut json = String::from("{\"clocks\":[");
+        let mut first_pair = true;
+        for (first, second) in self.clocks.offsets.keys() {
+            if let Some((ticks, samples)) = self.clocks.offset(first, second) {
+                if !first_pair {
+                    json.push(',');
+                }
+                first_pair = false;
+                let _ = write!(json, "{{\"first\":\"{}\",\"second\":\"{}\",\"offset\":{:.1},\"samples\":{}}}",
+                    escape(&first.to_string()), escape(&second.to_string()), ticks, samples);
+            }
+        }
+
+        json.push_str("],\"candidates\":[");
+        for (i, candidate) in self.recent_candidates.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            let _ = write!(json, "{{\"hex\":\"{:06x}\",\"frame\":\"", candidate.address);
+            for byte in &candidate.frame {
+                let _ = write!(json, "{:02x}", byte);
+            }
+            json.push_str("\",\"receivers\":[");
+            let reference = &candidate.observations[0];
+            for (j, observation) in candidate.observations.iter().enumerate() {
+                if j > 0 {
+                    json.push(',');
+                }
+                let _ = write!(json, "{{\"receiver\":\"{}\"", escape(&observation.receiver.to_string()));
+                let offset = if j == 0 { Some(0.0) } else { self.clocks.offset(&reference.receiver, &observation.receiver).map(|(ticks, _)| ticks) };
+                if let Some(offset) = offset {
+                    let ticks = counter_difference(observation.mlat_timestamp, reference.mlat_timestamp) as f64 - offset;
+                    let _ = write!(json, ",\"tdoa_us\":{:.3}", ticks / TICKS_PER_SECOND * 1e6);
+                }
+                json.push('}');
+            }
+            json.push_str("]}");
+        }
+        json.push_str("]}\n");
+        json
+    }
+}
+
+/// Correlates the frames from `frames`, as from `FrameObserver::listen`, keeping `state` up to date.
+/// Partners' receivers are synchronized once a frame they relay says where the receiver is. Returns when
+/// the observer goes away.
+pub fn correlate(frames: Receiver<ObservedFrame>, window: Duration, state: Arc<Mutex<MlatState>>) {
+    let mut correlator = Correlator::new(window);
+    loop {
+        match frames.recv_timeout(window / 2) {
+            Ok(observed) => {
+                if let Some(ref location) = observed.frame.receiver_location {
+                    state.lock().unwrap().clocks.set_receiver_location(observed.source.clone(), location);
+                }
+                correlator.add(&observed);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let mut state = state.lock().unwrap();
+        let ready = correlator.take_ready(Instant::now(), &mut state.clocks);
+        for candidate in ready {
+            state.add_candidate(candidate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::correlate;
+    use super::MlatState;
+    use super::ReceiverLocation;
+    use crate::cpr::Position;
+    use crate::ingest::ModeSFrame;
+    use crate::observer::FrameSource;
+    use crate::observer::ObservedFrame;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use std::time::Instant;
+    use std::time::SystemTime;
+
+    /// An airborne position at 38000 feet
+    const BEACON: [u8; 14] = [0x8D, 0x40, 0x62, 0x1D, 0x58, 0xC3, 0x82, 0xD6, 0x90, 0xC8, 0xAC, 0x28, 0x63, 0xA7];
+
+    fn heard(source: FrameSource, mlat_timestamp: u64, receiver_location: Option<ReceiverLocation>) -> ObservedFrame {
+        ObservedFrame {
+            source: source,
+            frame: ModeSFrame {
+                bytes: BEACON.to_vec(),
+                mlat_timestamp: Some(mlat_timestamp),
+                signal_level: None,
+                received_at: SystemTime::now(),
+                receiver_location: receiver_location,
+            },
+            position: Some(Position{lat: 52.2572, lon: 3.9194}),
+            received_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn synchronizes_a_partner_that_says_where_its_receiver_is() {
+        let local = FrameSource::Local("beast:localhost:30005".to_string());
+        let partner = FrameSource::Partner(7);
+        let state = Arc::new(Mutex::new(MlatState::new()));
+        state.lock().unwrap().clocks.set_receiver_location(local.clone(), &ReceiverLocation {
+            position: Position{lat: 52.0, lon: 4.5},
+            altitude_meters: 0.0,
+        });
+
+        let (frames, receiver) = channel();
+        let thread_state = state.clone();
+        let correlator = thread::spawn(move || correlate(receiver, Duration::from_millis(10), thread_state));
+
+        // The partner's clock is four million ticks ahead; until it says where it is, that cannot be told
+        // apart from the time the signal took.
+        frames.send(heard(local.clone(), 1_000_000, None)).unwrap();
+        frames.send(heard(partner.clone(), 5_000_000, None)).unwrap();
+        sleep(Duration::from_millis(50));
+        assert!(state.lock().unwrap().clocks.offset(&local, &partner).is_none());
+
+        let partner_location = ReceiverLocation {
+            position: Position{lat: 51.5, lon: 3.5},
+            altitude_meters: 10.0,
+        };
+        let mut second = BEACON;
+        second[13] ^= 1;
+        let mut from_local = heard(local.clone(), 2_000_000, None);
+        from_local.frame.bytes = second.to_vec();
+        let mut from_partner = heard(partner.clone(), 6_000_000, Some(partner_location));
+        from_partner.frame.bytes = second.to_vec();
+        frames.send(from_local).unwrap();
+        frames.send(from_partner).unwrap();
+        sleep(Duration::from_millis(50));
+        drop(frames);
+        correlator.join().unwrap();
+
+        let state = state.lock().unwrap();
+        let (ticks, samples) = state.clocks.offset(&local, &partner).unwrap();
+        assert_eq!(samples, 1);
+        assert!((ticks - 4_000_000.0).abs() < 12_000.0, "offset {}", ticks);
+
+        let json = state.json();
+        assert!(json.contains("\"second\":\"partner 7\""), "{}", json);
+        assert!(json.contains("\"tdoa_us\""), "{}", json);
+        let frame: String = second.iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert!(json.contains(&format!("\"frame\":\"{}\"", frame)), "{}", json);
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The Mode S parity generator polynomial, 0xFFF409 with its implicit top bit
+const GENERATOR: u32 = 0x1FFF409;
+
+/// Addresses recovered from address/parity fields are only trusted if the aircraft has been heard
+/// announcing itself this recently
+const KNOWN_ADDRESS_LIFETIME: Duration = Duration::from_secs(60);
+
+const SHORT_FRAME_LEN: usize = 7;
+const LONG_FRAME_LEN: usize = 14;
+
+pub fn downlink_format(frame: &[u8]) -> u8 {
+    let df = frame[0] >> 3;
+    // DF24 and up are all Comm-D, which only uses the first two bits.
+    if df >= 24 { 24 } else { df }
+}
+
+/// The number of bytes a frame with this downlink format should have
+pub fn frame_len(downlink_format: u8) -> usize {
+    if downlink_format < 16 { SHORT_FRAME_LEN } else { LONG_FRAME_LEN }
+}
+
+/// The 24-bit address in the AA field of DF11, DF17, and DF18
+pub fn announced_address(frame: &[u8]) -> u32 {
+    (frame[1] as u32) << 16 | (frame[2] as u32) << 8 | frame[3] as u32
+}
+
+pub fn crc24(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0;
+    for &b in data {
+        crc ^= (b as u32) << 16;
+        for _ in 0..8 {
+            crc <<= 1;
+            if crc & 0x1000000 != 0 {
+                crc ^= GENERATOR;
+            }
+        }
+    }
+    crc & 0xFFFFFF
+}
+
+/// The frame's parity field XORed with the parity computed over the rest of it. For DF11/17/18 this is
+/// 0 (apart from DF11's interrogator ID) if the frame is intact; other formats overlay the aircraft
+/// address, so the residual is the address.
+pub fn residual(frame: &[u8]) -> u32 {
+    let (data, parity) = frame.split_at(frame.len() - 3);
+    crc24(data) ^ ((parity[0] as u32) << 16 | (parity[1] as u32) << 8 | parity[2] as u32)
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Validity {
+    Valid,
+
+    /// A single bit was wrong and has been flipped back
+    Corrected,
+    Invalid,
+}
+
+/// Decides which frames are trustworthy, as dump1090 does: extended squitters and all-call replies must
+/// have correct parity, and everything else must carry the address of an aircraft heard from recently.
+pub struct FrameValidator {
+    correct_errors: bool,
+
+    /// Maps the residual left by a single wrong bit in a long frame to the index of that bit
+    single_bit_syndromes: HashMap<u32, usize>,
+    known_addresses: HashMap<u32, Instant>,
+    last_pruned: Instant,
+}
+
+impl FrameValidator {
+    pub fn new(correct_errors: bool) -> FrameValidator {
+        let mut single_bit_syndromes = HashMap::new();
+        // Flipping one of the first 5 bits would change the downlink format, and it is more likely that the
+        // frame was something else entirely than that it was a DF17/18 with a flipped bit.
+        for bit in 5..LONG_FRAME_LEN * 8 {
+            let mut frame = [0u8; LONG_FRAME_LEN];
+            frame[bit / 8] ^= 0x80 >> (bit % 8);
+            single_bit_syndromes.insert(residual(&frame), bit);
+        }
+
+        FrameValidator {
+            correct_errors: correct_errors,
+            single_bit_syndromes: single_bit_syndromes,
+            known_addresses: HashMap::new(),
+            last_pruned: Instant::now(),
+        }
+    }
+
+    fn is_known(&self, address: u32, now: Instant) -> bool {
+        self.known_addresses.get(&address)
+            .map(|&last_heard| now.duration_since(last_heard) < KNOWN_ADDRESS_LIFETIME)
+            .unwrap_or(false)
+    }
+
+    fn learn(&mut self, address: u32, now: Instant) {
+        self.known_addresses.insert(address, now);
+
+        if now.duration_since(self.last_pruned) > KNOWN_ADDRESS_LIFETIME {
+            self.known_addresses.retain(|_, last_heard| now.duration_since(*last_heard) < KNOWN_ADDRESS_LIFETIME);
+            self.last_pruned = now;
+        }
+    }
+
+    /// Checks `frame`, repairing it in place if that is allowed and possible.
+    pub fn validate(&mut self, frame: &mut [u8]) -> Validity {
+        if frame.is_empty() {
+            return Validity::Invalid;
+        }
+
+        let df = downlink_format(frame);
+        if frame.len() != frame_len(df) {
+            return Validity::Invalid;
+        }
+
+        let now = Instant::now();
+        let residual = residual(frame);
+        match df {
+            17 | 18 => {
+                let validity = if residual == 0 {
+                    Validity::Valid
+                } else if let (true, Some(&bit)) = (self.correct_errors, self.single_bit_syndromes.get(&residual)) {
+                    frame[bit / 8] ^= 0x80 >> (bit % 8);
+                    Validity::Corrected
+                } else {
+                    return Validity::Invalid;
+                };
+
+                self.learn(announced_address(frame), now);
+                validity
+            }
+            11 => {
+                // The low 7 bits may hold the interrogator's ID.
+                if residual & !0x7F != 0 {
+                    return Validity::Invalid;
+                }
+                self.learn(announced_address(frame), now);
+                Validity::Valid
+            }
+            0 | 4 | 5 | 16 | 20 | 21 | 24 => {
+                if self.is_known(residual, now) {
+                    Validity::Valid
+                } else {
+                    Validity::Invalid
+                }
+            }
+            _ => Validity::Invalid,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crc24;
+    use super::residual;
+    use super::FrameValidator;
+    use super::Validity;
+
+    /// KLM1023's identification, with good parity
+    const FRAME: [u8; 14] = [0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98];
+
+    fn flipped(frame: &[u8], bits: &[usize]) -> Vec<u8> {
+        let mut frame = frame.to_vec();
+        for &bit in bits {
+            frame[bit / 8] ^= 0x80 >> (bit % 8);
+        }
+        frame
+    }
+
+    /// A short frame with `overlay` XORed into its parity, as DF11 does with the interrogator ID and DF4 with the address
+    fn short_frame(first_byte: u8, body: u32, overlay: u32) -> Vec<u8> {
+        let mut frame = vec![first_byte, (body >> 16) as u8, (body >> 8) as u8, body as u8];
+        let parity = crc24(&frame) ^ overlay;
+        frame.extend_from_slice(&parity.to_be_bytes()[1..]);
+        frame
+    }
+
+    #[test]
+    fn computes_mode_s_parity() {
+        assert_eq!(crc24(&FRAME[..11]), 0x576098);
+        assert_eq!(residual(&FRAME), 0);
+        assert_eq!(crc24(&[]), 0);
+    }
+
+    #[test]
+    fn repairs_one_wrong_bit() {
+        let mut validator = FrameValidator::new(true);
+        let mut frame = FRAME;
+        assert_eq!(validator.validate(&mut frame), Validity::Valid);
+
+        for &bit in &[5, 40, 87, 100, 111] {
+            let mut frame = flipped(&FRAME, &[bit]);
+            assert_eq!(validator.validate(&mut frame), Validity::Corrected, "bit {}", bit);
+            assert_eq!(frame, FRAME.to_vec());
+        }
+    }
+
+    #[test]
+    fn does_not_repair_unless_asked() {
+        let mut validator = FrameValidator::new(false);
+        assert_eq!(validator.validate(&mut flipped(&FRAME, &[40])), Validity::Invalid);
+    }
+
+    #[test]
+    fn rejects_two_wrong_bits_and_wrong_downlink_formats() {
+        let mut validator = FrameValidator::new(true);
+        assert_eq!(validator.validate(&mut flipped(&FRAME, &[40, 41])), Validity::Invalid);
+        assert_eq!(validator.validate(&mut flipped(&FRAME, &[20, 90])), Validity::Invalid);
+
+        // A flip in the downlink format makes it some other format, which is not repaired as a DF17.
+        let mut frame = flipped(&FRAME, &[4]);
+        assert_ne!(validator.validate(&mut frame), Validity::Corrected);
+    }
+
+    #[test]
+    fn allows_only_an_interrogator_id_in_all_call_replies() {
+        let mut validator = FrameValidator::new(true);
+        assert_eq!(validator.validate(&mut short_frame(0x5D, 0x4840D6, 0)), Validity::Valid);
+        assert_eq!(validator.validate(&mut short_frame(0x5D, 0x4840D6, 0x2A)), Validity::Valid);
+
+        // DF11 errors are not repaired, even single bit ones.
+        let mut frame = flipped(&short_frame(0x5D, 0x4840D6, 0), &[20]);
+        assert_eq!(validator.validate(&mut frame), Validity::Invalid);
+    }
+
+    #[test]
+    fn accepts_address_overlaid_replies_only_from_known_aircraft() {
+        let mut validator = FrameValidator::new(false);
+        // DF4 with an altitude, its parity overlaid with the aircraft's address
+        let mut surveillance = short_frame(0x20, 0x001838, 0x4840D6);
+        assert_eq!(validator.validate(&mut surveillance.clone()), Validity::Invalid);
+
+        let mut frame = FRAME;
+        assert_eq!(validator.validate(&mut frame), Validity::Valid);
+        assert_eq!(validator.validate(&mut surveillance), Validity::Valid);
+
+        let mut stranger = short_frame(0x20, 0x001838, 0x123456);
+        assert_eq!(validator.validate(&mut stranger), Validity::Invalid);
+    }
+}
@@ -1,16 +1,23 @@
+use crate::message::Message;
 use crate::subscribe::handle_subscribe;
 use crate::subscribe_decline::handle_subscribe_decline;
 use crate::subscribe_accept::handle_subscribe_accept;
+use crate::subscribe_finalize::handle_subscribe_finalize;
+use crate::data::handle_data;
 use crate::profile_request::handle_profile_request;
 use crate::profile_response::handle_profile_response;
-use crate::partner_list_response::handle_partner_list_response;
 use crate::partner_list_request::handle_partner_list_request;
+use crate::partner_list_response::handle_partner_list_response;
 use crate::partner_list_request::PartnerListRequest;
 use crate::profile_request::ProfileRequest;
 use crate::data::DataSerializer;
+use crate::data::PayloadFormat;
 use std::net::SocketAddr;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::io;
+use std::time::Duration;
 use std::time::Instant;
-use std::collections::BTreeMap;
 use std::collections::HashSet;
 use std::collections::HashMap;
 use std::cmp::min;
@@ -18,6 +25,20 @@ use std::sync::mpsc::Sender;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::channel;
 use crate::subscribe::Subscribe;
+use crate::transport::Transport;
+use crate::resolver::Resolver;
+use crate::ingest::ModeSFrame;
+use crate::sequence_monitor::AnomalyAction;
+use crate::sequence_monitor::RepeatAndAlternationScorer;
+use crate::sequence_monitor::SequenceMonitor;
+use crate::replay_window::ReplayVerdict;
+use crate::replay_window::ReplayWindow;
+use crate::partner_list;
+use crate::subscription_policy::SubscriptionPolicy;
+use crate::subscription_policy::SubscriptionRequest;
+use crate::subscription_policy::SubscriptionDecision;
+use crate::subscription_policy::SubscriptionHistory;
+use crate::subscription_policy::PartnerCap;
 use rand::rngs::StdRng;
 use rand::FromEntropy;
 use rand::Rng;
@@ -32,7 +53,48 @@ pub struct DataRequestResolution {
     pub bytes: Vec<u8>
 }
 
+/// The contents of a `Data` packet whose signature checked out
+#[derive(Clone, Debug)]
+pub struct ReceivedData {
+    pub partnering_id: u32,
+
+    /// Not needed to use the frames, but it tells packets apart
+    #[allow(dead_code)]
+    pub sequence_number: u32,
+    
+    /// The frames in the packet as the sender sent them, with its reception details when it gave them. They
+    /// have not been validated. A keep-alive has none.
+    pub frames: Vec<ModeSFrame>,
+}
+
+/// Something that happened to a partnership without either side asking for it
+#[derive(Clone, Debug)]
+pub enum PartnershipEvent {
+    /// The partnership's sequence numbers suggested its key is being used by someone else as well
+    SequenceAnomaly{partnering_id: u32, score: f64, action: AnomalyAction},
+}
+
+/// Counts of `Data` packets from one partnership that were signed correctly but still dropped
+#[derive(Clone, Debug, Default)]
+pub struct PartnerStats {
+    /// Packets whose sequence number had already been seen
+    pub replayed: u64,
+    
+    /// Packets whose sequence number was too far behind the highest to tell
+    pub stale: u64,
+    
+    /// Records in the partner's `Data Bundle`s that could not be read
+    pub undecodable: u64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct NodeStats {
+    /// By partnering ID
+    pub partners: HashMap<u32, PartnerStats>,
+}
+
 #[derive(Eq, PartialEq)]
+#[allow(dead_code)] // Requests are told apart by their pending maps instead.
 pub enum DataRequestType {
     Profile,
     PartnerList,
@@ -47,9 +109,16 @@ pub struct Node {
     /// Partnerships that we have proposed
     pending_partnerships: HashMap<u32, (Partnership, Sender<PendingPartnershipResolution>)>,
     
+    /// Partnerships that others have proposed and we have accepted, but which have not been finalized
+    incoming_partnerships: HashMap<u32, IncomingPartnership>,
+    
     /// Active partnerships are what we are actively communicating with
     active_partnerships: HashMap<u32, Partnership>,
     
+    /// Active partnering IDs in the order they became active, so that the partner list only changes
+    /// where a partnership comes or goes, and other nodes reading it in chunks see as little drift as possible
+    active_order: Vec<u32>,
+    
     /// Inactive partnerships are understood to be temporarily offline
     inactive_partnerships: HashMap<u32, Partnership>,
     
@@ -62,8 +131,15 @@ pub struct Node {
     /// List of partnerships in the format expected by other nodes
     partner_list: Vec<u8>,
     
-    /// Instructions for how to reach 
-    contact_method: String,
+    /// Instructions for how to reach us
+    contact_host: String,
+    contact_port: u16,
+    
+    /// Decides whether to accept partnerships that others propose
+    subscription_policy: Box<dyn SubscriptionPolicy>,
+    
+    /// How previous `Subscribe` messages from each IP address were answered
+    subscription_history: HashMap<IpAddr, SubscriptionHistory>,
     
     /// If the node is instructed not to re-send a partnership proposal, it is stored here
     partnership_proposal_not_before: HashMap<Addressable, Instant>,
@@ -73,14 +149,51 @@ pub struct Node {
     
     pending_partner_list_requests: HashMap<u32, Sender<DataRequestResolution>>,
     
+    /// Everyone who wants to hear about verified `Data` from our partners
+    data_listeners: Vec<Sender<ReceivedData>>,
+    
     /// For broadcasts
     sequence_number: u32,
     
+    /// Which recent sequence numbers each partnership has used, so that replayed packets are dropped
+    replay_windows: HashMap<u32, ReplayWindow>,
+    replay_window_size: u32,
+    
+    /// Watches partners' sequence numbers for signs of a compromised key
+    sequence_monitor: SequenceMonitor,
+    anomaly_action: AnomalyAction,
+    
+    /// Everyone who wants to hear about partnerships being demoted or dropped
+    event_listeners: Vec<Sender<PartnershipEvent>>,
+    
+    stats: NodeStats,
+    
     rng: StdRng,
+    
+    transport: Arc<dyn Transport>,
+    
+    /// Looks up hostnames we have been asked to send to, away from the packet handling thread
+    resolver: Resolver,
 }
 
+/// A host and port in the form `to_socket_addrs` accepts, such as "mynode.net:4040" or "[::1]:4040"
 pub type Addressable = String;
 
+pub fn addressable(host: &str, port: u16) -> Addressable {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Splits an `Addressable` back into its host and port
+pub fn split_addressable(who: &str) -> Option<(&str, u16)> {
+    let (host, port) = who.rsplit_once(':')?;
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    Some( (host, port.parse().ok()?) )
+}
+
 pub struct Partnership {
     pub address: Addressable,
     pub resolved_address: Option<SocketAddr>,
@@ -88,6 +201,24 @@ pub struct Partnership {
     pub id: u32,
 }
 
+struct IncomingPartnership {
+    partnership: Partnership,
+    confirmation_nonce: u32,
+    
+    /// If the proposer has not sent a matching `Subscribe Finalize` by now, the partnership is forgotten
+    expires: Instant,
+}
+
+/// Bounds the memory spent remembering who has asked to subscribe
+const MAX_SUBSCRIPTION_HISTORY: usize = 4096;
+
+/// How long a proposer has to answer our `Subscribe Accept` with a `Subscribe Finalize`
+pub const FINALIZE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Bounds the memory spent on partnerships accepted but not yet finalized. Anyone can send a `Subscribe`
+/// from a forged address, so without this a flood of them could take all our memory.
+pub const MAX_INCOMING_PARTNERSHIPS: usize = 256;
+
 #[derive(Debug)]
 pub enum HandleError {
     MissingPacketType,
@@ -95,14 +226,21 @@ pub enum HandleError {
     PacketTruncated,
     PacketContinuedUnexpectedly,
     DeclinedSubscriptionDoesNotExist,
+    FinalizedSubscriptionDoesNotExist,
+    ConfirmationNonceMismatch,
+    InvalidContactMethod,
+    UnknownPartneringId,
+    InvalidSignature,
+    InvalidFrameType,
+    InvalidReceiverLocation,
+    InvalidReceiveTime,
+    SendFailed(io::Error),
 }
 
-fn packet_type_and_body(packet: &[u8]) -> Result<(u8, &[u8]), HandleError> {
-    if packet.len() == 0 {
-        return Err(HandleError::MissingPacketType);
+impl From<io::Error> for HandleError {
+    fn from(e: io::Error) -> HandleError {
+        HandleError::SendFailed(e)
     }
-    let (first, rest) = packet.split_at(1);
-    Ok((first[0], rest))
 }
 
 fn slice_of(source: &[u8], start: u32, len: usize) -> &[u8] {
@@ -121,23 +259,42 @@ fn slice_of(source: &[u8], start: u32, len: usize) -> &[u8] {
 
 
 impl Node {
-    pub fn new(contact_method: String) -> Node {
+    pub fn new(contact_host: String, contact_port: u16, transport: Arc<dyn Transport>) -> Node {
         let mut rng = StdRng::from_entropy();
         let sequence_number = rng.gen();
-        Node {
+        let mut node = Node {
             pending_partnerships: HashMap::new(),
+            incoming_partnerships: HashMap::new(),
             used_partnering_ids: HashSet::new(),
             profile: Vec::new(),
             partner_list: Vec::new(),
             active_partnerships: HashMap::new(),
+            active_order: Vec::new(),
             inactive_partnerships: HashMap::new(),
-            contact_method: contact_method,
+            contact_host: contact_host,
+            contact_port: contact_port,
             partnership_proposal_not_before: HashMap::new(),
+            subscription_policy: Box::new(PartnerCap{
+                max_active_partnerships: 40,
+                retry_delay_seconds: 60 * 60,
+            }),
+            subscription_history: HashMap::new(),
             pending_profile_requests: HashMap::new(),
             pending_partner_list_requests: HashMap::new(),
+            data_listeners: Vec::new(),
             rng: rng,
             sequence_number: sequence_number,
-        }
+            replay_windows: HashMap::new(),
+            replay_window_size: 256,
+            sequence_monitor: SequenceMonitor::new(32, 0.25, Box::new(RepeatAndAlternationScorer{jump_threshold: 1000})),
+            anomaly_action: AnomalyAction::Demote,
+            event_listeners: Vec::new(),
+            stats: NodeStats::default(),
+            resolver: Resolver::new(transport.clone()),
+            transport: transport,
+        };
+        node.update_partner_list();
+        node
     }
 
     fn random_key(&mut self) -> [u8; 32] {
@@ -174,22 +331,22 @@ impl Node {
         }
     }
     
-    fn make_partnership(&mut self, who: Addressable) -> Partnership {
+    fn make_partnership(&mut self, who: Addressable, resolved_address: SocketAddr) -> Partnership {
         Partnership{
             address: who,
-            resolved_address: None,
+            resolved_address: Some(resolved_address),
             key: self.random_key(),
             id: self.unused_partnering_id(),
         }
     }
     
-    pub fn create_partnership_proposal(&mut self, who: Addressable) -> (u32, Vec<u8>, Receiver<PendingPartnershipResolution>) {
+    pub fn create_partnership_proposal(&mut self, who: Addressable, resolved_address: SocketAddr) -> (u32, Vec<u8>, Receiver<PendingPartnershipResolution>) {
         let (sender, receiver) = channel();
     
-        let p = self.make_partnership(who);
+        let p = self.make_partnership(who, resolved_address);
         let id = p.id;
         
-        let message = Subscribe::new(id, &p.key, self.contact_method.as_bytes()).serialize();
+        let message = Message::Subscribe(Subscribe::new(id, &p.key, self.contact_port, self.contact_host.as_bytes())).encode();
         self.used_partnering_ids.insert(id);
         self.pending_partnerships.insert(id, (p, sender));
         
@@ -206,44 +363,175 @@ impl Node {
         }
     }
 
+    pub fn set_subscription_policy(&mut self, policy: Box<dyn SubscriptionPolicy>) {
+        self.subscription_policy = policy;
+    }
+    
+    /// Consults the subscription policy about a `Subscribe` and remembers what it decided.
+    pub fn decide_subscription(&mut self, source: &SocketAddr, contact_method: &str) -> SubscriptionDecision {
+        let now = Instant::now();
+        let decision = self.subscription_policy.decide(&SubscriptionRequest{
+            source: source,
+            contact_method: contact_method,
+            active_partnership_count: self.active_partnerships.len(),
+            pending_partnership_count: self.incoming_partnerships.len(),
+            history: self.subscription_history.get(&source.ip()),
+        });
+        
+        if !self.subscription_history.contains_key(&source.ip()) && self.subscription_history.len() >= MAX_SUBSCRIPTION_HISTORY {
+            let least_recent = self.subscription_history.iter()
+                .min_by_key(|&(_, history)| history.last_request)
+                .map(|(ip, _)| *ip);
+            if let Some(least_recent) = least_recent {
+                self.subscription_history.remove(&least_recent);
+            }
+        }
+        
+        let history = self.subscription_history.entry(source.ip()).or_default();
+        history.last_request = Some(now);
+        match decision {
+            SubscriptionDecision::Accept => {
+                history.accepted += 1;
+            }
+            SubscriptionDecision::Decline{retry_delay_seconds} => {
+                history.declined += 1;
+                history.retry_not_before = now.checked_add(Duration::from_secs(retry_delay_seconds as u64));
+            }
+        }
+        
+        decision
+    }
+    
+    pub fn is_partnering_id_used(&self, partnering_id: u32) -> bool {
+        self.used_partnering_ids.contains(&partnering_id)
+    }
+    
+    /// Whether another proposal can be accepted while the ones already accepted wait to be finalized
+    pub fn has_room_for_incoming_partnership(&self) -> bool {
+        self.incoming_partnerships.len() < MAX_INCOMING_PARTNERSHIPS
+    }
+    
+    /// Remembers a partnership someone else proposed, returning the `Confirmation Nonce` they must echo back
+    /// in a `Subscribe Finalize` before it becomes active.
+    pub fn accept_incoming_partnership(&mut self, partnering_id: u32, key: [u8; 32], who: Addressable) -> u32 {
+        let confirmation_nonce = self.random_u32();
+        
+        // Hostnames are resolved later, from wherever the `Subscribe Finalize` comes from, since resolving them
+        // here could block.
+        let resolved_address = who.parse().ok();
+        
+        self.used_partnering_ids.insert(partnering_id);
+        self.incoming_partnerships.insert(partnering_id, IncomingPartnership{
+            partnership: Partnership{
+                address: who,
+                resolved_address: resolved_address,
+                key: key,
+                id: partnering_id,
+            },
+            confirmation_nonce: confirmation_nonce,
+            expires: Instant::now() + FINALIZE_TIMEOUT,
+        });
+        
+        confirmation_nonce
+    }
+    
+    /// If we have already accepted this exact proposal, this gives the `Confirmation Nonce` we chose for it.
+    /// The `Subscribe` was most likely just duplicated in transit.
+    pub fn incoming_partnership_nonce(&self, partnering_id: u32, key: &[u8]) -> Option<u32> {
+        self.incoming_partnerships.get(&partnering_id)
+            .filter(|incoming| &incoming.partnership.key[..] == key)
+            .map(|incoming| incoming.confirmation_nonce)
+    }
+    
+    /// Promotes an accepted partnership to an active one, provided the proposer echoed back the right nonce in time.
+    pub fn finalize_incoming_partnership(&mut self, partnering_id: u32, confirmation_nonce: u32, source: &SocketAddr) -> Result<(), HandleError> {
+        self.expire_incoming_partnerships(Instant::now());
+        
+        let incoming = self.incoming_partnerships.remove(&partnering_id).ok_or(HandleError::FinalizedSubscriptionDoesNotExist)?;
+        if incoming.confirmation_nonce != confirmation_nonce {
+            // A wrong guess costs the proposal, so that the nonce cannot be searched for.
+            self.used_partnering_ids.remove(&partnering_id);
+            return Err(HandleError::ConfirmationNonceMismatch);
+        }
+        
+        let mut partnership = incoming.partnership;
+        if partnership.resolved_address.is_none() {
+            partnership.resolved_address = Some(*source);
+        }
+        self.add_active_partnership(partnership);
+        
+        Ok( () )
+    }
+    
+    pub fn expire_incoming_partnerships(&mut self, now: Instant) {
+        let used_partnering_ids = &mut self.used_partnering_ids;
+        self.incoming_partnerships.retain(|id, incoming| {
+            let keep = incoming.expires > now;
+            if !keep {
+                used_partnering_ids.remove(id);
+            }
+            keep
+        });
+    }
+    
     pub fn get_partnership(&self, partnership_id: u32) -> Option<&Partnership> {
         self.active_partnerships.get(&partnership_id).or_else(|| {
             self.inactive_partnerships.get(&partnership_id)
         })
     }
     
-    pub fn update_partner_list(&mut self) {
-        let mut partner_list = Vec::new();
+    /// Rebuilds the partner list string from our contact details and active partnerships. Each entry is
+    /// `[Port: 4 upper case hex digits] [IP Address or hostname] 0x00`, and ours comes first.
+    fn update_partner_list(&mut self) {
+        fn push_entry(partner_list: &mut Vec<u8>, host: &str, port: u16) {
+            let entry = format!("{:04X}{}", port, host);
+            // Anything other nodes would reject, such as a host containing the separator, is left out
+            // rather than spoiling the entries around it.
+            if partner_list::parse_entry(entry.as_bytes()).is_none() {
+                return;
+            }
+            partner_list.extend(entry.as_bytes());
+            partner_list.push(0);
+        }
         
-        partner_list.extend(self.contact_method.as_bytes());
-        partner_list.push(0);
+        let mut partner_list = Vec::new();
+        push_entry(&mut partner_list, &self.contact_host, self.contact_port);
         
-        for partner in self.active_partnerships.values() {
-            partner_list.extend(partner.address.as_bytes());
-            partner_list.push(0);
+        for partner in self.active_order.iter().filter_map(|id| self.active_partnerships.get(id)) {
+            if let Some((host, port)) = split_addressable(&partner.address) {
+                push_entry(&mut partner_list, host, port);
+            }
         }
+        
+        self.partner_list = partner_list;
     }
 
-    pub fn send(&self, destination: &SocketAddr, packet: &[u8]) {
+    pub fn send(&self, destination: &SocketAddr, packet: &[u8]) -> io::Result<()> {
+        self.transport.send(destination, packet)
+    }
+    
+    /// Sends to an address that may need a (blocking) name lookup first. When it does, the lookup and send
+    /// happen on the resolver's worker thread, and failures there can only be logged.
+    pub fn send_to_addressable(&self, who: &str, packet: Vec<u8>) -> io::Result<()> {
+        if let Ok(socket_addr) = who.parse::<SocketAddr>() {
+            return self.send(&socket_addr, &packet);
+        }
         
+        self.resolver.send(who, packet)
     }
     
     pub fn handle_received_packet(&mut self, source: &SocketAddr, packet: &[u8]) -> Result<(), HandleError> {
-        let (packet_type, body) = packet_type_and_body(packet)?;
-        
-        let handlers = [
-            handle_subscribe,
-            handle_subscribe_decline,
-            handle_subscribe_accept,
-            handle_profile_request,
-            handle_profile_response,
-            handle_partner_list_request,
-            handle_partner_list_response,
-        ];
-        
-        let handler = handlers.get(packet_type as usize).ok_or(HandleError::InvalidPacketType)?;
-        
-        handler(self, source, body)
+        match Message::decode(packet)? {
+            Message::Subscribe(message) => handle_subscribe(self, source, message),
+            Message::SubscribeDecline(message) => handle_subscribe_decline(self, source, message),
+            Message::SubscribeAccept(message) => handle_subscribe_accept(self, source, message),
+            Message::SubscribeFinalize(message) => handle_subscribe_finalize(self, source, message),
+            Message::Data(message) => handle_data(self, source, message),
+            Message::ProfileRequest(message) => handle_profile_request(self, source, message),
+            Message::ProfileResponse(message) => handle_profile_response(self, source, message),
+            Message::PartnerListRequest(message) => handle_partner_list_request(self, source, message),
+            Message::PartnerListResponse(message) => handle_partner_list_response(self, source, message),
+        }
     }
     
     pub fn extract_profile_slice(&mut self, start: u32, len: usize) -> &[u8] {
@@ -258,30 +546,160 @@ impl Node {
         self.active_partnerships.len()
     }
     
+    /// Makes a partnership active. A partnership with the same ID that was inactive or still being
+    /// negotiated is replaced, and a demoted one starts its sequence number history afresh.
     pub fn add_active_partnership(&mut self, partnership: Partnership) {
         let id = partnership.id;
-        self.active_partnerships.insert(id, partnership);
+        if self.inactive_partnerships.remove(&id).is_some() {
+            self.sequence_monitor.forget(id);
+        }
+        self.pending_partnerships.remove(&id);
+        self.incoming_partnerships.remove(&id);
+        if self.active_partnerships.insert(id, partnership).is_none() {
+            self.active_order.push(id);
+        }
         self.used_partnering_ids.insert(id);
+        self.update_partner_list();
+    }
+    
+    /// Stops sending to a partnership, while still accepting what it sends. Returns whether it was active.
+    pub fn demote_partnership(&mut self, partnering_id: u32) -> bool {
+        match self.active_partnerships.remove(&partnering_id) {
+            Some(partnership) => {
+                self.inactive_partnerships.insert(partnering_id, partnership);
+                self.active_order.retain(|&id| id != partnering_id);
+                self.update_partner_list();
+                true
+            }
+            None => false,
+        }
+    }
+    
+    /// Forgets a partnership, active or not. Its partnering ID stays reserved.
+    pub fn remove_partnership(&mut self, partnering_id: u32) -> Option<Partnership> {
+        self.sequence_monitor.forget(partnering_id);
+        self.replay_windows.remove(&partnering_id);
+        let removed = self.active_partnerships.remove(&partnering_id);
+        if removed.is_some() {
+            self.active_order.retain(|&id| id != partnering_id);
+            self.update_partner_list();
+            return removed;
+        }
+        self.inactive_partnerships.remove(&partnering_id)
+    }
+    
+    /// Chooses how many sequence numbers back from the highest a reordered packet may be and still be
+    /// accepted. Windows already in use keep their size.
+    pub fn set_replay_window_size(&mut self, size: u32) {
+        self.replay_window_size = size;
+    }
+    
+    /// Returns whether a signed `Data` packet is new, counting it against the partnership if it is not.
+    pub fn check_replay(&mut self, partnering_id: u32, sequence_number: u32) -> bool {
+        let size = self.replay_window_size;
+        let verdict = self.replay_windows.entry(partnering_id)
+            .or_insert_with(|| ReplayWindow::new(size))
+            .check(sequence_number);
+        match verdict {
+            ReplayVerdict::New => true,
+            ReplayVerdict::Replay => {
+                self.stats.partners.entry(partnering_id).or_default().replayed += 1;
+                false
+            }
+            ReplayVerdict::TooOld => {
+                self.stats.partners.entry(partnering_id).or_default().stale += 1;
+                false
+            }
+        }
+    }
+    
+    /// Chooses how partners' sequence numbers are judged, and what to do with a partnership that looks compromised
+    pub fn set_sequence_monitor(&mut self, monitor: SequenceMonitor, action: AnomalyAction) {
+        self.sequence_monitor = monitor;
+        self.anomaly_action = action;
+    }
+    
+    /// Returns a channel that hears about every partnership demoted or dropped from now on
+    pub fn listen_for_partnership_events(&mut self) -> Receiver<PartnershipEvent> {
+        let (sender, receiver) = channel();
+        self.event_listeners.push(sender);
+        receiver
+    }
+    
+    fn emit_event(&mut self, event: PartnershipEvent) {
+        self.event_listeners.retain(|listener| listener.send(event.clone()).is_ok());
+    }
+    
+    /// Feeds a signed `Data` packet's sequence number to the sequence monitor. If the partnership looks
+    /// compromised, it is demoted or dropped and `false` is returned, meaning the packet should be ignored.
+    /// Demotion is a partnership's one warning: the monitor starts a fresh window when it trips, and if
+    /// that window trips too while the partnership is still inactive, the partnership is dropped.
+    pub fn check_sequence_number(&mut self, partnering_id: u32, sequence_number: u32) -> bool {
+        let score = match self.sequence_monitor.record(partnering_id, sequence_number, Instant::now()) {
+            Some(score) => score,
+            None => return true,
+        };
+        
+        let action = if self.anomaly_action == AnomalyAction::Demote && self.demote_partnership(partnering_id) {
+            AnomalyAction::Demote
+        } else {
+            self.remove_partnership(partnering_id);
+            AnomalyAction::Drop
+        };
+        self.emit_event(PartnershipEvent::SequenceAnomaly{
+            partnering_id: partnering_id,
+            score: score,
+            action: action,
+        });
+        false
+    }
+    
+    /// Where our active partners can be reached, such as for requesting their partner lists
+    pub fn active_partner_addresses(&self) -> Vec<SocketAddr> {
+        self.active_partnerships.values()
+            .filter_map(|partnership| partnership.resolved_address)
+            .collect()
+    }
+    
+    /// Whether proposing a partnership to `who` would be pointless for now: it is us, we already have or
+    /// are negotiating a partnership with it, or it has asked us to wait.
+    pub fn is_unsuitable_partner_candidate(&self, who: &str, now: Instant) -> bool {
+        if who == addressable(&self.contact_host, self.contact_port) {
+            return true;
+        }
+        
+        let is_partner = self.active_partnerships.values()
+            .chain(self.inactive_partnerships.values())
+            .chain(self.pending_partnerships.values().map(|(partnership, _)| partnership))
+            .chain(self.incoming_partnerships.values().map(|incoming| &incoming.partnership))
+            .any(|partnership| partnership.address == who);
+        if is_partner {
+            return true;
+        }
+        
+        self.partnership_proposal_not_before.get(who)
+            .map(|&not_before| now < not_before)
+            .unwrap_or(false)
     }
     
     pub fn delay_partnership_proposal_until(&mut self, addressable: Addressable, when: Instant) {
         self.partnership_proposal_not_before.insert(addressable, when);
     }
     
-    pub fn send_partner_list_request(&mut self, destination: &SocketAddr, start_index: u32, len: usize) -> (u32, Receiver<DataRequestResolution>) {
+    pub fn send_partner_list_request(&mut self, destination: &SocketAddr, start_index: u32, len: usize) -> io::Result<(u32, Receiver<DataRequestResolution>)> {
         let token = self.unused_partner_list_request_token();
+        self.send(destination, &Message::PartnerListRequest(PartnerListRequest{token: token, start_index: start_index, requested_len: len}).encode())?;
         let (sender, receiver) = channel();
         self.pending_partner_list_requests.insert(token, sender);
-        self.send(destination, &PartnerListRequest{token: token, start_index: start_index, requested_len: len}.serialize());
-        (token, receiver)
+        Ok( (token, receiver) )
     }
     
-    pub fn send_profile_request(&mut self, destination: &SocketAddr, start_index: u32, len: usize) -> (u32, Receiver<DataRequestResolution>) {
+    pub fn send_profile_request(&mut self, destination: &SocketAddr, start_index: u32, len: usize) -> io::Result<(u32, Receiver<DataRequestResolution>)> {
         let token = self.unused_profile_request_token();
+        self.send(destination, &Message::ProfileRequest(ProfileRequest{token: token, start_index: start_index, requested_len: len}).encode())?;
         let (sender, receiver) = channel();
         self.pending_profile_requests.insert(token, sender);
-        self.send(destination, &ProfileRequest{token: token, start_index: start_index, requested_len: len}.serialize());
-        (token, receiver)
+        Ok( (token, receiver) )
     }
     
     pub fn cancel_partner_list_request(&mut self, token: u32) {
@@ -306,15 +724,297 @@ impl Node {
         Node::resolve_data_request(token, data, &mut self.pending_partner_list_requests)
     }
     
-    pub fn broadcast(&mut self, data: &[u8]) {
-        let mut serializer = DataSerializer::new(self.sequence_number, data);
+    pub fn stats(&self) -> &NodeStats {
+        &self.stats
+    }
+    
+    /// Counts a record from a partner that was skipped because it could not be read
+    pub fn count_undecodable_record(&mut self, partnering_id: u32) {
+        self.stats.partners.entry(partnering_id).or_default().undecodable += 1;
+    }
+    
+    /// Returns a channel that receives the frames of every verified `Data` packet from now on
+    pub fn listen_for_data(&mut self) -> Receiver<ReceivedData> {
+        let (sender, receiver) = channel();
+        self.data_listeners.push(sender);
+        receiver
+    }
+    
+    pub fn deliver_data(&mut self, data: ReceivedData) {
+        // Listeners that have hung up are forgotten.
+        self.data_listeners.retain(|listener| listener.send(data.clone()).is_ok());
+    }
+    
+    /// Sends `data`, a single frame or nothing for a keep-alive, to every active partner as a `Data` packet.
+    /// Our own frames go out in bundles instead, so for now nothing but keep-alives would use this.
+    #[allow(dead_code)]
+    pub fn broadcast(&mut self, data: &[u8]) -> io::Result<()> {
+        self.broadcast_as(PayloadFormat::Frame, data)
+    }
+    
+    /// Sends `bundle`, records of frames heard by our own receivers, to every active partner as a
+    /// `Data Bundle` packet.
+    pub fn broadcast_bundle(&mut self, bundle: &[u8]) -> io::Result<()> {
+        self.broadcast_as(PayloadFormat::Bundle, bundle)
+    }
+    
+    /// Sends `payload` to every active partner. A failure to reach one partner does not stop the others
+    /// from being sent to; the last error encountered is returned.
+    fn broadcast_as(&mut self, format: PayloadFormat, payload: &[u8]) -> io::Result<()> {
+        let mut result = Ok( () );
+        let mut serializer = DataSerializer::new(self.sequence_number, format, payload);
         for active_partnership in self.active_partnerships.values() {
             if let Some(ref resolved_address) = active_partnership.resolved_address {
                 let packet = serializer.serialize_for(active_partnership.id, &active_partnership.key);
             
-                self.send(resolved_address, packet);
+                if let Err(e) = self.send(resolved_address, packet) {
+                    result = Err(e);
+                }
             }
         }
         self.sequence_number = self.sequence_number.wrapping_add(1);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HandleError;
+    use super::Node;
+    use super::Partnership;
+    use super::PendingPartnershipResolution;
+    use crate::data::DataSerializer;
+    use crate::data::PayloadFormat;
+    use crate::message;
+    use crate::message::Message;
+    use crate::partner_list_request::PartnerListRequest;
+    use crate::partner_list_response::PartnerListResponse;
+    use crate::profile_request::ProfileRequest;
+    use crate::profile_response::ProfileResponse;
+    use crate::simulated_network::LinkConditions;
+    use crate::simulated_network::SimulatedEndpoint;
+    use crate::simulated_network::SimulatedNetwork;
+    use crate::subscribe::Subscribe;
+    use crate::subscribe_accept::SubscribeAccept;
+    use crate::subscribe_decline::SubscribeDecline;
+    use crate::subscribe_finalize::SubscribeFinalize;
+    use crate::transport::Transport;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    const KEY: [u8; 32] = [5; 32];
+
+    /// Our node, and a peer endpoint that sees whatever the node sends it
+    struct Harness {
+        network: SimulatedNetwork,
+        node: Node,
+        peer: SimulatedEndpoint,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+            let address: SocketAddr = "10.0.0.1:4040".parse().unwrap();
+            let node = Node::new(address.ip().to_string(), address.port(), Arc::new(network.endpoint(address).unwrap()));
+            let peer = network.endpoint("10.0.0.2:4040".parse().unwrap()).unwrap();
+            Harness {
+                network: network,
+                node: node,
+                peer: peer,
+            }
+        }
+
+        fn peer_address(&self) -> SocketAddr {
+            self.peer.address()
+        }
+
+        fn handle(&mut self, message: Message) -> Result<(), HandleError> {
+            let source = self.peer_address();
+            self.node.handle_received_packet(&source, &message.encode())
+        }
+
+        /// The packet the node sent the peer, if it sent one
+        fn sent(&self) -> Option<Vec<u8>> {
+            if self.network.in_flight_count() == 0 {
+                return None;
+            }
+            let mut buf = [0u8; 2048];
+            let (len, _) = self.peer.receive(&mut buf).unwrap();
+            Some(buf[..len].to_vec())
+        }
+    }
+
+    #[test]
+    fn subscribe_is_answered_with_subscribe_accept() {
+        let mut harness = Harness::new();
+        harness.handle(Message::Subscribe(Subscribe::new(9, &KEY, 4040, b"10.0.0.2"))).unwrap();
+
+        let sent = harness.sent().expect("nothing was sent");
+        match Message::decode(&sent).unwrap() {
+            Message::SubscribeAccept(accept) => assert_eq!(accept.partnering_id, 9),
+            _ => panic!("expected a Subscribe Accept"),
+        }
+    }
+
+    #[test]
+    fn subscribe_finalize_activates_the_partnership() {
+        let mut harness = Harness::new();
+        harness.handle(Message::Subscribe(Subscribe::new(9, &KEY, 4040, b"10.0.0.2"))).unwrap();
+        let sent = harness.sent().unwrap();
+        let confirmation_nonce = match Message::decode(&sent).unwrap() {
+            Message::SubscribeAccept(accept) => accept.confirmation_nonce,
+            _ => panic!("expected a Subscribe Accept"),
+        };
+
+        harness.handle(Message::SubscribeFinalize(SubscribeFinalize{partnering_id: 9, confirmation_nonce: confirmation_nonce})).unwrap();
+        assert_eq!(harness.node.active_partnership_count(), 1);
+    }
+
+    #[test]
+    fn subscribe_decline_resolves_the_proposal() {
+        let mut harness = Harness::new();
+        let peer_address = harness.peer_address();
+        let (partnering_id, _, resolution) = harness.node.create_partnership_proposal(peer_address.to_string(), peer_address);
+
+        harness.handle(Message::SubscribeDecline(SubscribeDecline{partnering_id: partnering_id, retry_delay_seconds: 60})).unwrap();
+        match resolution.try_recv() {
+            Ok(PendingPartnershipResolution::Declined{retry_delay_seconds}) => assert_eq!(retry_delay_seconds, 60),
+            _ => panic!("expected the proposal to be declined"),
+        }
+    }
+
+    #[test]
+    fn subscribe_accept_resolves_the_proposal() {
+        let mut harness = Harness::new();
+        let peer_address = harness.peer_address();
+        let (partnering_id, _, resolution) = harness.node.create_partnership_proposal(peer_address.to_string(), peer_address);
+
+        harness.handle(Message::SubscribeAccept(SubscribeAccept{partnering_id: partnering_id, confirmation_nonce: 1234})).unwrap();
+        match resolution.try_recv() {
+            Ok(PendingPartnershipResolution::Accepted(confirmation_nonce)) => assert_eq!(confirmation_nonce, 1234),
+            _ => panic!("expected the proposal to be accepted"),
+        }
+        assert_eq!(harness.node.active_partnership_count(), 1);
+    }
+
+    #[test]
+    fn data_is_delivered() {
+        let mut harness = Harness::new();
+        let peer_address = harness.peer_address();
+        harness.node.add_active_partnership(Partnership {
+            address: peer_address.to_string(),
+            resolved_address: Some(peer_address),
+            key: KEY,
+            id: 9,
+        });
+        let data = harness.node.listen_for_data();
+
+        let packet = DataSerializer::new(77, PayloadFormat::Frame, &[]).serialize_for(9, &KEY).to_vec();
+        harness.node.handle_received_packet(&peer_address, &packet).unwrap();
+        assert_eq!(data.try_recv().unwrap().sequence_number, 77);
+    }
+
+    #[test]
+    fn profile_request_is_answered_with_profile_response() {
+        let mut harness = Harness::new();
+        harness.handle(Message::ProfileRequest(ProfileRequest{token: 31, start_index: 0, requested_len: 100})).unwrap();
+
+        let sent = harness.sent().expect("nothing was sent");
+        match Message::decode(&sent).unwrap() {
+            Message::ProfileResponse(response) => assert_eq!(response.token, 31),
+            _ => panic!("expected a Profile Response"),
+        }
+    }
+
+    #[test]
+    fn partner_list_request_is_answered_with_partner_list_response() {
+        let mut harness = Harness::new();
+        harness.handle(Message::PartnerListRequest(PartnerListRequest{token: 32, start_index: 0, requested_len: 100})).unwrap();
+
+        let sent = harness.sent().expect("nothing was sent");
+        match Message::decode(&sent).unwrap() {
+            Message::PartnerListResponse(response) => {
+                assert_eq!(response.token, 32);
+                assert_eq!(response.slice, &b"0FC810.0.0.1\0"[..]);
+            }
+            _ => panic!("expected a Partner List Response"),
+        }
+    }
+
+    #[test]
+    fn profile_response_resolves_the_request() {
+        let mut harness = Harness::new();
+        let peer_address = harness.peer_address();
+        let (token, response) = harness.node.send_profile_request(&peer_address, 0, 16).unwrap();
+
+        harness.handle(Message::ProfileResponse(ProfileResponse{token: token, slice: b"profile"})).unwrap();
+        assert_eq!(response.try_recv().unwrap().bytes, b"profile".to_vec());
+    }
+
+    #[test]
+    fn partner_list_response_resolves_the_request() {
+        let mut harness = Harness::new();
+        let peer_address = harness.peer_address();
+        let (token, response) = harness.node.send_partner_list_request(&peer_address, 0, 16).unwrap();
+
+        harness.handle(Message::PartnerListResponse(PartnerListResponse{token: token, slice: b"0FC8a\0"})).unwrap();
+        assert_eq!(response.try_recv().unwrap().bytes, b"0FC8a\0".to_vec());
+    }
+
+    #[test]
+    fn unknown_packet_types_are_reported() {
+        let mut harness = Harness::new();
+        let source = harness.peer_address();
+        let known = [
+            message::SUBSCRIBE,
+            message::SUBSCRIBE_DECLINE,
+            message::SUBSCRIBE_ACCEPT,
+            message::SUBSCRIBE_FINALIZE,
+            message::DATA,
+            message::DATA_BUNDLE,
+            message::PROFILE_REQUEST,
+            message::PROFILE_RESPONSE,
+            message::PARTNER_LIST_REQUEST,
+            message::PARTNER_LIST_RESPONSE,
+        ];
+        for type_byte in 0..=255u8 {
+            if known.contains(&type_byte) {
+                continue;
+            }
+            let result = harness.node.handle_received_packet(&source, &[type_byte, 0, 0, 0, 0]);
+            assert!(matches!(result, Err(HandleError::InvalidPacketType)), "type {:#04x}", type_byte);
+        }
+        assert!(matches!(harness.node.handle_received_packet(&source, &[]), Err(HandleError::MissingPacketType)));
+    }
+
+    #[test]
+    fn partner_list_keeps_partners_in_the_order_they_joined() {
+        let mut harness = Harness::new();
+        let add = |node: &mut Node, id: u32, host: &str| {
+            node.add_active_partnership(Partnership {
+                address: format!("{}:4040", host),
+                resolved_address: None,
+                key: KEY,
+                id: id,
+            });
+        };
+        let list = |node: &mut Node| node.extract_partner_list_slice(0, 1024).to_vec();
+
+        // Partnering IDs are random, so they are added out of order here.
+        add(&mut harness.node, 900, "a");
+        add(&mut harness.node, 5, "b");
+        add(&mut harness.node, 40, "c");
+        assert_eq!(list(&mut harness.node), b"0FC810.0.0.1\x000FC8a\x000FC8b\x000FC8c\x00".to_vec());
+
+        harness.node.remove_partnership(5);
+        add(&mut harness.node, 1, "d");
+        assert_eq!(list(&mut harness.node), b"0FC810.0.0.1\x000FC8a\x000FC8c\x000FC8d\x00".to_vec());
+
+        harness.node.demote_partnership(900);
+        assert!(harness.node.inactive_partnerships.contains_key(&900));
+        add(&mut harness.node, 900, "a");
+        assert_eq!(list(&mut harness.node), b"0FC810.0.0.1\x000FC8c\x000FC8d\x000FC8a\x00".to_vec());
+        assert!(!harness.node.inactive_partnerships.contains_key(&900));
+        assert_eq!(harness.node.active_partnership_count(), 3);
     }
 }
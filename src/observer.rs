@@ -0,0 +1,257 @@
+use crate::adsb;
+use crate::adsb::SquitterContent;
+use crate::beast_output::EchoGuard;
+use crate::cpr::Position;
+use crate::cpr::PositionResolver;
+use crate::ingest::ModeSFrame;
+use crate::mode_s::FrameValidator;
+use crate::mode_s::Validity;
+use crate::node::ReceivedData;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::time::Instant;
+
+/// Where a Mode S frame came from
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum FrameSource {
+    /// One of our own receiver inputs, by name
+    Local(String),
+
+    /// A partner, by partnering ID
+    Partner(u32),
+}
+
+impl fmt::Display for FrameSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameSource::Local(ref name) => write!(f, "{}", name),
+            FrameSource::Partner(partnering_id) => write!(f, "partner {}", partnering_id),
+        }
+    }
+}
+
+/// A frame that passed validation, whether heard by our own receivers or relayed by a partner
+#[derive(Clone, Debug)]
+pub struct ObservedFrame {
+    pub source: FrameSource,
+    pub frame: ModeSFrame,
+
+    /// Where the frame places its aircraft, if it carried a position that could be resolved
+    pub position: Option<Position>,
+    pub received_at: Instant,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FrameStats {
+    /// Frames that passed validation, including corrected ones
+    pub accepted: u64,
+    pub corrected: u64,
+    pub rejected: u64,
+
+    /// Frames dropped because they were our own Beast output coming back in
+    pub echoed: u64,
+}
+
+/// Checks every frame, local or relayed, resolves the positions they carry, and passes them on to
+/// everyone listening. Everything here works on frames already received, so it is kept out of the `Node`.
+pub struct FrameObserver {
+    /// Keeps corrupt frames, whether from our receivers or our partners, from being passed on
+    frame_validator: FrameValidator,
+
+    /// Turns CPR encodings from every source into aircraft positions
+    position_resolver: PositionResolver,
+
+    /// Set while a Beast output is running, to keep its frames from looping back in as local ones
+    echo_guard: Option<EchoGuard>,
+
+    listeners: Vec<Sender<ObservedFrame>>,
+    stats: HashMap<FrameSource, FrameStats>,
+}
+
+impl FrameObserver {
+    pub fn new(correct_errors: bool, receiver_location: Option<Position>) -> FrameObserver {
+        FrameObserver {
+            frame_validator: FrameValidator::new(correct_errors),
+            position_resolver: PositionResolver::new(receiver_location),
+            echo_guard: None,
+            listeners: Vec::new(),
+            stats: HashMap::new(),
+        }
+    }
+
+    /// Starts recognizing frames served by a Beast output when they come back through our inputs.
+    pub fn set_echo_guard(&mut self, echo_guard: EchoGuard) {
+        self.echo_guard = Some(echo_guard);
+    }
+
+    /// Returns a channel that receives every valid frame, local or relayed, from now on
+    pub fn listen(&mut self) -> Receiver<ObservedFrame> {
+        let (sender, receiver) = channel();
+        self.listeners.push(sender);
+        receiver
+    }
+
+    pub fn stats(&self) -> &HashMap<FrameSource, FrameStats> {
+        &self.stats
+    }
+
+    /// The most recently resolved position of an aircraft, from any source
+    #[allow(dead_code)]
+    pub fn aircraft_position(&self, address: u32) -> Option<Position> {
+        self.position_resolver.position(address)
+    }
+
+    /// Checks a frame's parity, repairing it if possible, and passes it to the listeners with its
+    /// position resolved. Returns the frame, repaired, if it is fit to use, and `None` if it was corrupt
+    /// or an echo of our own Beast output.
+    pub fn observe(&mut self, source: FrameSource, mut frame: ModeSFrame) -> Option<ModeSFrame> {
+        let now = Instant::now();
+        let stats = self.stats.entry(source.clone()).or_default();
+        if let (FrameSource::Local(_), Some(ref echo_guard)) = (&source, &self.echo_guard) {
+            if echo_guard.is_echo(&frame, now) {
+                stats.echoed += 1;
+                return None;
+            }
+        }
+        match self.frame_validator.validate(&mut frame.bytes) {
+            Validity::Valid => {
+                stats.accepted += 1;
+            }
+            Validity::Corrected => {
+                stats.accepted += 1;
+                stats.corrected += 1;
+            }
+            Validity::Invalid => {
+                stats.rejected += 1;
+                return None;
+            }
+        }
+
+        let position = self.resolve_position(&source, &frame.bytes, now);
+        let observed = ObservedFrame {
+            source: source,
+            frame: frame,
+            position: position,
+            received_at: now,
+        };
+        if let Some(ref mut echo_guard) = self.echo_guard {
+            echo_guard.record(&observed);
+        }
+        // Listeners that have hung up are forgotten.
+        self.listeners.retain(|listener| listener.send(observed.clone()).is_ok());
+        Some(observed.frame)
+    }
+
+    /// Feeds a validated frame's position, if it carries one, to the per-aircraft CPR state
+    fn resolve_position(&mut self, source: &FrameSource, frame: &[u8], now: Instant) -> Option<Position> {
+        let squitter = adsb::decode(frame)?;
+        if !squitter.address_is_icao {
+            // Non-ICAO addresses may coincide with an aircraft's, so their positions are not mixed in.
+            return None;
+        }
+        let (cpr, surface) = match squitter.content {
+            SquitterContent::AirbornePosition(position) => (position.cpr, false),
+            SquitterContent::SurfacePosition(position) => (position.cpr, true),
+            _ => return None,
+        };
+
+        let heard_locally = match *source {
+            FrameSource::Local(_) => true,
+            FrameSource::Partner(_) => false,
+        };
+        self.position_resolver.resolve(squitter.address, cpr, surface, heard_locally, now)
+    }
+}
+
+/// Observes the frames of every `Data` packet from `data`, as from `Node::listen_for_data`. Returns when
+/// the node goes away.
+pub fn observe_partner_data(data: Receiver<ReceivedData>, observer: Arc<Mutex<FrameObserver>>) {
+    for data in data {
+        let mut observer = observer.lock().unwrap();
+        for frame in data.frames {
+            observer.observe(FrameSource::Partner(data.partnering_id), frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FrameObserver;
+    use super::FrameSource;
+    use crate::beast::BeastDecoder;
+    use crate::beast_output::encode_frame;
+    use crate::beast_output::EchoGuard;
+    use crate::ingest::FrameDecoder;
+    use crate::ingest::ModeSFrame;
+    use std::time::SystemTime;
+
+    const FRAME: [u8; 14] = [0x8D, 0x48, 0x40, 0xD6, 0x20, 0x2C, 0xC3, 0x71, 0xC3, 0x2C, 0xE0, 0x57, 0x60, 0x98];
+
+    fn frame(bytes: &[u8]) -> ModeSFrame {
+        ModeSFrame {
+            bytes: bytes.to_vec(),
+            mlat_timestamp: None,
+            signal_level: None,
+            received_at: SystemTime::now(),
+            receiver_location: None,
+        }
+    }
+
+    #[test]
+    fn passes_on_valid_frames_only() {
+        let mut observer = FrameObserver::new(false, None);
+        let frames = observer.listen();
+        let source = FrameSource::Partner(7);
+
+        let mut corrupt = FRAME;
+        corrupt[5] ^= 0x10;
+        assert!(observer.observe(source.clone(), frame(&corrupt)).is_none());
+        assert_eq!(observer.observe(source.clone(), frame(&FRAME)).unwrap().bytes, FRAME.to_vec());
+
+        let observed: Vec<_> = frames.try_iter().collect();
+        assert_eq!(observed.len(), 1);
+        assert_eq!(observed[0].source, source);
+        assert_eq!(observer.stats()[&source].accepted, 1);
+        assert_eq!(observer.stats()[&source].rejected, 1);
+    }
+
+    #[test]
+    fn counts_repaired_frames() {
+        let mut observer = FrameObserver::new(true, None);
+        let source = FrameSource::Local("avr:localhost:30002".to_string());
+
+        let mut one_wrong = FRAME;
+        one_wrong[6] ^= 0x04;
+        assert_eq!(observer.observe(source.clone(), frame(&one_wrong)).unwrap().bytes, FRAME.to_vec());
+
+        let mut two_wrong = one_wrong;
+        two_wrong[9] ^= 0x40;
+        assert!(observer.observe(source.clone(), frame(&two_wrong)).is_none());
+
+        let stats = &observer.stats()[&source];
+        assert_eq!( (stats.accepted, stats.corrected, stats.rejected), (1, 1, 1) );
+    }
+
+    #[test]
+    fn drops_our_own_output_coming_back_in() {
+        let mut observer = FrameObserver::new(false, None);
+        observer.set_echo_guard(EchoGuard::new(false));
+        let frames = observer.listen();
+        let local = FrameSource::Local("beast:localhost:30005".to_string());
+
+        assert!(observer.observe(FrameSource::Partner(7), frame(&FRAME)).is_some());
+        let mut decoder = BeastDecoder::new();
+        decoder.push(&encode_frame(&frames.try_recv().unwrap()).unwrap());
+        assert!(observer.observe(local.clone(), decoder.next_frame().unwrap()).is_none());
+        assert_eq!(observer.stats()[&local].echoed, 1);
+
+        // The same bytes heard by one of our own receivers are not an echo.
+        assert!(observer.observe(local.clone(), frame(&FRAME)).is_some());
+        assert_eq!(observer.stats()[&local].echoed, 1);
+    }
+}
@@ -0,0 +1,318 @@
+use crate::node::addressable;
+use crate::node::Addressable;
+use std::collections::HashSet;
+
+/// Each entry starts with the port as this many hex digits
+const PORT_DIGITS: usize = 4;
+
+/// The longest a DNS name can be
+const MAX_HOST_LEN: usize = 253;
+
+fn is_upper_hex_digit(b: u8) -> bool {
+    b.is_ascii_digit() || (b'A'..=b'F').contains(&b)
+}
+
+/// Hostnames, IPv4 addresses, and IPv6 addresses are all made of these
+fn is_host_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b':'
+}
+
+/// Reads one entry, without its terminating 0x00, as `[Port: 4 upper case hex digits] [IP Address or hostname]`.
+/// Entries that do not follow the format exactly are rejected.
+pub fn parse_entry(entry: &[u8]) -> Option<Addressable> {
+    if entry.len() <= PORT_DIGITS || entry.len() > PORT_DIGITS + MAX_HOST_LEN {
+        return None;
+    }
+
+    let (port, host) = entry.split_at(PORT_DIGITS);
+    if !port.iter().all(|&b| is_upper_hex_digit(b)) || !host.iter().all(|&b| is_host_byte(b)) {
+        return None;
+    }
+    if host.starts_with(b"-") || host.starts_with(b".") {
+        return None;
+    }
+
+    let port = u16::from_str_radix(std::str::from_utf8(port).ok()?, 16).ok()?;
+    if port == 0 {
+        return None;
+    }
+    Some(addressable(std::str::from_utf8(host).ok()?, port))
+}
+
+/// Reads every complete, valid entry of a whole partner list string. The first is the node that sent it.
+/// `PartnerListAssembler` is what reads lists off the network, since it copes with them changing.
+#[allow(dead_code)]
+pub fn parse_entries(partner_list: &[u8]) -> Vec<Addressable> {
+    let mut entries: Vec<&[u8]> = partner_list.split(|&b| b == 0).collect();
+    // Whatever follows the last 0x00 is either empty or an unterminated entry.
+    entries.pop();
+    entries.into_iter().filter_map(parse_entry).collect()
+}
+
+/// Puts a partner list together from `Partner List Response`s. The string may change between responses,
+/// so each response is read on its own: only entries that start and end within one response are kept,
+/// and each request starts at the last entry boundary of the previous response, overlapping it.
+pub struct PartnerListAssembler {
+    chunk_len: usize,
+    max_len: usize,
+    next_start: u32,
+    done: bool,
+    entries: Vec<Addressable>,
+    seen: HashSet<Addressable>,
+}
+
+impl PartnerListAssembler {
+    /// Requests will be for `chunk_len` bytes, and stop once `max_len` bytes of the string have been covered.
+    pub fn new(chunk_len: usize, max_len: usize) -> PartnerListAssembler {
+        PartnerListAssembler {
+            chunk_len: chunk_len.max(1),
+            max_len: max_len,
+            next_start: 0,
+            done: false,
+            entries: Vec::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// The start index and length of the next chunk to request, or `None` once the list is complete.
+    pub fn next_request(&self) -> Option<(u32, usize)> {
+        if self.done || self.next_start as usize >= self.max_len {
+            None
+        } else {
+            Some( (self.next_start, self.chunk_len) )
+        }
+    }
+
+    /// Takes in the response to the request for the chunk starting at `start`.
+    pub fn add_response(&mut self, start: u32, response: &[u8]) {
+        if self.done || start != self.next_start {
+            return;
+        }
+
+        let response = &response[..response.len().min(self.chunk_len)];
+        let mut pieces = response.split(|&b| b == 0);
+
+        // Unless the response starts the string, the first piece may be the tail of an entry cut in half.
+        if start != 0 {
+            pieces.next();
+        }
+        let mut pieces: Vec<&[u8]> = pieces.collect();
+        // The last piece was not terminated within this response.
+        pieces.pop();
+
+        for entry in pieces.into_iter().filter_map(parse_entry) {
+            if self.seen.insert(entry.clone()) {
+                self.entries.push(entry);
+            }
+        }
+
+        if response.len() < self.chunk_len {
+            // The string ended within this response.
+            self.done = true;
+            return;
+        }
+
+        // Continue from the 0x00 before the entry that was cut off, so that it arrives whole next time. If
+        // there was none to continue from, no entry of this response could be used, so move past it.
+        self.next_start = match response.iter().rposition(|&b| b == 0) {
+            Some(last_separator) if last_separator > 0 => start + last_separator as u32,
+            _ => start + response.len() as u32,
+        };
+    }
+
+    /// Gives up on the rest of the list, such as after a request goes unanswered.
+    pub fn abandon(&mut self) {
+        self.done = true;
+    }
+
+    /// The valid entries gathered so far, without duplicates, in the order they were found
+    pub fn into_entries(self) -> Vec<Addressable> {
+        self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_entries;
+    use super::parse_entry;
+    use super::PartnerListAssembler;
+    use crate::node::split_addressable;
+    use crate::node::Addressable;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    /// Runs an assembler to completion against `serve`, which answers a request for `len` bytes from
+    /// `start`. Panics if the assembler never finishes.
+    fn assemble<F: FnMut(u32, usize) -> Vec<u8>>(chunk_len: usize, max_len: usize, mut serve: F) -> Vec<Addressable> {
+        let mut assembler = PartnerListAssembler::new(chunk_len, max_len);
+        let mut requests = 0;
+        while let Some((start, len)) = assembler.next_request() {
+            requests += 1;
+            assert!(requests <= max_len + 1, "the assembler never finished");
+            let response = serve(start, len);
+            assembler.add_response(start, &response);
+        }
+        assembler.into_entries()
+    }
+
+    fn slice(list: &[u8], start: u32, len: usize) -> Vec<u8> {
+        let start = (start as usize).min(list.len());
+        list[start..(start + len).min(list.len())].to_vec()
+    }
+
+    fn list(entries: &[&str]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for entry in entries {
+            list.extend_from_slice(entry.as_bytes());
+            list.push(0);
+        }
+        list
+    }
+
+    #[test]
+    fn parses_entries() {
+        assert_eq!(parse_entry(b"0FC8mynode.net"), Some("mynode.net:4040".to_string()));
+        assert_eq!(parse_entry(b"0FC8::1"), Some("[::1]:4040".to_string()));
+        assert_eq!(parse_entries(&list(&["0FC8a", "0001b"])), vec!["a:4040".to_string(), "b:1".to_string()]);
+    }
+
+    #[test]
+    fn rejects_bad_hex() {
+        assert_eq!(parse_entry(b"0fc8mynode.net"), None);
+        assert_eq!(parse_entry(b"0FCGmynode.net"), None);
+        assert_eq!(parse_entry(b"+FC8mynode.net"), None);
+        assert_eq!(parse_entry(b"0000mynode.net"), None);
+        assert_eq!(parse_entry(b"0FCmynode.net"), None);
+    }
+
+    #[test]
+    fn rejects_bad_hosts() {
+        assert_eq!(parse_entry(b"0FC8"), None);
+        assert_eq!(parse_entry(b"0FC8my node"), None);
+        assert_eq!(parse_entry(b"0FC8my/node"), None);
+        assert_eq!(parse_entry(b"0FC8-node"), None);
+        assert_eq!(parse_entry(b"0FC8.node"), None);
+        assert_eq!(parse_entry("0FC8nöde".as_bytes()), None);
+        assert_eq!(parse_entry(&[b'0', b'F', b'C', b'8', b'a', 0xFF]), None);
+        let long_host = format!("0FC8{}", "a".repeat(254));
+        assert_eq!(parse_entry(long_host.as_bytes()), None);
+    }
+
+    #[test]
+    fn reads_a_list_across_many_chunks() {
+        let entries: Vec<String> = (1..=50).map(|i| format!("{:04X}node{}.example", i, i)).collect();
+        let entry_refs: Vec<&str> = entries.iter().map(|entry| entry.as_str()).collect();
+        let whole = list(&entry_refs);
+
+        for &chunk_len in &[32, 33, 64, 100, 1024] {
+            let assembled = assemble(chunk_len, 64 * 1024, |start, len| slice(&whole, start, len));
+            assert_eq!(assembled, parse_entries(&whole), "chunk length {}", chunk_len);
+        }
+    }
+
+    #[test]
+    fn the_empty_string_has_no_entries() {
+        assert!(assemble(64, 1024, |_, _| Vec::new()).is_empty());
+        assert!(parse_entries(b"").is_empty());
+    }
+
+    #[test]
+    fn tolerates_entries_shifting_between_chunks() {
+        // Each response comes from a different version of the list: an entry is removed from near the
+        // front before every response, so everything after it moves back.
+        let versions: Vec<Vec<u8>> = (0..40).map(|removed| {
+            let entries: Vec<String> = (0..40)
+                .filter(|&i| i >= removed)
+                .map(|i| format!("{:04X}host{}", i + 1, i))
+                .collect();
+            let entry_refs: Vec<&str> = entries.iter().map(|entry| entry.as_str()).collect();
+            list(&entry_refs)
+        }).collect();
+        let everything = parse_entries(&versions[0]);
+
+        let mut version = 0;
+        let assembled = assemble(40, 64 * 1024, |start, len| {
+            let response = slice(&versions[version.min(versions.len() - 1)], start, len);
+            version += 1;
+            response
+        });
+
+        // Whatever was read must be real, whole entries, with no duplicates.
+        assert!(!assembled.is_empty());
+        for entry in &assembled {
+            assert!(everything.contains(entry), "{} was never in the list", entry);
+        }
+        let mut unique = assembled.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), assembled.len());
+    }
+
+    #[test]
+    fn skips_an_entry_longer_than_a_chunk() {
+        let whole = list(&["0FC8a", "0FC8averyveryverylonghostname", "0FC8b"]);
+        let assembled = assemble(12, 1024, |start, len| slice(&whole, start, len));
+        assert_eq!(assembled, vec!["a:4040".to_string(), "b:4040".to_string()]);
+    }
+
+    #[test]
+    fn ignores_bytes_beyond_what_was_asked_for() {
+        let mut assembler = PartnerListAssembler::new(10, 1024);
+        let mut response = list(&["0FC8a", "0FC8b"]);
+        response.extend(list(&["0FC8c", "0FC8d"]));
+        assembler.add_response(0, &response);
+
+        // Only the first 10 bytes count, and they hold "a" whole and "b" cut off.
+        assert_eq!(assembler.next_request(), Some( (5, 10) ));
+        assert_eq!(assembler.into_entries(), vec!["a:4040".to_string()]);
+    }
+
+    #[test]
+    fn ignores_responses_for_other_starts() {
+        let mut assembler = PartnerListAssembler::new(10, 1024);
+        assembler.add_response(5, &list(&["0FC8x"]));
+        assert_eq!(assembler.next_request(), Some( (0, 10) ));
+
+        assembler.add_response(0, &list(&["0FC8a", "0FC8b"]));
+        assembler.add_response(0, &list(&["0FC8y"]));
+        assembler.add_response(11, &list(&["0FC8z"]));
+        assert_eq!(assembler.into_entries(), vec!["a:4040".to_string()]);
+    }
+
+    #[test]
+    fn abandoning_keeps_what_was_read() {
+        let mut assembler = PartnerListAssembler::new(10, 1024);
+        assembler.add_response(0, &list(&["0FC8a", "0FC8b"]));
+        assembler.abandon();
+        assert_eq!(assembler.next_request(), None);
+        assert_eq!(assembler.into_entries(), vec!["a:4040".to_string()]);
+    }
+
+    #[test]
+    fn stops_at_the_size_cap() {
+        // A node that never stops answering with full chunks
+        let assembled = assemble(16, 256, |_, len| b"0FC8a\0".iter().cycle().take(len).cloned().collect());
+        assert_eq!(assembled, vec!["a:4040".to_string()]);
+    }
+
+    #[test]
+    fn survives_adversarial_responses() {
+        let mut rng = StdRng::seed_from_u64(23);
+        let alphabet = b"0123456789ABCDEFabcdef.-:\0\0\0\xFF ";
+        for _ in 0..2000 {
+            let chunk_len = rng.gen_range(1, 64);
+            assemble(chunk_len, 2048, |_, len| {
+                let response_len = match rng.gen_range(0, 4) {
+                    0 => 0,
+                    1 => len,
+                    2 => rng.gen_range(0, len + 1),
+                    _ => len + rng.gen_range(1, 16),
+                };
+                (0..response_len).map(|_| alphabet[rng.gen_range(0, alphabet.len())]).collect()
+            }).iter().for_each(|entry| {
+                assert!(split_addressable(entry).is_some(), "{} is not an address", entry);
+            });
+        }
+    }
+}
@@ -19,9 +19,26 @@ pub fn peel_slice(xs: &[u8], len: usize) -> Result<(&[u8], &[u8]), HandleError>
 }
 
 pub fn peel_end(xs: &[u8]) -> Result<(), HandleError> {
-    if xs.len() != 0 {
+    if !xs.is_empty() {
         Err(HandleError::PacketContinuedUnexpectedly)
     } else {
         Ok( () )
     }
-}
\ No newline at end of file
+}
+
+pub fn peel_u8(xs: &[u8]) -> Result<(u8, &[u8]), HandleError> {
+    if xs.is_empty() {
+        return Err(HandleError::PacketTruncated);
+    }
+    
+    Ok( (xs[0], &xs[1..]) )
+}
+
+pub fn peel_u16(xs: &[u8]) -> Result<(u16, &[u8]), HandleError> {
+    if xs.len() < 2 {
+        return Err(HandleError::PacketTruncated);
+    }
+    
+    let (first_2, rest) = xs.split_at(2);
+    Ok( (u16::from_le_bytes( [ first_2[0], first_2[1] ] ), rest) )
+}
@@ -0,0 +1,33 @@
+use crate::node::HandleError;
+use crate::node::Node;
+use crate::transport::Transport;
+use std::io;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Large enough for any UDP datagram
+const MAX_DATAGRAM_LEN: usize = 65536;
+
+/// Reads datagrams from `transport` forever, passing each to the `Node`. Only returns if the transport
+/// itself fails; a malformed packet from one sender must not stop us from hearing everyone else.
+pub fn receive(node: Arc<Mutex<Node>>, transport: Arc<dyn Transport>) -> io::Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+    loop {
+        let (len, source) = match transport.receive(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted || e.kind() == ErrorKind::ConnectionReset => {
+                // ConnectionReset is how some platforms report an ICMP "port unreachable" from an earlier
+                // send. That is about some other host, not our socket.
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        match node.lock().unwrap().handle_received_packet(&source, &buf[..len]) {
+            Ok( () ) => {}
+            Err(HandleError::SendFailed(e)) => eprintln!("Failed to reply to {}: {}", source, e),
+            Err(e) => eprintln!("Failed to handle packet from {}: {:?}", source, e),
+        }
+    }
+}
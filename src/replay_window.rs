@@ -0,0 +1,207 @@
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReplayVerdict {
+    /// Not seen before; the packet should be processed
+    New,
+
+    /// Seen before within the window
+    Replay,
+
+    /// Too far behind the highest sequence number to tell
+    TooOld,
+}
+
+/// Remembers which of the most recent sequence numbers from one partner have been seen, as a bitmap
+/// reaching back `size` numbers from the highest seen. Packets reordered within the window are accepted
+/// once each.
+///
+/// Packets from before the window are always dropped, however many arrive: old packets are exactly what
+/// a replay is made of, so they cannot be trusted to say that the partner has started over. A partner
+/// that really does go back is the sequence monitor's business.
+pub struct ReplayWindow {
+    size: u32,
+    highest: Option<u32>,
+
+    /// Bit `s % capacity` is set if sequence number `s`, within the window, has been seen. The capacity
+    /// is a power of two, so that it divides the 2^32 sequence numbers and the bits stay in place when
+    /// the sequence number wraps.
+    seen: Vec<u64>,
+    capacity: u32,
+}
+
+impl ReplayWindow {
+    pub fn new(size: u32) -> ReplayWindow {
+        let size = size.clamp(1, 1 << 31);
+        let capacity = size.next_power_of_two().max(64);
+        ReplayWindow {
+            size: size,
+            highest: None,
+            seen: vec![0; (capacity / 64) as usize],
+            capacity: capacity,
+        }
+    }
+
+    fn bit(&self, sequence_number: u32) -> (usize, u64) {
+        let index = sequence_number & (self.capacity - 1);
+        ((index / 64) as usize, 1 << (index % 64))
+    }
+
+    fn mark(&mut self, sequence_number: u32) {
+        let (word, mask) = self.bit(sequence_number);
+        self.seen[word] |= mask;
+    }
+
+    fn clear(&mut self, sequence_number: u32) {
+        let (word, mask) = self.bit(sequence_number);
+        self.seen[word] &= !mask;
+    }
+
+    fn restart_at(&mut self, sequence_number: u32) {
+        for word in self.seen.iter_mut() {
+            *word = 0;
+        }
+        self.highest = Some(sequence_number);
+        self.mark(sequence_number);
+    }
+
+    /// Checks a sequence number, recording it if it is new.
+    pub fn check(&mut self, sequence_number: u32) -> ReplayVerdict {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => {
+                self.restart_at(sequence_number);
+                return ReplayVerdict::New;
+            }
+        };
+
+        // Sequence numbers wrap, so "ahead" means ahead by less than half the space.
+        let ahead = sequence_number.wrapping_sub(highest) as i32;
+        if ahead > 0 {
+            if ahead as u32 >= self.size {
+                self.restart_at(sequence_number);
+            } else {
+                // The bits being reused belonged to numbers at least `capacity` behind, which have left
+                // the window.
+                for skipped in 1..=ahead as u32 {
+                    self.clear(highest.wrapping_add(skipped));
+                }
+                self.highest = Some(sequence_number);
+                self.mark(sequence_number);
+            }
+            return ReplayVerdict::New;
+        }
+
+        let behind = highest.wrapping_sub(sequence_number);
+        if behind >= self.size {
+            return ReplayVerdict::TooOld;
+        }
+
+        let (word, mask) = self.bit(sequence_number);
+        if self.seen[word] & mask != 0 {
+            return ReplayVerdict::Replay;
+        }
+        self.seen[word] |= mask;
+        ReplayVerdict::New
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayVerdict::*;
+    use super::ReplayWindow;
+
+    #[test]
+    fn accepts_reordering_within_the_window_once() {
+        let mut window = ReplayWindow::new(16);
+        assert_eq!(window.check(100), New);
+        assert_eq!(window.check(103), New);
+        assert_eq!(window.check(101), New);
+        assert_eq!(window.check(102), New);
+        assert_eq!(window.check(101), Replay);
+        assert_eq!(window.check(103), Replay);
+    }
+
+    #[test]
+    fn drops_exact_duplicates() {
+        let mut window = ReplayWindow::new(16);
+        for sequence_number in 0..40 {
+            assert_eq!(window.check(sequence_number), New);
+            assert_eq!(window.check(sequence_number), Replay);
+        }
+    }
+
+    #[test]
+    fn drops_packets_behind_the_window() {
+        let mut window = ReplayWindow::new(16);
+        assert_eq!(window.check(100), New);
+        assert_eq!(window.check(85), New);
+        assert_eq!(window.check(84), TooOld);
+    }
+
+    #[test]
+    fn forgets_the_window_after_a_forward_jump() {
+        let mut window = ReplayWindow::new(16);
+        assert_eq!(window.check(100), New);
+        assert_eq!(window.check(99), New);
+        assert_eq!(window.check(200), New);
+        assert_eq!(window.check(185), New);
+        assert_eq!(window.check(184), TooOld);
+        assert_eq!(window.check(99), TooOld);
+        assert_eq!(window.check(1_000_000), New);
+        assert_eq!(window.check(200), TooOld);
+        assert_eq!(window.check(1_000_000), Replay);
+    }
+
+    #[test]
+    fn reused_bits_do_not_hide_new_packets() {
+        let mut window = ReplayWindow::new(16);
+        for sequence_number in (0..200).step_by(3) {
+            assert_eq!(window.check(sequence_number), New);
+        }
+        for sequence_number in 190..200 {
+            let expected = if sequence_number % 3 == 0 { Replay } else { New };
+            assert_eq!(window.check(sequence_number), expected, "{}", sequence_number);
+        }
+    }
+
+    #[test]
+    fn survives_wraparound_with_any_size() {
+        for &size in &[1, 7, 16, 100, 1000] {
+            let mut window = ReplayWindow::new(size);
+            let start = u32::MAX - size / 2;
+            for offset in 0..size * 2 {
+                let sequence_number = start.wrapping_add(offset);
+                assert_eq!(window.check(sequence_number), New, "size {} at {}", size, sequence_number);
+                let reordered = sequence_number.wrapping_sub(size - 1);
+                if offset >= size - 1 {
+                    assert_eq!(window.check(reordered), Replay, "size {} at {}", size, reordered);
+                }
+            }
+        }
+
+        // The example of 47 and 2^32 - 49 sharing a bit of a 100 number bitmap
+        let mut window = ReplayWindow::new(100);
+        assert_eq!(window.check(50), New);
+        assert_eq!(window.check(u32::MAX - 48), New);
+        assert_eq!(window.check(47), New);
+        assert_eq!(window.check(u32::MAX - 48), Replay);
+        assert_eq!(window.check(47), Replay);
+    }
+
+    #[test]
+    fn old_packets_cannot_reseat_the_window() {
+        let mut window = ReplayWindow::new(16);
+        for sequence_number in 1000..1010 {
+            assert_eq!(window.check(sequence_number), New);
+        }
+
+        // A captured run of old packets, replayed in order, never becomes acceptable.
+        for sequence_number in 500..600 {
+            assert_eq!(window.check(sequence_number), TooOld);
+        }
+        for sequence_number in 500..600 {
+            assert_eq!(window.check(sequence_number), TooOld);
+        }
+        assert_eq!(window.check(1010), New);
+        assert_eq!(window.check(1005), Replay);
+    }
+}
@@ -0,0 +1,54 @@
+use crate::transport::Transport;
+use std::io;
+use std::io::ErrorKind;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::sync::mpsc::sync_channel;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TrySendError;
+use std::thread;
+
+/// Sends waiting for a name lookup beyond this many are refused, so that a flood of packets naming
+/// hosts we cannot resolve quickly does not pile up
+const MAX_WAITING_SENDS: usize = 64;
+
+/// Sends packets to hostnames, looking each name up on a single worker thread. Name lookups block, and
+/// can take as long as the name's DNS servers like, so they cannot happen on the thread handling packets.
+pub struct Resolver {
+    sends: SyncSender<(String, Vec<u8>)>,
+}
+
+impl Resolver {
+    /// The worker thread runs until the `Resolver` is dropped.
+    pub fn new(transport: Arc<dyn Transport>) -> Resolver {
+        let (sends, waiting) = sync_channel::<(String, Vec<u8>)>(MAX_WAITING_SENDS);
+        thread::spawn(move || {
+            for (who, packet) in waiting {
+                match who.to_socket_addrs().ok().and_then(|mut socket_addrs| socket_addrs.next()) {
+                    Some(socket_addr) => {
+                        if let Err(e) = transport.send(&socket_addr, &packet) {
+                            eprintln!("Failed to send to {}: {}", who, e);
+                        }
+                    }
+                    None => {
+                        eprintln!("Failed to resolve {}", who);
+                    }
+                }
+            }
+        });
+
+        Resolver {
+            sends: sends,
+        }
+    }
+
+    /// Queues `packet` to be sent to `who` once its name is looked up. Fails without blocking if too
+    /// many sends are already waiting; failures after that can only be logged.
+    pub fn send(&self, who: &str, packet: Vec<u8>) -> io::Result<()> {
+        match self.sends.try_send( (who.to_string(), packet) ) {
+            Ok( () ) => Ok( () ),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(ErrorKind::WouldBlock, "too many name lookups waiting")),
+            Err(TrySendError::Disconnected(_)) => Err(io::Error::new(ErrorKind::BrokenPipe, "name lookup worker stopped")),
+        }
+    }
+}
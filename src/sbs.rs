@@ -0,0 +1,347 @@
+use crate::adsb;
+use crate::adsb::SquitterContent;
+use crate::fanout::Fanout;
+use crate::mode_s::announced_address;
+use crate::mode_s::downlink_format;
+use crate::mode_s::residual;
+use crate::observer::FrameSource;
+use crate::observer::ObservedFrame;
+use std::fmt::Write as FmtWrite;
+use std::net::TcpListener;
+use std::sync::mpsc::Receiver;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Which frames are passed on to SBS clients
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SbsOrigins {
+    All,
+
+    /// Only frames heard by our own receivers
+    LocalOnly,
+
+    /// Only frames relayed by partners
+    PartnersOnly,
+}
+
+impl SbsOrigins {
+    fn includes(self, source: &FrameSource) -> bool {
+        matches!((self, source),
+            (SbsOrigins::All, _) |
+            (SbsOrigins::LocalOnly, &FrameSource::Local(_)) |
+            (SbsOrigins::PartnersOnly, &FrameSource::Partner(_)))
+    }
+}
+
+/// The fields of one BaseStation line that vary with the message. Empty fields are `None`.
+#[derive(Default)]
+struct SbsLine {
+    transmission_type: u8,
+    callsign: Option<String>,
+    altitude: Option<i32>,
+    ground_speed: Option<f64>,
+    track: Option<f64>,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    vertical_rate: Option<i32>,
+    squawk: Option<u16>,
+    alert: Option<bool>,
+    emergency: Option<bool>,
+    spi: Option<bool>,
+    on_ground: Option<bool>,
+}
+
+fn flag(value: Option<bool>) -> &'static str {
+    match value {
+        Some(true) => "-1",
+        Some(false) => "0",
+        None => "",
+    }
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// The UTC date and time as BaseStation writes them, such as `2019/03/07` and `14:02:31.425`
+fn date_and_time(time: SystemTime) -> (String, String) {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let days = (seconds / 86400) as i64;
+    let seconds_of_day = seconds % 86400;
+
+    // Converts days since 1970-01-01 to a Gregorian date, counting in 400 year eras from 0000-03-01.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (
+        format!("{:04}/{:02}/{:02}", year, month, day),
+        format!("{:02}:{:02}:{:02}.{:03}", seconds_of_day / 3600, seconds_of_day / 60 % 60, seconds_of_day % 60, since_epoch.subsec_millis()),
+    )
+}
+
+/// Reads the flight status field of DF4, 5, 20, and 21 into the alert, SPI, and on-ground flags.
+fn flight_status(frame: &[u8], line: &mut SbsLine) {
+    let status = frame[0] & 7;
+    line.alert = Some((2..=4).contains(&status));
+    line.spi = Some(status == 4 || status == 5);
+    line.on_ground = match status {
+        0 | 2 => Some(false),
+        1 | 3 => Some(true),
+        _ => None,
+    };
+}
+
+fn ac13(frame: &[u8]) -> Option<i32> {
+    adsb::decode_ac13(((frame[2] as u32 & 0x1F) << 8) | frame[3] as u32)
+}
+
+fn id13(frame: &[u8]) -> u16 {
+    adsb::decode_id13(((frame[2] as u32 & 0x1F) << 8) | frame[3] as u32)
+}
+
+fn is_emergency_squawk(squawk: u16) -> bool {
+    squawk == 0x7500 || squawk == 0x7600 || squawk == 0x7700
+}
+
+fn squitter_line(frame: &[u8], observed: &ObservedFrame) -> Option<(u32, SbsLine)> {
+    let squitter = adsb::decode(frame)?;
+    let position = observed.position;
+    let line = match squitter.content {
+        SquitterContent::Identification(identification) => SbsLine {
+            transmission_type: 1,
+            callsign: Some(identification.callsign),
+            ..SbsLine::default()
+        },
+        SquitterContent::SurfacePosition(surface) => SbsLine {
+            transmission_type: 2,
+            ground_speed: surface.ground_speed,
+            track: surface.track,
+            lat: position.map(|position| position.lat),
+            lon: position.map(|position| position.lon),
+            on_ground: Some(true),
+            ..SbsLine::default()
+        },
+        SquitterContent::AirbornePosition(airborne) => SbsLine {
+            transmission_type: 3,
+            altitude: airborne.altitude,
+            lat: position.map(|position| position.lat),
+            lon: position.map(|position| position.lon),
+            alert: Some(airborne.surveillance_status == 1 || airborne.surveillance_status == 2),
+            emergency: Some(airborne.surveillance_status == 1),
+            spi: Some(airborne.surveillance_status == 3),
+            on_ground: Some(false),
+            ..SbsLine::default()
+        },
+        SquitterContent::AirborneVelocity(velocity) => SbsLine {
+            transmission_type: 4,
+            ground_speed: velocity.ground_speed,
+            track: velocity.track,
+            vertical_rate: velocity.vertical_rate,
+            ..SbsLine::default()
+        },
+        SquitterContent::AircraftStatus(status) => SbsLine {
+            transmission_type: 6,
+            squawk: status.squawk,
+            emergency: Some(status.emergency != 0),
+            ..SbsLine::default()
+        },
+        SquitterContent::OperationalStatus(_) => return None,
+    };
+    Some( (squitter.address, line) )
+}
+
+/// Formats a frame as a BaseStation `MSG` line, without the line ending. Frames BaseStation has no
+/// message for give `None`.
+pub fn format_message(observed: &ObservedFrame, time: SystemTime) -> Option<String> {
+    let frame = &observed.frame.bytes;
+    if frame.is_empty() {
+        return None;
+    }
+
+    let (address, line) = match downlink_format(frame) {
+        17 | 18 => squitter_line(frame, observed)?,
+        4 | 20 => {
+            let mut line = SbsLine {
+                transmission_type: 5,
+                altitude: ac13(frame),
+                ..SbsLine::default()
+            };
+            flight_status(frame, &mut line);
+            (residual(frame), line)
+        }
+        5 | 21 => {
+            let squawk = id13(frame);
+            let mut line = SbsLine {
+                transmission_type: 6,
+                squawk: Some(squawk),
+                emergency: Some(is_emergency_squawk(squawk)),
+                ..SbsLine::default()
+            };
+            flight_status(frame, &mut line);
+            (residual(frame), line)
+        }
+        0 | 16 => {
+            let line = SbsLine {
+                transmission_type: 7,
+                altitude: ac13(frame),
+                on_ground: Some(frame[0] & 0x04 != 0),
+                ..SbsLine::default()
+            };
+            (residual(frame), line)
+        }
+        11 => (announced_address(frame), SbsLine{transmission_type: 8, ..SbsLine::default()}),
+        _ => return None,
+    };
+
+    let (date, time) = date_and_time(time);
+    let mut text = String::new();
+    let _ = write!(text, "MSG,{},1,1,{:06X},1,{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+        line.transmission_type,
+        address,
+        date, time, date, time,
+        line.callsign.unwrap_or_default(),
+        optional(line.altitude),
+        optional(line.ground_speed.map(|speed| format!("{:.0}", speed))),
+        optional(line.track.map(|track| format!("{:.0}", track))),
+        optional(line.lat.map(|lat| format!("{:.5}", lat))),
+        optional(line.lon.map(|lon| format!("{:.5}", lon))),
+        optional(line.vertical_rate),
+        optional(line.squawk.map(|squawk| format!("{:04x}", squawk))),
+        flag(line.alert),
+        flag(line.emergency),
+        flag(line.spi),
+        flag(line.on_ground));
+    Some(text)
+}
+
+/// Sends every frame from `frames`, as from `FrameObserver::listen`, to each client connected to
+/// `listener` as BaseStation lines, as dump1090 does on port 30003. Returns when the node goes away.
+pub fn serve(listener: TcpListener, frames: Receiver<ObservedFrame>, origins: SbsOrigins) {
+    let clients = Fanout::new(listener, "SBS");
+
+    for observed in frames {
+        if !origins.includes(&observed.source) {
+            continue;
+        }
+
+        if let Some(mut line) = format_message(&observed, SystemTime::now()) {
+            line.push_str("\r\n");
+            clients.send(line.into_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_message;
+    use crate::cpr::Position;
+    use crate::ingest::ModeSFrame;
+    use crate::mode_s::crc24;
+    use crate::observer::FrameSource;
+    use crate::observer::ObservedFrame;
+    use std::time::Duration;
+    use std::time::Instant;
+    use std::time::SystemTime;
+    use std::time::UNIX_EPOCH;
+
+    fn hex(text: &str) -> Vec<u8> {
+        (0..text.len()).step_by(2).map(|i| u8::from_str_radix(&text[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn observed(bytes: Vec<u8>, position: Option<Position>) -> ObservedFrame {
+        ObservedFrame {
+            source: FrameSource::Partner(7),
+            frame: ModeSFrame {
+                bytes: bytes,
+                mlat_timestamp: None,
+                signal_level: None,
+                received_at: SystemTime::now(),
+                receiver_location: None,
+            },
+            position: position,
+            received_at: Instant::now(),
+        }
+    }
+
+    /// A short reply from 0x4840D6, its parity overlaid with the address
+    fn reply(first_byte: u8, field: u32) -> Vec<u8> {
+        let mut frame = vec![first_byte, (field >> 16) as u8, (field >> 8) as u8, field as u8];
+        let parity = crc24(&frame) ^ 0x4840D6;
+        frame.extend_from_slice(&parity.to_be_bytes()[1..]);
+        frame
+    }
+
+    /// Formats as of 2019/03/07 14:02:31.425 UTC.
+    fn line(observed: &ObservedFrame) -> String {
+        format_message(observed, UNIX_EPOCH + Duration::from_millis(1551967351425)).unwrap()
+    }
+
+    const STAMP: &str = "2019/03/07,14:02:31.425,2019/03/07,14:02:31.425";
+
+    #[test]
+    fn writes_identification_as_msg_1() {
+        assert_eq!(
+            line(&observed(hex("8D4840D6202CC371C32CE0576098"), None)),
+            format!("MSG,1,1,1,4840D6,1,{},KLM1023,,,,,,,,,,,", STAMP)
+        );
+    }
+
+    #[test]
+    fn writes_airborne_positions_as_msg_3() {
+        let frame = hex("8D40621D58C382D690C8AC2863A7");
+        assert_eq!(
+            line(&observed(frame.clone(), Some(Position { lat: 52.257202, lon: 3.919373 }))),
+            format!("MSG,3,1,1,40621D,1,{},,38000,,,52.25720,3.91937,,,0,0,0,0", STAMP)
+        );
+        // Until a position is resolved, only the altitude is known.
+        assert_eq!(line(&observed(frame, None)), format!("MSG,3,1,1,40621D,1,{},,38000,,,,,,,0,0,0,0", STAMP));
+    }
+
+    #[test]
+    fn writes_velocities_as_msg_4() {
+        assert_eq!(
+            line(&observed(hex("8D485020994409940838175B284F"), None)),
+            format!("MSG,4,1,1,485020,1,{},,,159,183,,,-832,,,,,", STAMP)
+        );
+    }
+
+    #[test]
+    fn writes_surveillance_altitude_replies_as_msg_5() {
+        // DF4, airborne with no alert, at 38000 feet. Without a squawk there is no saying whether it is an emergency.
+        assert_eq!(line(&observed(reply(0x20, 0x001838), None)), format!("MSG,5,1,1,4840D6,1,{},,38000,,,,,,,0,,0,0", STAMP));
+    }
+
+    #[test]
+    fn writes_squawks_as_msg_6() {
+        // DF5 on the ground, squawking 7700
+        assert_eq!(line(&observed(reply(0x29, 0x000AAA), None)), format!("MSG,6,1,1,4840D6,1,{},,,,,,,,7700,0,-1,0,-1", STAMP));
+
+        // An aircraft status squitter with an emergency
+        let mut frame = vec![0x8D, 0x48, 0x40, 0xD6, 0xE1, 0x2A, 0xAA, 0, 0, 0, 0];
+        let parity = crc24(&frame);
+        frame.extend_from_slice(&parity.to_be_bytes()[1..]);
+        assert_eq!(line(&observed(frame, None)), format!("MSG,6,1,1,4840D6,1,{},,,,,,,,7700,,-1,,", STAMP));
+    }
+
+    #[test]
+    fn every_line_has_22_fields() {
+        let frames = vec![
+            hex("8D4840D6202CC371C32CE0576098"),
+            hex("8D40621D58C382D690C8AC2863A7"),
+            hex("8D485020994409940838175B284F"),
+            reply(0x20, 0x001838),
+            reply(0x29, 0x000AAA),
+        ];
+        for frame in frames {
+            assert_eq!(line(&observed(frame, None)).split(',').count(), 22);
+        }
+    }
+}
@@ -4,11 +4,23 @@ use std::sync::Arc;
 use std::thread::sleep;
 use std::time::Duration;
 use crate::node::Addressable;
-use std::sync::mpsc::Receiver;
 use std::net::ToSocketAddrs;
 use crate::node::PendingPartnershipResolution;
 use crate::subscribe_finalize::SubscribeFinalize;
+use crate::message::Message;
 use std::time::Instant;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use crate::fetch::fetch_partner_list;
+use crate::fetch::FetchOptions;
+use rand::seq::SliceRandom;
+use rand::thread_rng;
+
+/// How often to look for new candidates even if some are still waiting
+const CRAWL_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The most candidates to hold on to at once
+const MAX_CANDIDATES: usize = 1024;
 
 
 fn needs_more_partners(node: &Mutex<Node>) -> bool {
@@ -16,7 +28,50 @@ fn needs_more_partners(node: &Mutex<Node>) -> bool {
     node.lock().unwrap().active_partnership_count() < WANTED_PARTNERS
 }
 
-fn get_partner_candidate(node: &Mutex<Node>) -> Option<Addressable> {
+/// Nodes we have heard of and might propose a partnership to, in the order we heard of them
+#[derive(Default)]
+struct CandidatePool {
+    queue: VecDeque<Addressable>,
+    queued: HashSet<Addressable>,
+}
+
+impl CandidatePool {
+    fn add(&mut self, who: Addressable) {
+        if self.queue.len() < MAX_CANDIDATES && self.queued.insert(who.clone()) {
+            self.queue.push_back(who);
+        }
+    }
+    
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+/// Adds the partners of one of our partners, or of a seed, to the pool.
+fn crawl(node: &Mutex<Node>, seeds: &[Addressable], pool: &mut CandidatePool) {
+    let mut targets = node.lock().unwrap().active_partner_addresses();
+    for seed in seeds {
+        // to_socket_addrs can block on network receive and so does not belong in the `Node`
+        if let Some(socket_addr) = seed.to_socket_addrs().ok().and_then(|mut socket_addrs| socket_addrs.next()) {
+            targets.push(socket_addr);
+        }
+    }
+    
+    if let Some(target) = targets.choose(&mut thread_rng()) {
+        for who in fetch_partner_list(node, target, &FetchOptions::default()) {
+            pool.add(who);
+        }
+    }
+}
+
+fn get_partner_candidate(node: &Mutex<Node>, pool: &mut CandidatePool) -> Option<Addressable> {
+    let now = Instant::now();
+    while let Some(who) = pool.queue.pop_front() {
+        pool.queued.remove(&who);
+        if !node.lock().unwrap().is_unsuitable_partner_candidate(&who, now) {
+            return Some(who);
+        }
+    }
     None
 }
 
@@ -26,8 +81,11 @@ fn request_partnership(node: &Mutex<Node>, who: Addressable) -> bool {
         const MAX_TRIES: u8 = 4;
         while try_number < MAX_TRIES {
             try_number += 1;
-            let (potential_id, message, result_receiver) = node.lock().unwrap().create_partnership_proposal(who.clone());
-            node.lock().unwrap().send(&socket_addr, &message);
+            let (potential_id, message, result_receiver) = node.lock().unwrap().create_partnership_proposal(who.clone(), socket_addr);
+            if node.lock().unwrap().send(&socket_addr, &message).is_err() {
+                node.lock().unwrap().remove_pending_partnership_proposal(potential_id, PendingPartnershipResolution::Timeout);
+                return false;
+            }
             
             // We allow 10 seconds to receive a accept or decline before declaring a timeout. The 10 seconds is not just
             // for network latency, but also to give the node some time to consider our request. It could (hypothetically)
@@ -37,14 +95,12 @@ fn request_partnership(node: &Mutex<Node>, who: Addressable) -> bool {
                 Ok(PendingPartnershipResolution::Accepted(confirmation_nonce)) => {
                     // The `Node` will have removed the partnership proposed and promoted it to a partnership.
                     
-                    let confirmation_message = SubscribeFinalize{
+                    let confirmation_message = Message::SubscribeFinalize(SubscribeFinalize{
                         partnering_id: potential_id,
                         confirmation_nonce: confirmation_nonce
-                    }.serialize();
+                    }).encode();
                     
-                    node.lock().unwrap().send(&socket_addr, &confirmation_message);
-                    
-                    return true;
+                    return node.lock().unwrap().send(&socket_addr, &confirmation_message).is_ok();
                 }
                 Ok(PendingPartnershipResolution::Declined{retry_delay_seconds}) => {
                     if retry_delay_seconds > 0 {
@@ -74,14 +130,28 @@ fn request_partnership(node: &Mutex<Node>, who: Addressable) -> bool {
     }
 }
 
-pub fn seek(node: Arc<Mutex<Node>>) {
+/// Keeps proposing partnerships until we have enough partners. Candidates come from `seeds` and from
+/// the partner lists of our partners and seeds.
+pub fn seek(node: Arc<Mutex<Node>>, seeds: Vec<Addressable>) {
+    let mut pool = CandidatePool::default();
+    for seed in &seeds {
+        pool.add(seed.clone());
+    }
+    let mut last_crawl: Option<Instant> = None;
+    
     loop {
         if needs_more_partners(&node) {
-            if let Some(addressable) = get_partner_candidate(&node) {
+            let crawl_due = last_crawl.map(|last_crawl| last_crawl.elapsed() >= CRAWL_INTERVAL).unwrap_or(true);
+            if pool.is_empty() || crawl_due {
+                crawl(&node, &seeds, &mut pool);
+                last_crawl = Some(Instant::now());
+            }
+            
+            if let Some(addressable) = get_partner_candidate(&node, &mut pool) {
                 request_partnership(&node, addressable);
             }
         }
         
         sleep(Duration::from_secs(30))
     }
-}
\ No newline at end of file
+}
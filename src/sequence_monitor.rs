@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// A window with fewer samples than this is not scored, so that a new partnership is not judged on
+/// its first few packets
+const MIN_SAMPLES: usize = 8;
+
+/// One `Data` packet's sequence number, and when it arrived
+#[derive(Clone, Copy, Debug)]
+pub struct SequenceSample {
+    /// For scorers that weigh timing; `RepeatAndAlternationScorer` does not
+    #[allow(dead_code)]
+    pub received_at: Instant,
+    pub sequence_number: u32,
+}
+
+/// Judges how suspicious a partner's recent sequence numbers are, from 0 (normal) up. A score at or
+/// over the monitor's threshold trips it.
+pub trait SequenceScorer: Send {
+    fn score(&self, samples: &VecDeque<SequenceSample>) -> f64;
+}
+
+/// Scores by how often sequence numbers repeat and how often they jump far away and then back, which
+/// is what two senders sharing one key look like. A single jump, as when a partner restarts, is normal.
+pub struct RepeatAndAlternationScorer {
+    /// A change in sequence number bigger than this, either way, is a jump
+    pub jump_threshold: u32,
+}
+
+impl SequenceScorer for RepeatAndAlternationScorer {
+    fn score(&self, samples: &VecDeque<SequenceSample>) -> f64 {
+        if samples.len() < 2 {
+            return 0.0;
+        }
+
+        let steps: Vec<i64> = samples.iter().zip(samples.iter().skip(1))
+            .map(|(earlier, later)| later.sequence_number.wrapping_sub(earlier.sequence_number) as i32 as i64)
+            .collect();
+        let is_jump = |step: i64| step.unsigned_abs() > self.jump_threshold as u64;
+
+        let repeats = steps.iter().filter(|&&step| step == 0).count();
+        let alternations = steps.iter().zip(steps.iter().skip(1))
+            .filter(|&(&first, &second)| is_jump(first) && is_jump(second) && first.signum() != second.signum())
+            .count();
+
+        // An alternation is two jumps, so it counts twice.
+        (repeats + 2 * alternations) as f64 / steps.len() as f64
+    }
+}
+
+/// What happens to a partnership whose sequence numbers look like its key has been compromised
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AnomalyAction {
+    /// Stop sending to it, but keep listening. A partnership that trips again while demoted is dropped.
+    Demote,
+
+    /// Forget the partnership, so that its packets are no longer accepted
+    Drop,
+}
+
+/// Keeps the most recent sequence numbers from each partnership and scores them
+pub struct SequenceMonitor {
+    window_len: usize,
+    threshold: f64,
+    scorer: Box<dyn SequenceScorer>,
+    windows: HashMap<u32, VecDeque<SequenceSample>>,
+}
+
+impl SequenceMonitor {
+    pub fn new(window_len: usize, threshold: f64, scorer: Box<dyn SequenceScorer>) -> SequenceMonitor {
+        SequenceMonitor {
+            window_len: window_len,
+            threshold: threshold,
+            scorer: scorer,
+            windows: HashMap::new(),
+        }
+    }
+
+    /// Records a sequence number and returns the window's score if it tripped the monitor. The window
+    /// is cleared when it trips, so the partnership is judged afresh from then on.
+    pub fn record(&mut self, partnering_id: u32, sequence_number: u32, now: Instant) -> Option<f64> {
+        let window = self.windows.entry(partnering_id).or_default();
+        window.push_back(SequenceSample {
+            received_at: now,
+            sequence_number: sequence_number,
+        });
+        while window.len() > self.window_len {
+            window.pop_front();
+        }
+
+        if window.len() < MIN_SAMPLES.min(self.window_len) {
+            return None;
+        }
+
+        let score = self.scorer.score(window);
+        if score >= self.threshold {
+            window.clear();
+            Some(score)
+        } else {
+            None
+        }
+    }
+
+    pub fn forget(&mut self, partnering_id: u32) {
+        self.windows.remove(&partnering_id);
+    }
+}
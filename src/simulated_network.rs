@@ -0,0 +1,434 @@
+use crate::transport::Transport;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+/// How datagrams between two endpoints are mistreated
+#[derive(Clone, Debug)]
+pub struct LinkConditions {
+    pub drop_probability: f64,
+    pub duplicate_probability: f64,
+
+    /// A reordered datagram is held back by an extra `max_delay` so that ones sent after it overtake it
+    pub reorder_probability: f64,
+
+    /// Each datagram (and each duplicate) is delayed by a uniformly random duration in this range
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl LinkConditions {
+    pub fn perfect() -> LinkConditions {
+        LinkConditions {
+            drop_probability: 0.0,
+            duplicate_probability: 0.0,
+            reorder_probability: 0.0,
+            min_delay: Duration::from_secs(0),
+            max_delay: Duration::from_secs(0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SimulatedNetworkStats {
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub delivered: u64,
+    pub undeliverable: u64,
+}
+
+struct InFlight {
+    deliver_at: Instant,
+
+    /// Breaks ties between datagrams due at the same instant, so that they arrive in the order sent
+    order: u64,
+    source: SocketAddr,
+    destination: SocketAddr,
+    packet: Vec<u8>,
+}
+
+struct NetworkState {
+    default_conditions: LinkConditions,
+    link_conditions: HashMap<(SocketAddr, SocketAddr), LinkConditions>,
+    endpoints: HashSet<SocketAddr>,
+    in_flight: Vec<InFlight>,
+    next_order: u64,
+    stats: SimulatedNetworkStats,
+    rng: StdRng,
+}
+
+impl NetworkState {
+    fn random_delay(&mut self, conditions: &LinkConditions) -> Duration {
+        if conditions.max_delay <= conditions.min_delay {
+            return conditions.min_delay;
+        }
+        let spread = (conditions.max_delay - conditions.min_delay).as_nanos() as u64;
+        conditions.min_delay + Duration::from_nanos(self.rng.gen_range(0, spread + 1))
+    }
+
+    fn enqueue(&mut self, source: SocketAddr, destination: SocketAddr, packet: &[u8]) {
+        self.stats.sent += 1;
+
+        if !self.endpoints.contains(&destination) {
+            // Like UDP, sending to nobody is not an error for the sender.
+            self.stats.undeliverable += 1;
+            return;
+        }
+
+        let conditions = self.link_conditions.get(&(source, destination))
+            .unwrap_or(&self.default_conditions)
+            .clone();
+
+        if self.rng.gen::<f64>() < conditions.drop_probability {
+            self.stats.dropped += 1;
+            return;
+        }
+
+        let copies = if self.rng.gen::<f64>() < conditions.duplicate_probability {
+            self.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        let now = Instant::now();
+        for _ in 0..copies {
+            let mut delay = self.random_delay(&conditions);
+            if self.rng.gen::<f64>() < conditions.reorder_probability {
+                delay += conditions.max_delay;
+            }
+
+            let order = self.next_order;
+            self.next_order += 1;
+            self.in_flight.push(InFlight {
+                deliver_at: now + delay,
+                order: order,
+                source: source,
+                destination: destination,
+                packet: packet.to_vec(),
+            });
+        }
+    }
+
+    /// Finds the datagram `destination` should receive next, whether or not it is due yet
+    fn next_for(&self, destination: &SocketAddr) -> Option<usize> {
+        self.in_flight.iter()
+            .enumerate()
+            .filter(|&(_, in_flight)| in_flight.destination == *destination)
+            .min_by_key(|&(_, in_flight)| (in_flight.deliver_at, in_flight.order))
+            .map(|(index, _)| index)
+    }
+}
+
+struct Shared {
+    state: Mutex<NetworkState>,
+    arrival: Condvar,
+}
+
+/// An in-process stand-in for the internet, connecting any number of `SimulatedEndpoint`s. This allows
+/// many `Node`s to be run against each other in one process without touching real sockets.
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    shared: Arc<Shared>,
+}
+
+impl SimulatedNetwork {
+    /// The `seed` makes the drop/duplicate/delay decisions repeatable.
+    pub fn new(conditions: LinkConditions, seed: u64) -> SimulatedNetwork {
+        SimulatedNetwork {
+            shared: Arc::new(Shared {
+                state: Mutex::new(NetworkState {
+                    default_conditions: conditions,
+                    link_conditions: HashMap::new(),
+                    endpoints: HashSet::new(),
+                    in_flight: Vec::new(),
+                    next_order: 0,
+                    stats: SimulatedNetworkStats::default(),
+                    rng: StdRng::seed_from_u64(seed),
+                }),
+                arrival: Condvar::new(),
+            })
+        }
+    }
+
+    pub fn endpoint(&self, address: SocketAddr) -> io::Result<SimulatedEndpoint> {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.endpoints.insert(address) {
+            return Err(io::Error::new(ErrorKind::AddrInUse, format!("{} is already on the simulated network", address)));
+        }
+
+        Ok(SimulatedEndpoint {
+            address: address,
+            shared: self.shared.clone(),
+        })
+    }
+
+    /// Changes how datagrams are treated on links that do not have their own conditions
+    pub fn set_default_conditions(&self, conditions: LinkConditions) {
+        self.shared.state.lock().unwrap().default_conditions = conditions;
+    }
+
+    /// Changes how datagrams sent from `source` to `destination` are treated. The reverse direction is unaffected.
+    pub fn set_link_conditions(&self, source: SocketAddr, destination: SocketAddr, conditions: LinkConditions) {
+        self.shared.state.lock().unwrap().link_conditions.insert((source, destination), conditions);
+    }
+
+    pub fn stats(&self) -> SimulatedNetworkStats {
+        self.shared.state.lock().unwrap().stats.clone()
+    }
+
+    /// The number of datagrams that have been sent but not yet received
+    pub fn in_flight_count(&self) -> usize {
+        self.shared.state.lock().unwrap().in_flight.len()
+    }
+}
+
+/// One address on a `SimulatedNetwork`
+pub struct SimulatedEndpoint {
+    address: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+impl SimulatedEndpoint {
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Transport for SimulatedEndpoint {
+    fn send(&self, destination: &SocketAddr, packet: &[u8]) -> io::Result<()> {
+        self.shared.state.lock().unwrap().enqueue(self.address, *destination, packet);
+        self.shared.arrival.notify_all();
+        Ok( () )
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let wait = match state.next_for(&self.address) {
+                Some(index) if state.in_flight[index].deliver_at <= now => {
+                    let in_flight = state.in_flight.swap_remove(index);
+                    state.stats.delivered += 1;
+
+                    // Like UDP, a datagram too large for the buffer is truncated.
+                    let len = in_flight.packet.len().min(buf.len());
+                    buf[..len].copy_from_slice(&in_flight.packet[..len]);
+                    return Ok( (len, in_flight.source) );
+                }
+                Some(index) => Some(state.in_flight[index].deliver_at - now),
+                None => None,
+            };
+
+            state = match wait {
+                Some(wait) => self.shared.arrival.wait_timeout(state, wait).unwrap().0,
+                None => self.shared.arrival.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Drop for SimulatedEndpoint {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.endpoints.remove(&self.address);
+        let address = self.address;
+        state.in_flight.retain(|in_flight| in_flight.destination != address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LinkConditions;
+    use super::SimulatedNetwork;
+    use crate::message::Message;
+    use crate::node::Node;
+    use crate::node::PendingPartnershipResolution;
+    use crate::receive::receive;
+    use crate::subscribe_finalize::SubscribeFinalize;
+    use crate::transport::Transport;
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+    use std::thread::sleep;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    fn spawn_node(network: &SimulatedNetwork, address: SocketAddr) -> Arc<Mutex<Node>> {
+        let transport: Arc<dyn Transport> = Arc::new(network.endpoint(address).unwrap());
+        let node = Arc::new(Mutex::new(Node::new(address.ip().to_string(), address.port(), transport.clone())));
+        let thread_node = node.clone();
+        thread::spawn(move || receive(thread_node, transport));
+        node
+    }
+
+    /// Checks `condition` until it holds or `timeout` passes, and says whether it held
+    fn wait_until<F: FnMut() -> bool>(timeout: Duration, mut condition: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        while !condition() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    /// Makes `a` and `b` partners, repeating whatever the network loses. Returns the partnering ID.
+    fn pair(a: &Mutex<Node>, a_address: SocketAddr, b: &Mutex<Node>, b_address: SocketAddr) -> u32 {
+        // Subscribe until a Subscribe Accept makes it back. Proposals lost on the way are abandoned.
+        let mut accepted = None;
+        for _ in 0..50 {
+            let (partnering_id, subscribe, resolution) = a.lock().unwrap().create_partnership_proposal(b_address.to_string(), b_address);
+            a.lock().unwrap().send(&b_address, &subscribe).unwrap();
+            match resolution.recv_timeout(Duration::from_millis(100)) {
+                Ok(PendingPartnershipResolution::Accepted(confirmation_nonce)) => {
+                    accepted = Some( (partnering_id, confirmation_nonce) );
+                    break;
+                }
+                _ => {
+                    a.lock().unwrap().remove_pending_partnership_proposal(partnering_id, PendingPartnershipResolution::Timeout);
+                }
+            }
+        }
+        let (partnering_id, confirmation_nonce) = accepted.expect("no Subscribe Accept arrived");
+
+        // The Subscribe Finalize may be lost too, so it is repeated until it takes.
+        let finalize = Message::SubscribeFinalize(SubscribeFinalize{
+            partnering_id: partnering_id,
+            confirmation_nonce: confirmation_nonce,
+        }).encode();
+        let finalized = wait_until(Duration::from_secs(5), || {
+            if b.lock().unwrap().active_partner_addresses().contains(&a_address) {
+                return true;
+            }
+            a.lock().unwrap().send(&b_address, &finalize).unwrap();
+            sleep(Duration::from_millis(10));
+            false
+        });
+        assert!(finalized, "the Subscribe Finalize never took");
+        partnering_id
+    }
+
+    #[test]
+    fn nodes_pair_and_exchange_data_over_a_lossy_network() {
+        let network = SimulatedNetwork::new(LinkConditions {
+            drop_probability: 0.1,
+            duplicate_probability: 0.05,
+            reorder_probability: 0.05,
+            min_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(3),
+        }, 7);
+        let a_address: SocketAddr = "10.0.0.1:4040".parse().unwrap();
+        let b_address: SocketAddr = "10.0.0.2:4040".parse().unwrap();
+        let a = spawn_node(&network, a_address);
+        let b = spawn_node(&network, b_address);
+        let data = b.lock().unwrap().listen_for_data();
+
+        pair(&a, a_address, &b, b_address);
+        assert_eq!(a.lock().unwrap().active_partnership_count(), 1);
+        assert_eq!(b.lock().unwrap().active_partnership_count(), 1);
+
+        const SENT: usize = 200;
+        for _ in 0..SENT {
+            a.lock().unwrap().broadcast(&[]).unwrap();
+        }
+        let mut delivered: Vec<u32> = Vec::new();
+        let settled = wait_until(Duration::from_secs(5), || {
+            delivered.extend(data.try_iter().map(|data| data.sequence_number));
+            network.in_flight_count() == 0 && delivered.len() > SENT / 2
+        });
+        assert!(settled, "only {} of {} arrived", delivered.len(), SENT);
+
+        let unique: HashSet<u32> = delivered.iter().cloned().collect();
+        assert_eq!(unique.len(), delivered.len(), "a duplicated packet was delivered twice");
+        assert!(delivered.len() <= SENT);
+
+        let stats = network.stats();
+        assert!(stats.dropped > 0);
+        assert!(stats.duplicated > 0);
+        assert_eq!(b.lock().unwrap().active_partnership_count(), 1);
+    }
+
+    #[test]
+    fn a_ring_of_dozens_of_nodes_pairs_up_and_hears_from_every_partner() {
+        const NODES: usize = 30;
+        let network = SimulatedNetwork::new(LinkConditions {
+            drop_probability: 0.1,
+            duplicate_probability: 0.1,
+            reorder_probability: 0.05,
+            min_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(2),
+        }, 11);
+        let addresses: Vec<SocketAddr> = (0..NODES).map(|i| format!("10.0.1.{}:4040", i + 1).parse().unwrap()).collect();
+        let nodes: Vec<Arc<Mutex<Node>>> = addresses.iter().map(|&address| spawn_node(&network, address)).collect();
+        let data: Vec<_> = nodes.iter().map(|node| node.lock().unwrap().listen_for_data()).collect();
+
+        // Each node proposes to the next, so every node ends up with two partners.
+        let mut expected: Vec<HashSet<u32>> = vec![HashSet::new(); NODES];
+        for i in 0..NODES {
+            let next = (i + 1) % NODES;
+            let partnering_id = pair(&nodes[i], addresses[i], &nodes[next], addresses[next]);
+            expected[i].insert(partnering_id);
+            expected[next].insert(partnering_id);
+        }
+        for node in &nodes {
+            assert_eq!(node.lock().unwrap().active_partnership_count(), 2);
+        }
+
+        // Keep-alives go out until every node has heard one from each of its partners.
+        let mut heard: Vec<HashSet<u32>> = vec![HashSet::new(); NODES];
+        let everyone_heard = wait_until(Duration::from_secs(10), || {
+            for node in &nodes {
+                node.lock().unwrap().broadcast(&[]).unwrap();
+            }
+            for (heard, data) in heard.iter_mut().zip(&data) {
+                heard.extend(data.try_iter().map(|data| data.partnering_id));
+            }
+            heard == expected
+        });
+        assert!(everyone_heard, "some partners were never heard from");
+
+        let stats = network.stats();
+        assert!(stats.dropped > 0);
+        assert!(stats.duplicated > 0);
+    }
+
+    #[test]
+    fn link_conditions_apply_in_one_direction_only() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let a = network.endpoint("10.0.0.1:4040".parse().unwrap()).unwrap();
+        let b = network.endpoint("10.0.0.2:4040".parse().unwrap()).unwrap();
+        let cut = LinkConditions {
+            drop_probability: 1.0,
+            ..LinkConditions::perfect()
+        };
+        network.set_link_conditions(a.address(), b.address(), cut.clone());
+
+        a.send(&b.address(), b"lost").unwrap();
+        b.send(&a.address(), b"kept").unwrap();
+        let mut buf = [0u8; 16];
+        let (len, source) = a.receive(&mut buf).unwrap();
+        assert_eq!((&buf[..len], source), (&b"kept"[..], b.address()));
+        assert_eq!(network.in_flight_count(), 0);
+
+        // Links without conditions of their own follow the defaults.
+        network.set_default_conditions(cut);
+        b.send(&a.address(), b"lost").unwrap();
+        assert_eq!(network.in_flight_count(), 0);
+        assert_eq!(network.stats().dropped, 2);
+    }
+}
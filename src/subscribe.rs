@@ -1,66 +1,151 @@
 use crate::node::Node;
 use crate::node::HandleError;
+use crate::node::addressable;
+use crate::node::FINALIZE_TIMEOUT;
+use crate::message::Message;
 use crate::subscribe_decline::SubscribeDecline;
+use crate::subscribe_accept::SubscribeAccept;
+use crate::subscription_policy::SubscriptionDecision;
 use std::net::SocketAddr;
-use crate::peel::{peel_u32, peel_slice};
+use std::str;
+use std::time::Instant;
 
 pub struct Subscribe<'a> {
-    partnering_id: u32,
-    key: &'a [u8],
-    contact_method: &'a [u8],
+    pub partnering_id: u32,
+    pub key: &'a [u8],
+    pub contact_port: u16,
+    pub contact_host: &'a [u8],
 }
 
 
 impl<'a> Subscribe<'a> {
-    pub fn new(partnering_id: u32, key: &'a [u8; 32], contact_method: &'a [u8]) -> Subscribe<'a> {
+    pub fn new(partnering_id: u32, key: &'a [u8; 32], contact_port: u16, contact_host: &'a [u8]) -> Subscribe<'a> {
         Subscribe{
             partnering_id: partnering_id,
             key: &key[..],
-            contact_method: contact_method
+            contact_port: contact_port,
+            contact_host: contact_host,
         }
     }
+}
 
-    pub fn deserialize(body: &[u8]) -> Result<Subscribe, HandleError> {
-        let (partnering_id, body) = peel_u32(body)?;
-        let (key, body) = peel_slice(body, 32)?;
-        let contact_method = body;
-        
-        Ok(Subscribe {
-            partnering_id: partnering_id,
-            key: key,
-            contact_method: contact_method,
-        })
+pub fn handle_subscribe(node: &mut Node, source: &SocketAddr, message: Subscribe) -> Result<(), HandleError> {   
+    let contact_host = str::from_utf8(message.contact_host).map_err(|_| HandleError::InvalidContactMethod)?;
+    if contact_host.is_empty() || contact_host.contains('\0') {
+        // A 0 would corrupt our partner list, which uses it as a separator.
+        return Err(HandleError::InvalidContactMethod);
     }
+    let contact_method = addressable(contact_host, message.contact_port);
     
-    pub fn serialize(&self) -> Vec<u8> {
-        let capacity: usize = 1 + 4 + 32 + self.contact_method.len();
-        let mut bs = Vec::with_capacity(capacity);
-        
-        bs.push(1);
-        bs.extend_from_slice(&self.partnering_id.to_le_bytes()[..]);
-        bs.extend_from_slice(self.key);
-        bs.extend_from_slice(self.contact_method);
-        
-        bs
+    node.expire_incoming_partnerships(Instant::now());
+    
+    if let Some(confirmation_nonce) = node.incoming_partnership_nonce(message.partnering_id, message.key) {
+        // We already accepted this; our `Subscribe Accept` may have been lost.
+        return node.send_to_addressable(
+            &contact_method,
+            Message::SubscribeAccept(SubscribeAccept{
+                partnering_id: message.partnering_id,
+                confirmation_nonce: confirmation_nonce,
+            }).encode()
+        ).map_err(HandleError::from);
     }
-}
-
-pub fn handle_subscribe(node: &mut Node, source: &SocketAddr, body: &[u8]) -> Result<(), HandleError> {   
-    let message = Subscribe::deserialize(body)?;
     
-    if node.get_partnership(message.partnering_id).is_some() {
+    if node.is_partnering_id_used(message.partnering_id) {
         // We are being asked to establish a partnership for an ID that is already used.
         // This is probably an unfortunate and rare coincidence.
         // We will ask the sender to retry again immediately, which amounts to just re-randomizing the proposed partnership id.
         node.send(
             source,
-            &SubscribeDecline{
+            &Message::SubscribeDecline(SubscribeDecline{
                 partnering_id: message.partnering_id,
                 retry_delay_seconds: 0,
-            }.serialize()
-        );
+            }).encode()
+        )?;
         return Ok( () );
     }
     
+    if !node.has_room_for_incoming_partnership() {
+        // Too many proposals are waiting to be finalized, which may mean someone is flooding us with them.
+        // The policy is not consulted, so that a flood does not fill the subscription history either.
+        node.send(
+            source,
+            &Message::SubscribeDecline(SubscribeDecline{
+                partnering_id: message.partnering_id,
+                retry_delay_seconds: FINALIZE_TIMEOUT.as_secs() as u32,
+            }).encode()
+        )?;
+        return Ok( () );
+    }
+    
+    if let SubscriptionDecision::Decline{retry_delay_seconds} = node.decide_subscription(source, &contact_method) {
+        node.send(
+            source,
+            &Message::SubscribeDecline(SubscribeDecline{
+                partnering_id: message.partnering_id,
+                retry_delay_seconds: retry_delay_seconds,
+            }).encode()
+        )?;
+        return Ok( () );
+    }
+    
+    let mut key = [0u8; 32];
+    key.copy_from_slice(message.key);
+    let confirmation_nonce = node.accept_incoming_partnership(message.partnering_id, key, contact_method.clone());
+    
+    // The accept goes to where the proposer claims to be reachable rather than to the source. If the claim is
+    // false, the partnership never gets finalized; the nonce keeps a third party from finalizing it for them.
+    node.send_to_addressable(
+        &contact_method,
+        Message::SubscribeAccept(SubscribeAccept{
+            partnering_id: message.partnering_id,
+            confirmation_nonce: confirmation_nonce,
+        }).encode()
+    )?;
+    
     Ok( () )
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Subscribe;
+    use crate::message::Message;
+    use crate::node::Node;
+    use crate::node::MAX_INCOMING_PARTNERSHIPS;
+    use crate::simulated_network::LinkConditions;
+    use crate::simulated_network::SimulatedNetwork;
+    use crate::subscription_policy::PartnerCap;
+    use crate::transport::Transport;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    #[test]
+    fn a_flood_of_subscribes_is_declined_once_too_many_wait_for_finalizing() {
+        let network = SimulatedNetwork::new(LinkConditions::perfect(), 1);
+        let address: SocketAddr = "10.0.0.1:4040".parse().unwrap();
+        let mut node = Node::new(address.ip().to_string(), address.port(), Arc::new(network.endpoint(address).unwrap()));
+        let source = network.endpoint("10.0.0.2:4040".parse().unwrap()).unwrap();
+        node.set_subscription_policy(Box::new(PartnerCap{max_active_partnerships: usize::MAX, retry_delay_seconds: 0}));
+
+        let key = [1u8; 32];
+        let mut declined = 0;
+        for i in 0..1000u32 {
+            // Each claims a different contact address, none of which will ever finalize.
+            let contact_host = format!("10.1.{}.{}", i / 256, i % 256);
+            let subscribe = Message::Subscribe(Subscribe::new(i, &key, 4040, contact_host.as_bytes())).encode();
+            node.handle_received_packet(&source.address(), &subscribe).unwrap();
+
+            if network.in_flight_count() > 0 {
+                let mut buf = [0u8; 64];
+                let (len, _) = source.receive(&mut buf).unwrap();
+                match Message::decode(&buf[..len]).unwrap() {
+                    Message::SubscribeDecline(decline) => assert_eq!(decline.partnering_id, i),
+                    _ => panic!("expected a Subscribe Decline"),
+                }
+                declined += 1;
+            }
+        }
+
+        assert!(!node.has_room_for_incoming_partnership());
+        assert_eq!(declined, 1000 - MAX_INCOMING_PARTNERSHIPS);
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// The retry delay that amounts to "never ask again"
+pub const PERMANENT_RETRY_DELAY_SECONDS: u32 = 0xFFFFFFFF;
+
+/// What we know about a `Subscribe` when deciding whether to accept it
+pub struct SubscriptionRequest<'a> {
+    pub source: &'a SocketAddr,
+
+    /// Where the source asked to be reached. None of the built-in policies look at it.
+    #[allow(dead_code)]
+    pub contact_method: &'a str,
+    pub active_partnership_count: usize,
+
+    /// Partnerships we have accepted that are waiting for their `Subscribe Finalize`, any of which may
+    /// become active
+    pub pending_partnership_count: usize,
+
+    /// Previous `Subscribe` messages from the same IP address, if there were any. Also unused by the
+    /// built-in policies.
+    #[allow(dead_code)]
+    pub history: Option<&'a SubscriptionHistory>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SubscriptionHistory {
+    pub accepted: u32,
+    pub declined: u32,
+    pub last_request: Option<Instant>,
+
+    /// When the last decline told the source it could try again
+    pub retry_not_before: Option<Instant>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SubscriptionDecision {
+    Accept,
+    Decline{retry_delay_seconds: u32},
+}
+
+/// Decides who we partner with when they ask. This is a policy choice for each node's operator.
+pub trait SubscriptionPolicy: Send {
+    fn decide(&mut self, request: &SubscriptionRequest) -> SubscriptionDecision;
+}
+
+/// Accepts anyone until we have `max_active_partnerships`, counting those accepted but not yet finalized
+pub struct PartnerCap {
+    pub max_active_partnerships: usize,
+    pub retry_delay_seconds: u32,
+}
+
+impl SubscriptionPolicy for PartnerCap {
+    fn decide(&mut self, request: &SubscriptionRequest) -> SubscriptionDecision {
+        if request.active_partnership_count + request.pending_partnership_count >= self.max_active_partnerships {
+            SubscriptionDecision::Decline{retry_delay_seconds: self.retry_delay_seconds}
+        } else {
+            SubscriptionDecision::Accept
+        }
+    }
+}
+
+/// Accepts only the listed IP addresses and permanently declines everyone else
+pub struct AllowList {
+    pub allowed: HashSet<IpAddr>,
+}
+
+impl SubscriptionPolicy for AllowList {
+    fn decide(&mut self, request: &SubscriptionRequest) -> SubscriptionDecision {
+        if self.allowed.contains(&request.source.ip()) {
+            SubscriptionDecision::Accept
+        } else {
+            SubscriptionDecision::Decline{retry_delay_seconds: PERMANENT_RETRY_DELAY_SECONDS}
+        }
+    }
+}
+
+/// Permanently declines the listed IP addresses and accepts everyone else
+pub struct DenyList {
+    pub denied: HashSet<IpAddr>,
+}
+
+impl SubscriptionPolicy for DenyList {
+    fn decide(&mut self, request: &SubscriptionRequest) -> SubscriptionDecision {
+        if self.denied.contains(&request.source.ip()) {
+            SubscriptionDecision::Decline{retry_delay_seconds: PERMANENT_RETRY_DELAY_SECONDS}
+        } else {
+            SubscriptionDecision::Accept
+        }
+    }
+}
+
+/// Accepts only if every one of the policies does. The first decline, in order, is the one sent.
+pub struct AllOf {
+    pub policies: Vec<Box<dyn SubscriptionPolicy>>,
+}
+
+impl SubscriptionPolicy for AllOf {
+    fn decide(&mut self, request: &SubscriptionRequest) -> SubscriptionDecision {
+        for policy in self.policies.iter_mut() {
+            let decision = policy.decide(request);
+            if decision != SubscriptionDecision::Accept {
+                return decision;
+            }
+        }
+        SubscriptionDecision::Accept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PartnerCap;
+    use super::SubscriptionDecision;
+    use super::SubscriptionPolicy;
+    use super::SubscriptionRequest;
+    use std::net::SocketAddr;
+
+    fn decide(policy: &mut dyn SubscriptionPolicy, active_partnership_count: usize, pending_partnership_count: usize) -> SubscriptionDecision {
+        let source: SocketAddr = "10.0.0.2:4040".parse().unwrap();
+        policy.decide(&SubscriptionRequest {
+            source: &source,
+            contact_method: "10.0.0.2:4040",
+            active_partnership_count: active_partnership_count,
+            pending_partnership_count: pending_partnership_count,
+            history: None,
+        })
+    }
+
+    #[test]
+    fn partner_cap_counts_partnerships_waiting_to_be_finalized() {
+        let mut policy = PartnerCap{max_active_partnerships: 3, retry_delay_seconds: 60};
+        let declined = SubscriptionDecision::Decline{retry_delay_seconds: 60};
+        assert_eq!(decide(&mut policy, 2, 0), SubscriptionDecision::Accept);
+        assert_eq!(decide(&mut policy, 0, 2), SubscriptionDecision::Accept);
+        assert_eq!(decide(&mut policy, 3, 0), declined);
+        assert_eq!(decide(&mut policy, 1, 2), declined);
+        assert_eq!(decide(&mut policy, 0, 3), declined);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::adsb;
+use crate::adsb::SquitterContent;
+use crate::cpr::Position;
+use crate::mode_s::announced_address;
+use crate::mode_s::downlink_format;
+use crate::mode_s::residual;
+use crate::observer::FrameSource;
+use crate::observer::ObservedFrame;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How often stale aircraft are looked for when frames are scarce
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What is known about one aircraft, merged from every frame heard from it
+#[derive(Clone, Debug)]
+pub struct Aircraft {
+    /// The ICAO 24-bit address
+    pub address: u32,
+    pub callsign: Option<String>,
+    pub category: Option<u8>,
+    pub position: Option<Position>,
+    pub position_updated: Option<Instant>,
+
+    /// Barometric altitude in feet
+    pub altitude: Option<i32>,
+
+    /// Knots
+    pub ground_speed: Option<f64>,
+
+    /// Degrees clockwise from true north
+    pub track: Option<f64>,
+
+    /// Barometric vertical rate in feet per minute
+    pub vertical_rate: Option<i32>,
+
+    /// Mode A code with one octal digit per nibble
+    pub squawk: Option<u16>,
+
+    /// The most recent signal level reported by one of our own receivers
+    pub signal_level: Option<u8>,
+    #[allow(dead_code)] // aircraft.json has no field for it
+    pub first_seen: Instant,
+    pub last_seen: Instant,
+    pub messages: u64,
+
+    /// Whether one of our own receivers has heard this aircraft
+    pub heard_locally: bool,
+
+    /// Partnering IDs of the partnerships that relayed frames from this aircraft
+    pub partners: HashSet<u32>,
+}
+
+impl Aircraft {
+    fn new(address: u32, now: Instant) -> Aircraft {
+        Aircraft {
+            address: address,
+            callsign: None,
+            category: None,
+            position: None,
+            position_updated: None,
+            altitude: None,
+            ground_speed: None,
+            track: None,
+            vertical_rate: None,
+            squawk: None,
+            signal_level: None,
+            first_seen: now,
+            last_seen: now,
+            messages: 0,
+            heard_locally: false,
+            partners: HashSet::new(),
+        }
+    }
+
+    fn apply_squitter(&mut self, content: SquitterContent) {
+        match content {
+            SquitterContent::Identification(identification) => {
+                self.callsign = Some(identification.callsign);
+                self.category = Some(identification.category);
+            }
+            SquitterContent::AirbornePosition(position) => {
+                if position.altitude_source == adsb::AltitudeSource::Barometric && position.altitude.is_some() {
+                    self.altitude = position.altitude;
+                }
+            }
+            SquitterContent::SurfacePosition(position) => {
+                self.ground_speed = position.ground_speed.or(self.ground_speed);
+                self.track = position.track.or(self.track);
+            }
+            SquitterContent::AirborneVelocity(velocity) => {
+                self.ground_speed = velocity.ground_speed.or(self.ground_speed);
+                self.track = velocity.track.or(self.track);
+                if velocity.vertical_rate_source == adsb::AltitudeSource::Barometric && velocity.vertical_rate.is_some() {
+                    self.vertical_rate = velocity.vertical_rate;
+                }
+            }
+            SquitterContent::AircraftStatus(status) => {
+                self.squawk = status.squawk.or(self.squawk);
+            }
+            SquitterContent::OperationalStatus(_) => {}
+        }
+    }
+}
+
+/// Every aircraft heard recently, by our receivers or our partners', keyed by ICAO address
+pub struct AircraftTable {
+    aircraft: HashMap<u32, Aircraft>,
+
+    /// Aircraft not heard from for this long are dropped
+    timeout: Duration,
+}
+
+impl AircraftTable {
+    pub fn new(timeout: Duration) -> AircraftTable {
+        AircraftTable {
+            aircraft: HashMap::new(),
+            timeout: timeout,
+        }
+    }
+
+    /// Merges what a frame says into the table.
+    pub fn update(&mut self, observed: &ObservedFrame) {
+        let frame = &observed.frame.bytes;
+        if frame.is_empty() {
+            return;
+        }
+
+        let squitter = match downlink_format(frame) {
+            17 | 18 => match adsb::decode(frame) {
+                Some(ref squitter) if !squitter.address_is_icao => return,
+                squitter => squitter,
+            },
+            _ => None,
+        };
+
+        let address = match downlink_format(frame) {
+            11 | 17 | 18 => announced_address(frame),
+            // Frames that passed validation without announcing an address overlay it on the parity.
+            _ => residual(frame),
+        };
+
+        let aircraft = self.aircraft.entry(address).or_insert_with(|| Aircraft::new(address, observed.received_at));
+        aircraft.last_seen = observed.received_at;
+        aircraft.messages += 1;
+        match observed.source {
+            FrameSource::Local(_) => {
+                aircraft.heard_locally = true;
+                aircraft.signal_level = observed.frame.signal_level.or(aircraft.signal_level);
+            }
+            FrameSource::Partner(partnering_id) => {
+                aircraft.partners.insert(partnering_id);
+            }
+        }
+
+        if let Some(position) = observed.position {
+            aircraft.position = Some(position);
+            aircraft.position_updated = Some(observed.received_at);
+        }
+
+        match downlink_format(frame) {
+            5 | 21 => {
+                let id13 = ((frame[2] as u32 & 0x1F) << 8) | frame[3] as u32;
+                aircraft.squawk = Some(adsb::decode_id13(id13));
+            }
+            17 | 18 => {
+                if let Some(squitter) = squitter {
+                    aircraft.apply_squitter(squitter.content);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Drops aircraft that have not been heard from within the timeout.
+    pub fn expire(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.aircraft.retain(|_, aircraft| now.duration_since(aircraft.last_seen) < timeout);
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, address: u32) -> Option<&Aircraft> {
+        self.aircraft.get(&address)
+    }
+
+    pub fn aircraft(&self) -> impl Iterator<Item=&Aircraft> {
+        self.aircraft.values()
+    }
+
+    #[allow(dead_code)]
+    pub fn len(&self) -> usize {
+        self.aircraft.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool {
+        self.aircraft.is_empty()
+    }
+}
+
+/// Keeps `table` up to date with the frames from `frames`, as from `FrameObserver::listen`. Returns
+/// when the node goes away.
+pub fn track(frames: Receiver<ObservedFrame>, table: Arc<Mutex<AircraftTable>>) {
+    let mut last_expired = Instant::now();
+    loop {
+        match frames.recv_timeout(EXPIRY_INTERVAL) {
+            Ok(observed) => {
+                table.lock().unwrap().update(&observed);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+
+        let now = Instant::now();
+        if now.duration_since(last_expired) >= EXPIRY_INTERVAL {
+            table.lock().unwrap().expire(now);
+            last_expired = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AircraftTable;
+    use crate::cpr::Position;
+    use crate::ingest::ModeSFrame;
+    use crate::mode_s::crc24;
+    use crate::observer::FrameSource;
+    use crate::observer::ObservedFrame;
+    use std::time::Duration;
+    use std::time::Instant;
+    use std::time::SystemTime;
+
+    const ADDRESS: u32 = 0x4840D6;
+
+    /// An extended squitter from `ADDRESS` carrying `me`
+    fn squitter(me: u64) -> Vec<u8> {
+        let mut frame = vec![0x8D, (ADDRESS >> 16) as u8, (ADDRESS >> 8) as u8, ADDRESS as u8];
+        frame.extend_from_slice(&me.to_be_bytes()[1..]);
+        let parity = crc24(&frame);
+        frame.extend_from_slice(&parity.to_be_bytes()[1..]);
+        frame
+    }
+
+    /// Callsign KLM1023
+    const IDENTIFICATION: u64 = 0x202CC371C32CE0;
+    /// Barometric altitude 38000 feet
+    const AIRBORNE_POSITION: u64 = 0x58C382D690C8AC;
+    /// 159 knots over the ground on 182.88 degrees, and a GNSS vertical rate
+    const GROUND_VELOCITY: u64 = 0x99440994083817;
+    /// An airspeed, and a barometric vertical rate of -2304 feet per minute
+    const AIR_VELOCITY: u64 = 0x9B06B6AF189400;
+
+    fn observed(source: FrameSource, bytes: Vec<u8>, signal_level: Option<u8>, received_at: Instant) -> ObservedFrame {
+        ObservedFrame {
+            source: source,
+            frame: ModeSFrame {
+                bytes: bytes,
+                mlat_timestamp: None,
+                signal_level: signal_level,
+                received_at: SystemTime::now(),
+                receiver_location: None,
+            },
+            position: None,
+            received_at: received_at,
+        }
+    }
+
+    fn local() -> FrameSource {
+        FrameSource::Local("beast:localhost:30005".to_string())
+    }
+
+    #[test]
+    fn merges_frames_from_every_source() {
+        let mut table = AircraftTable::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        table.update(&observed(local(), squitter(IDENTIFICATION), Some(0x80), now));
+        let mut position = observed(FrameSource::Partner(7), squitter(AIRBORNE_POSITION), Some(0x20), now);
+        position.position = Some(Position { lat: 52.2572, lon: 3.9194 });
+        table.update(&position);
+        table.update(&observed(FrameSource::Partner(9), squitter(GROUND_VELOCITY), None, now));
+        table.update(&observed(FrameSource::Partner(9), squitter(AIR_VELOCITY), None, now));
+        // A surveillance reply with squawk 7700, its parity overlaid with the address
+        let mut surveillance = vec![0x28, 0x00, 0x0A, 0xAA];
+        let parity = crc24(&surveillance) ^ ADDRESS;
+        surveillance.extend_from_slice(&parity.to_be_bytes()[1..]);
+        table.update(&observed(local(), surveillance, None, now));
+
+        assert_eq!(table.len(), 1);
+        let aircraft = table.get(ADDRESS).unwrap();
+        assert_eq!(aircraft.callsign.as_deref(), Some("KLM1023"));
+        assert_eq!(aircraft.altitude, Some(38000));
+        assert_eq!(aircraft.position, Some(Position { lat: 52.2572, lon: 3.9194 }));
+        assert_eq!(aircraft.position_updated, Some(now));
+        assert!((aircraft.ground_speed.unwrap() - 159.20).abs() < 0.01);
+        assert!((aircraft.track.unwrap() - 182.88).abs() < 0.01);
+        assert_eq!(aircraft.vertical_rate, Some(-2304));
+        assert_eq!(aircraft.squawk, Some(0x7700));
+        assert_eq!(aircraft.messages, 5);
+
+        // Only our own receivers' signal levels mean anything to us.
+        assert_eq!(aircraft.signal_level, Some(0x80));
+        assert!(aircraft.heard_locally);
+        let mut partners: Vec<u32> = aircraft.partners.iter().cloned().collect();
+        partners.sort();
+        assert_eq!(partners, vec![7, 9]);
+    }
+
+    #[test]
+    fn keeps_only_barometric_vertical_rates() {
+        let mut table = AircraftTable::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        table.update(&observed(FrameSource::Partner(7), squitter(GROUND_VELOCITY), None, now));
+        assert_eq!(table.get(ADDRESS).unwrap().vertical_rate, None);
+        table.update(&observed(FrameSource::Partner(7), squitter(AIR_VELOCITY), None, now));
+        table.update(&observed(FrameSource::Partner(7), squitter(GROUND_VELOCITY), None, now));
+        assert_eq!(table.get(ADDRESS).unwrap().vertical_rate, Some(-2304));
+        assert!(!table.get(ADDRESS).unwrap().heard_locally);
+    }
+
+    #[test]
+    fn ignores_non_icao_addresses() {
+        let mut table = AircraftTable::new(Duration::from_secs(60));
+        let mut frame = squitter(IDENTIFICATION);
+        // DF18 with CF 1: an anonymous address
+        frame[0] = 0x91;
+        table.update(&observed(local(), frame, None, Instant::now()));
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn ages_aircraft_by_when_they_were_last_heard() {
+        let mut table = AircraftTable::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        let mut position = observed(local(), squitter(AIRBORNE_POSITION), None, start);
+        position.position = Some(Position { lat: 52.2572, lon: 3.9194 });
+        table.update(&position);
+        table.update(&observed(local(), squitter(IDENTIFICATION), None, start + Duration::from_secs(30)));
+
+        let aircraft = table.get(ADDRESS).unwrap();
+        assert_eq!(aircraft.first_seen, start);
+        assert_eq!(aircraft.last_seen, start + Duration::from_secs(30));
+        assert_eq!(aircraft.position_updated, Some(start));
+
+        table.expire(start + Duration::from_secs(89));
+        assert_eq!(table.len(), 1);
+        table.expire(start + Duration::from_secs(90));
+        assert!(table.is_empty());
+    }
+}
@@ -0,0 +1,23 @@
+use std::io;
+use std::net::SocketAddr;
+use std::net::UdpSocket;
+
+/// How a `Node` exchanges datagrams with the rest of the mesh. The protocol is built entirely on
+/// unreliable datagrams, so an implementation is free to lose, duplicate, or reorder them.
+pub trait Transport: Send + Sync {
+    fn send(&self, destination: &SocketAddr, packet: &[u8]) -> io::Result<()>;
+
+    /// Blocks until a datagram arrives, returning its length and who sent it
+    fn receive(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+}
+
+impl Transport for UdpSocket {
+    fn send(&self, destination: &SocketAddr, packet: &[u8]) -> io::Result<()> {
+        self.send_to(packet, destination)?;
+        Ok( () )
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from(buf)
+    }
+}